@@ -1,18 +1,116 @@
-use crate::message::{ClientMessage, client_message, ServerMessage};
-use log::{error, info};
+use crate::error::ProtocolError;
+use crate::codec::Codec;
+use crate::framing::{self, FrameTooLarge};
+use crate::transport::Stream;
+use crate::message::{
+    client_message, server_message, AddRequest, ClientMessage, EchoMessage, LimitsRequest,
+    PingMessage, PongMessage, PublishRequest, ServerMessage, StatusRequest, StatusResponse,
+    StreamEchoRequest, Subscribe, Unsubscribe, Upgrade,
+};
+use log::{error, info, warn};
 use prost::Message;
-use std::io::{Read, Write};
 use std::{
-    io,
+    collections::VecDeque,
+    io::{self, Write},
     net::{SocketAddr, TcpStream, ToSocketAddrs},
-    time::Duration,
+    path::PathBuf,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Delay before racing the next address family, per RFC 8305 section 5.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Default for the largest message the client will read.
+const DEFAULT_MAX_RECEIVE_SIZE: usize = 1024 * 1024;
+
+/// How often `connect_cancellable` checks its cancel flag.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Idle time after which `send` checks that the server has not closed the
+/// connection before writing.
+const IDLE_PROBE_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// Round-trip latency summary returned by `Client::measure_rtt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    pub samples: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    /// Nearest-rank 99th percentile.
+    pub p99: Duration,
+}
+
+/// How `Client::request_retry` retries after transport failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first; zero is treated as one.
+    pub max_attempts: usize,
+    /// Pause before each reconnect.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Resolves "host:port" to candidate addresses. The default uses the
+/// system resolver via `ToSocketAddrs`.
+pub type Resolver = Arc<dyn Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync>;
+
+/// A connection to the server.
+///
+/// Threading model: every method that touches the connection takes
+/// `&mut self`, so a `Client` can be moved between threads but never used by
+/// two at once, and a `disconnect` cannot race a `send`. To send from one
+/// thread while receiving on another, `split` the client into owned halves;
+/// closing the sending half ends the conversation cleanly for the reader.
 pub struct Client {
     ip: String,
     port: u32,
     timeout: Duration,
-    stream: Option<TcpStream>,
+    stream: Option<Stream>,
+    /// Set by `connect_unix`; `connect` then reconnects to this socket
+    /// instead of `ip:port`.
+    unix_path: Option<PathBuf>,
+    // Messages read while waiting for a specific reply (e.g. pushes that
+    // arrive before a subscribe ack), with their wire bytes; handed out by
+    // `receive` first.
+    pending: VecDeque<(ServerMessage, Vec<u8>)>,
+    codec: Codec,
+    frame_sync: bool,
+    /// Compress outgoing frames with a longer payload; see
+    /// `set_compression_threshold`.
+    compression: Option<usize>,
+    resolver: Resolver,
+    /// Re-resolve the host on every connect rather than reuse `resolved`.
+    resolve_on_connect: bool,
+    resolved: Option<Vec<SocketAddr>>,
+    /// Largest request payload the server accepts, once learned through
+    /// `negotiate_limits`.
+    max_send_size: Option<usize>,
+    /// Largest message payload `receive` will read; a longer length prefix
+    /// is rejected before anything is allocated.
+    max_receive_size: usize,
+    /// Set by a `Throttle` from the server; sends wait until then.
+    resume_sending_at: Option<Instant>,
+    /// When the connection was last opened, written or read successfully.
+    last_activity: Instant,
+    /// Whether the last send or receive failed, leaving the connection in
+    /// an unknown state. Cleared by a successful `connect`.
+    failed: bool,
 }
 
 impl Client {
@@ -22,30 +120,377 @@ impl Client {
             port,
             timeout: Duration::from_millis(timeout_ms),
             stream: None,
+            unix_path: None,
+            pending: VecDeque::new(),
+            codec: Codec::Protobuf,
+            frame_sync: false,
+            compression: None,
+            resolver: Arc::new(|address| Ok(address.to_socket_addrs()?.collect())),
+            resolve_on_connect: false,
+            resolved: None,
+            max_send_size: None,
+            max_receive_size: DEFAULT_MAX_RECEIVE_SIZE,
+            resume_sending_at: None,
+            last_activity: Instant::now(),
+            failed: false,
         }
     }
 
-    pub fn connect(&mut self) -> io::Result<()> {
+    /// Sets the largest message payload, in bytes, the client will accept.
+    /// A frame announcing more is rejected with `MessageTooLarge` instead of
+    /// being allocated, so a faulty server cannot exhaust memory. Defaults
+    /// to 1 MiB, the server's default request limit.
+    pub fn set_max_receive_size(&mut self, bytes: usize) {
+        self.max_receive_size = bytes;
+    }
+
+    /// Replaces the resolver used to turn the host and port into addresses.
+    pub fn set_resolver<F>(&mut self, resolver: F)
+    where
+        F: Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static,
+    {
+        self.resolver = Arc::new(resolver);
+        self.resolved = None;
+    }
+
+    /// Whether every connect resolves the host afresh, so a reconnect
+    /// follows DNS round-robin or failover changes. By default the first
+    /// successful resolution is reused for later connects until connecting
+    /// to all of its addresses fails.
+    pub fn set_resolve_on_connect(&mut self, enabled: bool) {
+        self.resolve_on_connect = enabled;
+        self.resolved = None;
+    }
+
+    /// Selects the wire encoding. Must match the server's `with_codec`
+    /// setting.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Prefixes every frame with `FRAME_SYNC_MARKER` and expects it on
+    /// received frames. Must match the server's `with_frame_sync` setting.
+    pub fn set_frame_sync(&mut self, enabled: bool) {
+        self.frame_sync = enabled;
+    }
+
+    /// Compresses outgoing frames whose payload shrinks by doing so, e.g.
+    /// large echoes of log text. The server inflates them without any
+    /// configuration; replies are not compressed. Only applies to the
+    /// protobuf codec.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled.then_some(0);
+    }
+
+    /// Builder form of `set_compression`, e.g.
+    /// `Client::new(ip, port, timeout_ms).with_compression(true)`.
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.set_compression(enabled);
+        self
+    }
+
+    /// Like `set_compression(true)`, but only for messages whose encoded
+    /// payload is longer than `bytes`; shorter ones are sent raw, as
+    /// compressing them costs more than it saves. Each frame's length
+    /// prefix records whether it was compressed.
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression = Some(bytes);
+    }
+
+    /// Builder form of `set_compression_threshold`.
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.set_compression_threshold(bytes);
+        self
+    }
+
+    /// Connects to the server, trying each resolved address in order and
+    /// waiting at most the configured timeout for each. If every address
+    /// fails, the last error is returned and the next connect resolves the
+    /// host again.
+    ///
+    /// An active refusal (nothing listening on the port) is reported as an
+    /// `Io` error of kind `ConnectionRefused` as soon as the peer answers,
+    /// without waiting for the timeout. Only a peer that never answers
+    /// produces `TimedOut`.
+    pub fn connect(&mut self) -> Result<(), ProtocolError> {
+        #[cfg(unix)]
+        if let Some(path) = self.unix_path.clone() {
+            return self.connect_unix(path);
+        }
         println!("Connecting to {}:{}", self.ip, self.port);
 
-        let address = format!("{}:{}", self.ip, self.port);
-        let socket_addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let mut last_error = None;
+        for addr in self.resolve()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    self.attach(Stream::Tcp(stream))?;
+                    println!("Connected to the server!");
+                    return Ok(());
+                }
+                Err(e) => {
+                    log_connect_error(&addr, self.timeout, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
 
-        if socket_addrs.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid IP or port",
-            ));
+        self.resolved = None;
+        Err(last_error.expect("resolve returned no addresses").into())
+    }
+
+    /// Calls `connect` up to `max_attempts` times, sleeping `base_delay`,
+    /// then twice that, four times that and so on between failures, for
+    /// servers that may be restarting. Returns the last error if every
+    /// attempt fails; zero attempts is treated as one.
+    pub fn connect_with_retry(
+        &mut self,
+        max_attempts: u32,
+        base_delay: Duration,
+    ) -> Result<(), ProtocolError> {
+        let max_attempts = max_attempts.max(1);
+        let mut delay = base_delay;
+        let mut attempt = 1;
+        loop {
+            info!("Connection attempt {}/{}", attempt, max_attempts);
+            match self.connect() {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < max_attempts => {
+                    warn!(
+                        "Connection attempt {}/{} failed: {}; retrying in {:?}",
+                        attempt, max_attempts, e, delay
+                    );
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("Giving up after {} connection attempts: {}", max_attempts, e);
+                    return Err(e);
+                }
+            }
         }
+    }
 
-        let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
-        self.stream = Some(stream);
+    /// Connects and then asks the server for its limits; see
+    /// `negotiate_limits`.
+    pub fn connect_negotiated(&mut self) -> Result<(), ProtocolError> {
+        self.connect()?;
+        self.negotiate_limits()?;
+        Ok(())
+    }
+
+    /// Asks the server for the largest request it accepts and remembers it,
+    /// so `send` rejects oversized messages locally with `MessageTooLarge`
+    /// instead of losing the connection to a server-side rejection.
+    pub fn negotiate_limits(&mut self) -> Result<usize, ProtocolError> {
+        let response =
+            self.request_checked(client_message::Message::LimitsRequest(LimitsRequest {}))?;
+        match response.message {
+            Some(server_message::Message::LimitsResponse(limits)) => {
+                let limit = limits.max_message_size as usize;
+                info!("Server accepts messages up to {} bytes", limit);
+                self.max_send_size = Some(limit);
+                Ok(limit)
+            }
+            other => Err(unexpected_reply("a limits response", other)),
+        }
+    }
+
+    /// The server's request size limit, if it has been negotiated.
+    pub fn max_send_size(&self) -> Option<usize> {
+        self.max_send_size
+    }
+
+    /// Like `connect`, but gives up promptly with an `Interrupted` `Io` error
+    /// once `cancel` is set, e.g. by another thread shutting the application
+    /// down. The resolved addresses are tried in order like `connect` does,
+    /// and `cancel` is checked during and between attempts. An abandoned attempt
+    /// finishes in the background and its socket, if any, is closed.
+    pub fn connect_cancellable(
+        &mut self,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<(), ProtocolError> {
+        let cancelled = || io::Error::new(io::ErrorKind::Interrupted, "Connect cancelled");
+        if cancel.load(Ordering::SeqCst) {
+            return Err(cancelled().into());
+        }
+
+        let mut last_error = None;
+        for addr in self.resolve()? {
+            if cancel.load(Ordering::SeqCst) {
+                info!("Connect cancelled before trying {}", addr);
+                return Err(cancelled().into());
+            }
+            match connect_or_cancel(addr, self.timeout, &cancel) {
+                Ok(Some(stream)) => {
+                    self.attach(Stream::Tcp(stream))?;
+                    info!("Connected to {}", addr);
+                    return Ok(());
+                }
+                Ok(None) => {
+                    info!("Connect to {} cancelled", addr);
+                    return Err(cancelled().into());
+                }
+                Err(e) => {
+                    log_connect_error(&addr, self.timeout, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        self.resolved = None;
+        Err(last_error.expect("resolve returned no addresses").into())
+    }
+
+    /// Connects using RFC 8305 "happy eyeballs": resolved addresses are
+    /// interleaved by family (IPv6 first) and a new attempt is started every
+    /// `HAPPY_EYEBALLS_DELAY` or as soon as the previous one fails, keeping
+    /// whichever connection completes first.
+    pub fn connect_happy_eyeballs(&mut self) -> Result<(), ProtocolError> {
+        info!("Connecting to {}:{} (happy eyeballs)", self.ip, self.port);
 
-        println!("Connected to the server!");
+        let socket_addrs = interleave_families(self.resolve()?);
+
+        let (tx, rx) = mpsc::channel();
+        let mut remaining = socket_addrs.into_iter().peekable();
+        let mut in_flight = 0;
+        let mut last_error = None;
+
+        let start_attempt = |addr: SocketAddr| {
+            let tx = tx.clone();
+            let timeout = self.timeout;
+            // Losing attempts finish in the background; their streams are
+            // dropped once the receiver is gone.
+            thread::spawn(move || {
+                let _ = tx.send(TcpStream::connect_timeout(&addr, timeout));
+            });
+        };
+
+        if let Some(addr) = remaining.next() {
+            start_attempt(addr);
+            in_flight += 1;
+        }
+
+        while in_flight > 0 {
+            let result = if remaining.peek().is_some() {
+                rx.recv_timeout(HAPPY_EYEBALLS_DELAY)
+            } else {
+                rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match result {
+                Ok(Ok(stream)) => {
+                    info!("Connected to {}", stream.peer_addr()?);
+                    self.attach(Stream::Tcp(stream))?;
+                    return Ok(());
+                }
+                Ok(Err(e)) => {
+                    in_flight -= 1;
+                    last_error = Some(e);
+                    if let Some(addr) = remaining.next() {
+                        start_attempt(addr);
+                        in_flight += 1;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(addr) = remaining.next() {
+                        start_attempt(addr);
+                        in_flight += 1;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        Err(last_error.map_or(ProtocolError::NotConnected, ProtocolError::from))
+    }
+
+    /// Connects through the SOCKS5 proxy at `proxy` ("host:port"), which
+    /// opens the tunnel to this client's configured ip and port. The target
+    /// name is resolved by the proxy.
+    #[cfg(feature = "socks5")]
+    pub fn connect_via_socks5(&mut self, proxy: &str) -> Result<(), ProtocolError> {
+        info!("Connecting to {}:{} via SOCKS5 proxy {}", self.ip, self.port, proxy);
+
+        let proxy_addr = proxy.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid proxy address")
+        })?;
+        let port = u16::try_from(self.port)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid IP or port"))?;
+
+        let stream = crate::socks5::connect(proxy_addr, &self.ip, port, self.timeout)
+            .inspect_err(|e| error!("SOCKS5 connect via {} failed: {}", proxy_addr, e))?;
+        Ok(self.attach(Stream::Tcp(stream))?)
+    }
+
+    /// Connects over the Unix domain socket at `path` instead of TCP, e.g.
+    /// to a server started with `Server::bind_unix`. The path is remembered:
+    /// later `connect` calls, including reconnects, use it too.
+    #[cfg(unix)]
+    pub fn connect_unix(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), ProtocolError> {
+        let path = path.as_ref();
+        info!("Connecting to unix socket {}", path.display());
+        let stream = std::os::unix::net::UnixStream::connect(path)
+            .inspect_err(|e| error!("Failed to connect to {}: {}", path.display(), e))?;
+        self.attach(Stream::Unix(stream))?;
+        self.unix_path = Some(path.to_path_buf());
+        info!("Connected to unix socket {}", path.display());
+        Ok(())
+    }
+
+    /// Adopts a newly connected stream. Reads on it give up after the
+    /// client's timeout, so a server that never replies cannot block
+    /// `receive` forever.
+    fn attach(&mut self, stream: Stream) -> io::Result<()> {
+        stream.set_read_timeout(self.read_timeout())?;
+        self.stream = Some(stream);
+        self.failed = false;
+        self.last_activity = Instant::now();
         Ok(())
     }
 
-    pub fn disconnect(&mut self) -> io::Result<()> {
+    /// The client's timeout as a socket read timeout; zero means none.
+    fn read_timeout(&self) -> Option<Duration> {
+        Some(self.timeout).filter(|timeout| !timeout.is_zero())
+    }
+
+    /// Whether the client holds an open stream, i.e. `connect` succeeded
+    /// and `disconnect` has not been called since. A connection the server
+    /// closed still counts until the next send or receive fails.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// The local address of the connection, e.g. to see which interface a
+    /// multi-homed host connected from. Fails with `NotConnected` before
+    /// `connect`, and with an `Unsupported` `Io` error over a Unix socket.
+    pub fn local_addr(&self) -> Result<SocketAddr, ProtocolError> {
+        Ok(self.tcp_stream()?.local_addr()?)
+    }
+
+    /// The server address the connection ended up using, which may be any
+    /// of those the host resolved to. Fails like `local_addr`.
+    pub fn peer_addr(&self) -> Result<SocketAddr, ProtocolError> {
+        Ok(self.tcp_stream()?.peer_addr()?)
+    }
+
+    fn tcp_stream(&self) -> io::Result<&TcpStream> {
+        let stream = self.stream.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "No active connection")
+        })?;
+        stream.as_tcp().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "Not a TCP connection")
+        })
+    }
+
+    /// Closes the connection. The client stays configured and can `connect`
+    /// again; messages still queued from the old connection are dropped so
+    /// they cannot be mistaken for replies on the new one.
+    pub fn disconnect(&mut self) -> Result<(), ProtocolError> {
+        self.pending.clear();
         if let Some(stream) = self.stream.take() {
             stream.shutdown(std::net::Shutdown::Both)?;
         }
@@ -54,21 +499,61 @@ impl Client {
         Ok(())
     }
 
-    pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+    /// Returns the client to a known-good state after an error: anything
+    /// buffered from the old connection (queued pushes, unread replies) is
+    /// discarded, the old stream is closed, and a fresh one is opened.
+    ///
+    /// Closing the old stream is best-effort, since it is often the reason
+    /// for recovering in the first place; only the reconnect can fail.
+    pub fn drain_and_reconnect(&mut self) -> Result<(), ProtocolError> {
+        self.pending.clear();
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        self.connect()
+    }
+
+    /// Sends one framed message.
+    ///
+    /// If the connection has been idle for a while, it is probed without
+    /// blocking before writing: if the server has already closed it, this
+    /// returns a `BrokenPipe` `Io` error instead of letting the write land in
+    /// the local socket buffer and appear to succeed. A close during back-to-back
+    /// requests, or one that races with the send, is reported by the next
+    /// `receive` instead.
+    pub fn send(&mut self, message: client_message::Message) -> Result<(), ProtocolError> {
+        self.send_with_priority(message, 0)
+    }
+
+    /// Like `send`, but asks the server to schedule the request ahead of
+    /// lower-priority work when it is backlogged. Responses on this
+    /// connection still arrive in request order.
+    pub fn send_with_priority(
+        &mut self,
+        message: client_message::Message,
+        priority: u8,
+    ) -> Result<(), ProtocolError> {
+        self.send_request(ClientMessage {
+            message: Some(message),
+            priority: priority as u32,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn send_request(&mut self, client_message: ClientMessage) -> io::Result<()> {
+        let result = self.write_request(client_message);
+        self.record_outcome(result.is_ok());
+        result
+    }
+
+    fn write_request(&mut self, client_message: ClientMessage) -> io::Result<()> {
+        self.honor_throttle();
+        self.check_open()?;
         if let Some(ref mut stream) = self.stream {
-            let client_message = ClientMessage {
-                message: Some(message),
-            };
-            
             let payload = client_message.encode_to_vec();
-            let len = payload.len() as u32;
-            
-            // Write length prefix
-            stream.write_all(&len.to_be_bytes())?;
-            
-            // Write payload
-            stream.write_all(&payload)?;
-            stream.flush()?;
+            check_send_size(payload.len(), self.max_send_size)?;
+            write_frame(self.codec, self.compression, stream, &payload, self.frame_sync)?;
 
             println!("Sent message: {:?}", client_message);
             Ok(())
@@ -77,28 +562,920 @@ impl Client {
         }
     }
 
-    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+    /// Pipelines `messages` in one write: every message is framed into a
+    /// single buffer that is written and flushed once. Read the replies with
+    /// `receive_all`; they arrive in the same order.
+    pub fn send_all(&mut self, messages: &[client_message::Message]) -> Result<(), ProtocolError> {
+        let result = self.write_pipelined(messages);
+        self.record_outcome(result.is_ok());
+        Ok(result?)
+    }
+
+    fn write_pipelined(&mut self, messages: &[client_message::Message]) -> io::Result<()> {
+        self.honor_throttle();
+        self.check_open()?;
+        if let Some(ref mut stream) = self.stream {
+            let mut buffer = Vec::new();
+            for message in messages {
+                let client_message = ClientMessage {
+                    message: Some(message.clone()),
+                    ..Default::default()
+                };
+                let payload = client_message.encode_to_vec();
+                check_send_size(payload.len(), self.max_send_size)?;
+                write_frame(self.codec, self.compression, &mut buffer, &payload, self.frame_sync)?;
+            }
+            stream.write_all(&buffer)?;
+            stream.flush()?;
+
+            info!("Sent {} pipelined messages", messages.len());
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "No active connection"))
+        }
+    }
+
+    /// Receives the next `count` messages in order.
+    pub fn receive_all(&mut self, count: usize) -> Result<Vec<ServerMessage>, ProtocolError> {
+        (0..count).map(|_| self.receive()).collect()
+    }
+
+    /// Returns the next message from the server, failing with `TimedOut` if
+    /// none arrives within the client's timeout. A reply that stops part-way
+    /// also fails with `TimedOut`, for which `framing::is_stalled` holds,
+    /// and closes the connection, since the stream is no longer aligned.
+    ///
+    /// If the server reset the framing (see `Server::with_reset_on_malformed`)
+    /// the reset is acknowledged and an `Io` error is returned for which
+    /// `framing::is_reset` holds; unanswered requests can be resent.
+    pub fn receive(&mut self) -> Result<ServerMessage, ProtocolError> {
+        self.receive_raw().map(|(message, _)| message)
+    }
+
+    /// Like `receive`, but waits at most `timeout` for the message instead
+    /// of the client's timeout, failing with `TimedOut`. Only this call is
+    /// affected. `timeout` must be non-zero.
+    pub fn receive_timeout(&mut self, timeout: Duration) -> Result<ServerMessage, ProtocolError> {
+        if let Some((message, _)) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        let stream = self.stream.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "No active connection")
+        })?;
+        stream.set_read_timeout(Some(timeout))?;
+        let result = self.read_frame();
+        if let Some(ref stream) = self.stream {
+            stream.set_read_timeout(self.read_timeout())?;
+        }
+        Ok(result.map(|(message, _)| message)?)
+    }
+
+    /// Like `receive`, but also returns the message's payload exactly as it
+    /// arrived (without the length prefix), so it can be forwarded verbatim
+    /// with unknown fields intact.
+    pub fn receive_raw(&mut self) -> Result<(ServerMessage, Vec<u8>), ProtocolError> {
+        if let Some(received) = self.pending.pop_front() {
+            return Ok(received);
+        }
+        Ok(self.read_frame()?)
+    }
+
+    /// Sends `message` and waits for its reply, whatever it is. `receive`
+    /// blocks until a whole frame arrives, so no pause is needed between the
+    /// two.
+    pub fn request(
+        &mut self,
+        message: client_message::Message,
+    ) -> Result<ServerMessage, ProtocolError> {
+        self.send(message)?;
+        self.receive()
+    }
+
+    /// Sends every message in `messages` in one write, then reads as many
+    /// replies, which the server answers in request order; the round trips
+    /// overlap instead of adding up. Like `request`, replies are taken as
+    /// they come, whatever they are.
+    pub fn request_batch(
+        &mut self,
+        messages: Vec<client_message::Message>,
+    ) -> Result<Vec<ServerMessage>, ProtocolError> {
+        self.send_all(&messages)?;
+        self.receive_all(messages.len())
+    }
+
+    /// Like `request`, but an `ErrorResponse` from the server is returned as
+    /// `ProtocolError::Server`, so transport and application failures can
+    /// both be propagated with `?`.
+    pub fn request_checked(
+        &mut self,
+        message: client_message::Message,
+    ) -> Result<ServerMessage, ProtocolError> {
+        check_response(self.request(message)?)
+    }
+
+    /// Echoes `content` through the server and returns what came back. An
+    /// error response becomes `ProtocolError::Server`; any other reply is
+    /// `DecodeFailed`.
+    pub fn echo(&mut self, content: &str) -> Result<String, ProtocolError> {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        match self.request_checked(message)?.message {
+            Some(server_message::Message::EchoMessage(echo)) => Ok(echo.content),
+            other => Err(unexpected_reply("an echo", other)),
+        }
+    }
+
+    /// Asks the server for `a + b`. Errors as for `echo`; an overflowing
+    /// sum is rejected by the server with `InvalidArgument`.
+    pub fn add(&mut self, a: i32, b: i32) -> Result<i32, ProtocolError> {
+        let message = client_message::Message::AddRequest(AddRequest {
+            a,
+            b,
+            ..Default::default()
+        });
+        match self.request_checked(message)?.message {
+            Some(server_message::Message::AddResponse(add)) => Ok(add.result),
+            other => Err(unexpected_reply("an add response", other)),
+        }
+    }
+
+    /// Sends a ping and waits for the matching pong, returning its nonce.
+    /// A pong carrying a different nonce is a `DecodeFailed` error; a dead
+    /// connection surfaces as the transport error, or as a timeout.
+    pub fn ping(&mut self, nonce: u64) -> Result<u64, ProtocolError> {
+        self.ping_with_load(nonce).map(|pong| pong.nonce)
+    }
+
+    /// Like `ping`, but returns the whole pong, which also reports the
+    /// server's current load: open connections, queued jobs and recent
+    /// error rate.
+    pub fn ping_with_load(&mut self, nonce: u64) -> Result<PongMessage, ProtocolError> {
+        let message = client_message::Message::PingMessage(PingMessage { nonce });
+        match self.request_checked(message)?.message {
+            Some(server_message::Message::PongMessage(pong)) if pong.nonce == nonce => Ok(pong),
+            Some(server_message::Message::PongMessage(pong)) => Err(ProtocolError::DecodeFailed(
+                format!("Pong nonce {} does not match ping nonce {}", pong.nonce, nonce),
+            )),
+            other => Err(unexpected_reply("a pong", other)),
+        }
+    }
+
+    /// Asks the server for its uptime, open connection count and the number
+    /// of requests it has handled.
+    pub fn status(&mut self) -> Result<StatusResponse, ProtocolError> {
+        let message = client_message::Message::StatusRequest(StatusRequest {});
+        match self.request_checked(message)?.message {
+            Some(server_message::Message::StatusResponse(status)) => Ok(status),
+            other => Err(unexpected_reply("a status response", other)),
+        }
+    }
+
+    /// Like `request_checked`, but survives transient transport failures:
+    /// the client reconnects (see `drain_and_reconnect`) and resends, up to
+    /// `policy.max_attempts` times. Each request carries an idempotency key,
+    /// so a request the server handled before the connection dropped is not
+    /// handled twice; the retry gets the original response.
+    ///
+    /// Subscribe, unsubscribe and upgrade requests only affect the
+    /// connection they are sent on, which a reconnect discards, so they are
+    /// sent once without retrying. So are stream echoes and publishes, which
+    /// are answered with more than the one message read here.
+    pub fn request_retry(
+        &mut self,
+        message: client_message::Message,
+        policy: RetryPolicy,
+    ) -> Result<ServerMessage, ProtocolError> {
+        if !is_idempotent(&message) {
+            return self.request_checked(message);
+        }
+
+        let request = ClientMessage {
+            message: Some(message),
+            idempotency_key: new_idempotency_key(),
+            ..Default::default()
+        };
+        let max_attempts = policy.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let result = if attempt == 1 {
+                Ok(())
+            } else {
+                thread::sleep(policy.backoff);
+                self.drain_and_reconnect()
+            }
+            .and_then(|()| Ok(self.send_request(request.clone())?))
+            .and_then(|()| self.receive());
+
+            match result {
+                Ok(response) => return check_response(response),
+                Err(e) if attempt < max_attempts && is_transient(&e) => {
+                    warn!(
+                        "Request attempt {}/{} failed: {}; retrying",
+                        attempt, max_attempts, e
+                    );
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends `samples` echo requests one after another and times each round
+    /// trip. Fails if `samples` is zero or any echo comes back altered.
+    pub fn measure_rtt(&mut self, samples: usize) -> Result<RttStats, ProtocolError> {
+        if samples == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one sample is required",
+            )
+            .into());
+        }
+
+        let mut rtts = Vec::with_capacity(samples);
+        for i in 0..samples {
+            let content = format!("rtt probe {}", i);
+            let start = Instant::now();
+            let response = self.request_checked(client_message::Message::EchoMessage(EchoMessage {
+                content: content.clone(),
+            }))?;
+            rtts.push(start.elapsed());
+
+            match response.message {
+                Some(server_message::Message::EchoMessage(echo)) if echo.content == content => {}
+                other => return Err(unexpected_reply("the RTT probe echoed back", other)),
+            }
+        }
+
+        rtts.sort();
+        let total: Duration = rtts.iter().sum();
+        let p99_rank = (samples * 99).div_ceil(100);
+        Ok(RttStats {
+            samples,
+            min: rtts[0],
+            max: rtts[samples - 1],
+            mean: total / samples as u32,
+            p99: rtts[p99_rank - 1],
+        })
+    }
+
+    /// Subscribes to `topic` and waits for the server's acknowledgement.
+    /// Messages received in the meantime are kept for `receive`.
+    pub fn subscribe(&mut self, topic: &str) -> Result<(), ProtocolError> {
+        self.send(client_message::Message::Subscribe(Subscribe {
+            topic: topic.to_string(),
+        }))?;
+        self.await_subscribe_ack(topic, true)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), ProtocolError> {
+        self.send(client_message::Message::Unsubscribe(Unsubscribe {
+            topic: topic.to_string(),
+        }))?;
+        self.await_subscribe_ack(topic, false)
+    }
+
+    /// Publishes `content` to `topic` and returns how many subscribers the
+    /// server delivered it to. Messages received in the meantime, such as
+    /// this client's own copy if it is subscribed, are kept for `receive`.
+    pub fn publish(&mut self, topic: &str, content: &str) -> Result<u32, ProtocolError> {
+        self.send(client_message::Message::PublishRequest(PublishRequest {
+            topic: topic.to_string(),
+            content: content.to_string(),
+        }))?;
+        loop {
+            let (message, raw) = self.read_frame()?;
+            match message.message {
+                Some(server_message::Message::PublishResponse(ack)) => return Ok(ack.delivered),
+                Some(server_message::Message::ErrorResponse(error)) => {
+                    return Err(ProtocolError::Server {
+                        code: error.code(),
+                        message: error.message,
+                    })
+                }
+                _ => self.pending.push_back((message, raw)),
+            }
+        }
+    }
+
+    /// Sends a stream echo and passes each echo to `on_chunk` as it arrives,
+    /// returning how many were received. The server answers with exactly
+    /// `request.count` echoes, so the stream ends once that many have
+    /// arrived; a rejected count ends it at once with
+    /// `ProtocolError::Server`. Messages received in the meantime, such as
+    /// topic pushes, are kept for `receive`.
+    pub fn request_stream(
+        &mut self,
+        request: StreamEchoRequest,
+        mut on_chunk: impl FnMut(EchoMessage),
+    ) -> Result<u32, ProtocolError> {
+        let count = request.count;
+        self.send(client_message::Message::StreamEchoRequest(request))?;
+        let mut received = 0;
+        while received < count {
+            let (message, raw) = self.read_frame()?;
+            match message.message {
+                Some(server_message::Message::EchoMessage(echo)) => {
+                    received += 1;
+                    on_chunk(echo);
+                }
+                Some(server_message::Message::ErrorResponse(error)) => {
+                    return Err(ProtocolError::Server {
+                        code: error.code(),
+                        message: error.message,
+                    })
+                }
+                _ => self.pending.push_back((message, raw)),
+            }
+        }
+        Ok(received)
+    }
+
+    /// Switches the connection to full-duplex streaming and splits it into a
+    /// sender and a receiver that can be used from different threads. Once
+    /// upgraded, the server answers requests as they arrive and may push
+    /// messages at any time.
+    pub fn upgrade(mut self) -> Result<(DuplexSender, DuplexReceiver), ProtocolError> {
+        self.send(client_message::Message::Upgrade(Upgrade {}))?;
+        loop {
+            let (message, raw) = self.read_frame()?;
+            match message.message {
+                Some(server_message::Message::UpgradeAck(_)) => break,
+                _ => self.pending.push_back((message, raw)),
+            }
+        }
+        self.split()
+    }
+
+    /// Splits the connection into a sending and a receiving half that can
+    /// be moved to different threads, e.g. to pipeline requests from one
+    /// thread while another reads the replies. Messages already buffered by
+    /// this client are handed to the receiving half.
+    ///
+    /// The halves have no read timeout: a receiver may wait as long as it
+    /// takes for the server to push something.
+    pub fn split(mut self) -> Result<(DuplexSender, DuplexReceiver), ProtocolError> {
+        let stream = self.stream.take().ok_or(ProtocolError::NotConnected)?;
+        stream.set_read_timeout(None)?;
+        let sender = DuplexSender {
+            stream: stream.try_clone()?,
+            codec: self.codec,
+            frame_sync: self.frame_sync,
+            compression: self.compression,
+        };
+        let receiver = DuplexReceiver {
+            stream,
+            codec: self.codec,
+            frame_sync: self.frame_sync,
+            max_receive_size: self.max_receive_size,
+            pending: self.pending.drain(..).map(|(message, _)| message).collect(),
+        };
+        Ok((sender, receiver))
+    }
+
+    /// Iterates over messages as they arrive, including server pushes. Ends
+    /// once the connection is closed or after the first error.
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming {
+            client: self,
+            done: false,
+        }
+    }
+
+    /// Resolves the configured host and port, or reuses the previous result
+    /// when re-resolving is disabled. Never returns an empty list.
+    fn resolve(&mut self) -> io::Result<Vec<SocketAddr>> {
+        if !self.resolve_on_connect {
+            if let Some(ref addrs) = self.resolved {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let address = format!("{}:{}", self.ip, self.port);
+        let addrs = (self.resolver)(&address)?;
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid IP or port",
+            ));
+        }
+        if !self.resolve_on_connect {
+            self.resolved = Some(addrs.clone());
+        }
+        Ok(addrs)
+    }
+
+    fn await_subscribe_ack(&mut self, topic: &str, subscribed: bool) -> Result<(), ProtocolError> {
+        loop {
+            let (message, raw) = self.read_frame()?;
+            match message.message {
+                Some(server_message::Message::SubscribeAck(ref ack))
+                    if ack.topic == topic && ack.subscribed == subscribed =>
+                {
+                    return Ok(());
+                }
+                _ => self.pending.push_back((message, raw)),
+            }
+        }
+    }
+
+    /// Waits out the delay requested by the last `Throttle`, if any.
+    fn honor_throttle(&mut self) {
+        if let Some(resume_at) = self.resume_sending_at.take() {
+            let now = Instant::now();
+            if resume_at > now {
+                thread::sleep(resume_at - now);
+            }
+        }
+    }
+
+    /// Reads the next message, consuming any `Throttle` hints on the way:
+    /// they only delay later sends and are never returned.
+    fn read_frame(&mut self) -> io::Result<(ServerMessage, Vec<u8>)> {
+        let result = self.read_next_frame();
+        self.record_outcome(result.is_ok());
+        result
+    }
+
+    fn record_outcome(&mut self, succeeded: bool) {
+        if succeeded {
+            self.last_activity = Instant::now();
+        } else {
+            self.failed = true;
+        }
+    }
+
+    /// Checks for a close by the server before writing, but only on a
+    /// connection idle past `IDLE_PROBE_THRESHOLD`. Between back-to-back
+    /// requests a close is reported by the next `receive` instead.
+    fn check_open(&self) -> io::Result<()> {
+        match self.stream {
+            Some(ref stream) if self.last_activity.elapsed() >= IDLE_PROBE_THRESHOLD => {
+                ensure_open(stream)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read_next_frame(&mut self) -> io::Result<(ServerMessage, Vec<u8>)> {
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
-            
-            // Read message length
-            let mut len_buf = [0u8; 4];
-            stream.read_exact(&mut len_buf)?;
-            let message_len = u32::from_be_bytes(len_buf) as usize;
-
-            // Read the message
-            let mut buffer = vec![0u8; message_len];
-            stream.read_exact(&mut buffer)?;
-
-            ServerMessage::decode(&buffer[..]).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to decode ServerMessage: {}", e),
-                )
-            })
+            loop {
+                match self.codec.read_frame(stream, self.frame_sync, self.max_receive_size) {
+                    Ok(buffer) => {
+                        let message = decode_server_message(self.codec, &buffer)?;
+                        if let Some(server_message::Message::Throttle(throttle)) = message.message
+                        {
+                            let delay = Duration::from_millis(throttle.retry_after_ms.into());
+                            info!("Server is overloaded; holding requests for {:?}", delay);
+                            self.resume_sending_at = Some(Instant::now() + delay);
+                            continue;
+                        }
+                        return Ok((message, buffer));
+                    }
+                    Err(e) if framing::is_reset(&e) => {
+                        // Whatever was buffered belongs to the old framing.
+                        self.pending.clear();
+                        framing::write_reset(stream, self.frame_sync)?;
+                        return Err(e);
+                    }
+                    // Unread frame bytes would be misread as the next frame.
+                    Err(e) if leaves_stream_misaligned(&e) => {
+                        warn!("Lost frame alignment ({}); closing the connection", e);
+                        self.pending.clear();
+                        self.stream = None;
+                        return Err(e);
+                    }
+                    // How a read timeout surfaces depends on the platform.
+                    Err(e) if is_timeout(&e) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Timed out waiting for the server",
+                        ));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
         } else {
             error!("No active connection");
             Err(io::Error::new(io::ErrorKind::NotConnected, "No active connection"))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Sending half of a split or upgraded connection.
+pub struct DuplexSender {
+    stream: Stream,
+    codec: Codec,
+    frame_sync: bool,
+    compression: Option<usize>,
+}
+
+impl DuplexSender {
+    pub fn send(&mut self, message: client_message::Message) -> Result<(), ProtocolError> {
+        let client_message = ClientMessage {
+            message: Some(message),
+            ..Default::default()
+        };
+        let payload = client_message.encode_to_vec();
+        write_frame(
+            self.codec,
+            self.compression,
+            &mut self.stream,
+            &payload,
+            self.frame_sync,
+        )?;
+        Ok(())
+    }
+
+    /// Closes the sending direction; the receiver keeps reading until the
+    /// server closes its side.
+    pub fn close(self) -> Result<(), ProtocolError> {
+        Ok(self.stream.shutdown(std::net::Shutdown::Write)?)
+    }
+}
+
+/// Receiving half of a split or upgraded connection.
+pub struct DuplexReceiver {
+    stream: Stream,
+    codec: Codec,
+    frame_sync: bool,
+    max_receive_size: usize,
+    pending: VecDeque<ServerMessage>,
+}
+
+impl DuplexReceiver {
+    pub fn receive(&mut self) -> Result<ServerMessage, ProtocolError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        let buffer = self
+            .codec
+            .read_frame(&mut self.stream, self.frame_sync, self.max_receive_size)
+            .inspect_err(|e| {
+                if leaves_stream_misaligned(e) {
+                    let _ = self.stream.shutdown(std::net::Shutdown::Read);
+                }
+            })?;
+        Ok(decode_server_message(self.codec, &buffer)?)
+    }
+}
+
+pub struct Incoming<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<ServerMessage, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.client.receive() {
+            Ok(message) => Some(Ok(message)),
+            Err(e) => {
+                self.done = true;
+                match e {
+                    ProtocolError::NotConnected => None,
+                    ProtocolError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+                    e => Some(Err(e)),
+                }
+            }
+        }
+    }
+}
+
+/// A fixed set of connected clients to one server, shared between threads.
+/// `get` hands out a client for exclusive use and it returns to the pool
+/// when the `PooledClient` is dropped.
+///
+/// The server keeps a worker on each connection while it waits for the
+/// connection's next request, so a pool larger than the server's thread
+/// pool leaves some clients stalled until an idle connection times out.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    idle: Mutex<Vec<Client>>,
+    returned: Condvar,
+    size: usize,
+}
+
+impl ClientPool {
+    /// Opens `size` connections to `ip:port`, failing with the first
+    /// connect error. `size` must be at least 1.
+    pub fn new(ip: &str, port: u32, timeout_ms: u64, size: usize) -> Result<Self, ProtocolError> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client pool size must be at least 1",
+            )
+            .into());
+        }
+        let clients = (0..size)
+            .map(|_| {
+                let mut client = Client::new(ip, port, timeout_ms);
+                client.connect().map(|()| client)
+            })
+            .collect::<Result<Vec<_>, ProtocolError>>()?;
+        Ok(ClientPool {
+            inner: Arc::new(PoolInner {
+                idle: Mutex::new(clients),
+                returned: Condvar::new(),
+                size,
+            }),
+        })
+    }
+
+    /// Number of clients in the pool, whether idle or handed out.
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    /// Starts a background thread that pings every idle client each
+    /// `interval` and replaces those that do not answer with a fresh
+    /// connection, so `get` rarely hands out one the server has dropped. A
+    /// replacement that cannot connect yet is retried by the next sweep or
+    /// `get`. The thread exits once the pool and every client borrowed from
+    /// it have been dropped.
+    pub fn with_health_check(self, interval: Duration) -> Self {
+        let pool = Arc::downgrade(&self.inner);
+        thread::spawn(move || {
+            let mut nonce = 0;
+            loop {
+                thread::sleep(interval);
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                pool.sweep(&mut nonce);
+            }
+        });
+        self
+    }
+
+    /// Takes an idle client, waiting for one to be returned if all are in
+    /// use. A client whose last send or receive failed is reconnected
+    /// first; if that fails the error is returned and the client goes back
+    /// to the pool to be retried by the next `get`.
+    pub fn get(&self) -> Result<PooledClient, ProtocolError> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        let mut client = loop {
+            match idle.pop() {
+                Some(client) => break client,
+                None => idle = self.inner.returned.wait(idle).unwrap(),
+            }
+        };
+        drop(idle);
+
+        if client.failed || !client.is_connected() {
+            info!("Reconnecting pooled client after a failure");
+            if let Err(e) = client.drain_and_reconnect() {
+                self.inner.put(client);
+                return Err(e);
+            }
+        }
+        Ok(PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+        })
+    }
+}
+
+impl PoolInner {
+    fn put(&self, client: Client) {
+        self.idle.lock().unwrap().push(client);
+        self.returned.notify_one();
+    }
+
+    /// Pings each idle client, reconnecting those that fail. The clients
+    /// are out of the pool while they are checked, and `get` waits for them
+    /// as for borrowed ones.
+    fn sweep(&self, nonce: &mut u64) {
+        let clients = std::mem::take(&mut *self.idle.lock().unwrap());
+        for mut client in clients {
+            *nonce += 1;
+            let checked = if client.failed || !client.is_connected() {
+                Err(ProtocolError::NotConnected)
+            } else {
+                client.ping(*nonce).map(drop)
+            };
+            if let Err(e) = checked {
+                info!("Evicting pooled connection that failed its health check: {}", e);
+                if let Err(e) = client.drain_and_reconnect() {
+                    warn!("Failed to replace evicted pooled connection: {}", e);
+                }
+            }
+            self.put(client);
+        }
+    }
+}
+
+/// A client borrowed from a `ClientPool`; derefs to `Client`.
+pub struct PooledClient {
+    client: Option<Client>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put(client);
+        }
+    }
+}
+
+/// Connects to `addr` on a background thread, returning `Ok(None)` as soon
+/// as `cancel` is set instead of waiting for the attempt to finish.
+fn connect_or_cancel(
+    addr: SocketAddr,
+    timeout: Duration,
+    cancel: &AtomicBool,
+) -> io::Result<Option<TcpStream>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(TcpStream::connect_timeout(&addr, timeout));
+    });
+    loop {
+        match rx.recv_timeout(CANCEL_POLL_INTERVAL) {
+            Ok(result) => return result.map(Some),
+            Err(RecvTimeoutError::Timeout) if cancel.load(Ordering::SeqCst) => return Ok(None),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("Connect attempt thread exited"));
+            }
+        }
+    }
+}
+
+/// Rejects a message over the server's negotiated limit before it is sent.
+/// The error carries `FrameTooLarge`, so it converts to
+/// `ProtocolError::MessageTooLarge`.
+fn check_send_size(len: usize, limit: Option<usize>) -> io::Result<()> {
+    match limit {
+        Some(max) if len > max => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            FrameTooLarge { len, max },
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_response(response: ServerMessage) -> Result<ServerMessage, ProtocolError> {
+    match response.message {
+        Some(server_message::Message::ErrorResponse(error)) => Err(ProtocolError::Server {
+            code: error.code(),
+            message: error.message,
+        }),
+        _ => Ok(response),
+    }
+}
+
+fn unexpected_reply(expected: &str, reply: Option<server_message::Message>) -> ProtocolError {
+    ProtocolError::DecodeFailed(format!("Expected {}, got {:?}", expected, reply))
+}
+
+/// Writes `payload`, compressed if it is longer than the `compression`
+/// threshold.
+fn write_frame<W: Write>(
+    codec: Codec,
+    compression: Option<usize>,
+    writer: &mut W,
+    payload: &[u8],
+    sync: bool,
+) -> io::Result<()> {
+    if compression.is_some_and(|threshold| payload.len() > threshold) {
+        codec.write_compressed_frame::<ClientMessage, _>(writer, payload, sync)
+    } else {
+        codec.write_frame::<ClientMessage, _>(writer, payload, sync)
+    }
+}
+
+/// Whether resending `message` on a new connection is meaningful.
+fn is_idempotent(message: &client_message::Message) -> bool {
+    match message {
+        client_message::Message::EchoMessage(_)
+        | client_message::Message::AddRequest(_)
+        | client_message::Message::MultiplyRequest(_)
+        | client_message::Message::LimitsRequest(_)
+        | client_message::Message::PingMessage(_)
+        | client_message::Message::StatusRequest(_) => true,
+        client_message::Message::Subscribe(_)
+        | client_message::Message::Unsubscribe(_)
+        | client_message::Message::Upgrade(_)
+        | client_message::Message::StreamEchoRequest(_)
+        | client_message::Message::PublishRequest(_) => false,
+    }
+}
+
+/// Failures after which the request may not have been answered, but a new
+/// connection could succeed.
+fn is_transient(e: &ProtocolError) -> bool {
+    let ProtocolError::Io(e) = e else {
+        return matches!(e, ProtocolError::NotConnected);
+    };
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Whether a read failed part-way through a frame, leaving bytes of it on
+/// the stream: an oversized frame whose payload was not read, a missing
+/// sync marker, or a frame that stalled.
+fn leaves_stream_misaligned(e: &io::Error) -> bool {
+    framing::is_too_large(e) || framing::is_desynced(e) || framing::is_stalled(e)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// A key unique to this request: process id, wall-clock time and a counter,
+/// so concurrent clients and restarted processes do not collide.
+fn new_idempotency_key() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "{:x}-{:x}-{:x}",
+        std::process::id(),
+        nanos,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn decode_server_message(codec: Codec, buffer: &[u8]) -> io::Result<ServerMessage> {
+    codec.decode(buffer).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to decode ServerMessage: {}", e),
+        )
+    })
+}
+
+fn log_connect_error(addr: &SocketAddr, timeout: Duration, e: &io::Error) {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => error!("Connection to {} refused", addr),
+        io::ErrorKind::TimedOut => {
+            error!("Connection to {} timed out after {:?}", addr, timeout)
+        }
+        _ => error!("Failed to connect to {}: {}", addr, e),
+    }
+}
+
+/// Fails with `BrokenPipe` if the peer has closed the connection. Pending
+/// unread data counts as open, since the peer may still be sending.
+fn ensure_open(stream: &Stream) -> io::Result<()> {
+    let mut probe = [0u8; 1];
+    match stream.peek_nonblocking(&mut probe) {
+        Ok(0) => Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "Connection closed by the server",
+        )),
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Orders addresses IPv6, IPv4, IPv6, ... keeping the resolver's order
+/// within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    v6.reverse();
+    v4.reverse();
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+    ordered
+}
@@ -0,0 +1,938 @@
+//! Wire encodings a server and client can agree on. `Protobuf` is the
+//! length-prefixed framing in `framing`; `Json` is one JSON object per line,
+//! for tooling that cannot speak protobuf:
+//!
+//! ```text
+//! {"echo":"hi"}
+//! {"add":{"a":1,"b":2},"priority":5}
+//! ```
+//!
+//! Each message kind is a single key named after it (`echo`, `add`,
+//! `multiply`, `subscribe`, `unsubscribe`, `upgrade`, `limits`, `ping`,
+//! `stream_echo`, `status`, `publish`; replies add `error`,
+//! `subscribe_ack`, `topic_message`, `upgrade_ack`, `throttle` and `pong`),
+//! alongside the request's `priority` and `idempotency_key`. Fields follow
+//! the protobuf field names and default the same way when absent; unknown
+//! fields are ignored. Status codes are written by name, e.g.
+//! `"INVALID_ARGUMENT"`.
+//!
+//! Messages are handled as protobuf internally whichever codec is on the
+//! wire: writers take the protobuf encoding and transcode it, readers return
+//! the payload as received for `decode`. Frame sync markers, RESET frames and
+//! compression only exist in protobuf framing.
+use crate::framing::{self, FrameStalled, FrameTooLarge};
+use crate::message::{
+    client_message, server_message, AddRequest, AddResponse, ClientMessage, EchoMessage,
+    ErrorResponse, LimitsRequest, LimitsResponse, MultiplyRequest, MultiplyResponse, PingMessage,
+    PongMessage, PublishRequest, PublishResponse, ServerMessage, StatusCode, StatusRequest,
+    StatusResponse, StreamEchoRequest,
+    Subscribe, SubscribeAck, Throttle, TopicMessage, Unsubscribe, Upgrade, UpgradeAck,
+};
+use prost::Message;
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// Deepest nesting a JSON message may use; real messages need two levels.
+const MAX_JSON_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+/// A message with a JSON form, so either codec can carry it.
+pub trait JsonMessage: Message + Default + Sized {
+    fn to_json(&self) -> String;
+    fn from_json(text: &str) -> io::Result<Self>;
+}
+
+impl Codec {
+    /// Writes one message given its protobuf encoding, transcoding it for
+    /// the JSON codec.
+    pub fn write_frame<M: JsonMessage, W: Write>(
+        self,
+        writer: &mut W,
+        payload: &[u8],
+        sync: bool,
+    ) -> io::Result<()> {
+        match self {
+            Codec::Protobuf => framing::write_frame(writer, payload, sync),
+            Codec::Json => {
+                let message = M::decode(payload).map_err(|e| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Failed to decode message: {}", e),
+                    )
+                })?;
+                let mut line = message.to_json().into_bytes();
+                line.push(b'\n');
+                writer.write_all(&line)?;
+                writer.flush()
+            }
+        }
+    }
+
+    /// Like `write_frame`, but compresses the frame where the codec
+    /// supports it (see `framing::write_compressed_frame`). JSON lines are
+    /// written as they are.
+    pub fn write_compressed_frame<M: JsonMessage, W: Write>(
+        self,
+        writer: &mut W,
+        payload: &[u8],
+        sync: bool,
+    ) -> io::Result<()> {
+        match self {
+            Codec::Protobuf => framing::write_compressed_frame(writer, payload, sync),
+            Codec::Json => self.write_frame::<M, W>(writer, payload, sync),
+        }
+    }
+
+    /// Like `framing::read_frame`, for either codec.
+    pub fn read_frame<R: Read>(
+        self,
+        reader: &mut R,
+        sync: bool,
+        max_len: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.try_read_frame(reader, sync, max_len)?
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Connection closed"))
+    }
+
+    /// Like `framing::try_read_frame`, for either codec. A JSON line is
+    /// returned without its line ending; blank lines are skipped.
+    pub fn try_read_frame<R: Read>(
+        self,
+        reader: &mut R,
+        sync: bool,
+        max_len: usize,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Codec::Protobuf => framing::try_read_frame(reader, sync, max_len),
+            Codec::Json => loop {
+                match read_line(reader, max_len)? {
+                    Some(line) if line.iter().all(u8::is_ascii_whitespace) => continue,
+                    line => return Ok(line),
+                }
+            },
+        }
+    }
+
+    /// Decodes a payload returned by `read_frame`. Failures are `InvalidData`.
+    pub fn decode<M: JsonMessage>(self, payload: &[u8]) -> io::Result<M> {
+        match self {
+            Codec::Protobuf => M::decode(payload)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string())),
+            Codec::Json => {
+                let text = std::str::from_utf8(payload).map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "line is not valid UTF-8")
+                })?;
+                M::from_json(text)
+            }
+        }
+    }
+}
+
+/// Reads up to the next `\n`, dropping it and any `\r` before it. Reads a
+/// byte at a time so nothing past the line is consumed; buffer the reader
+/// if that matters. Timeouts behave as in `framing::try_read_frame`.
+fn read_line<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut stalls = 0;
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("Connection closed mid-line after {} bytes", line.len()),
+                ))
+            }
+            Ok(_) if byte[0] == b'\n' => {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(Some(line));
+            }
+            Ok(_) if line.len() == max_len => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    FrameTooLarge {
+                        len: line.len() + 1,
+                        max: max_len,
+                    },
+                ))
+            }
+            Ok(_) => line.push(byte[0]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(ref e)
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && !line.is_empty() =>
+            {
+                stalls += 1;
+                if stalls >= framing::MID_FRAME_STALL_LIMIT {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        FrameStalled { read: line.len() },
+                    ));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl JsonMessage for ClientMessage {
+    fn to_json(&self) -> String {
+        use client_message::Message::*;
+        let mut fields = Vec::new();
+        if let Some(ref message) = self.message {
+            let (kind, body) = match message {
+                EchoMessage(echo) => ("echo", Value::string(&echo.content)),
+                AddRequest(add) => (
+                    "add",
+                    Value::object([
+                        ("a", Value::int(add.a)),
+                        ("b", Value::int(add.b)),
+                        ("request_id", Value::int(add.request_id)),
+                    ]),
+                ),
+                MultiplyRequest(multiply) => (
+                    "multiply",
+                    Value::object([("a", Value::int(multiply.a)), ("b", Value::int(multiply.b))]),
+                ),
+                Subscribe(subscribe) => ("subscribe", Value::string(&subscribe.topic)),
+                Unsubscribe(unsubscribe) => ("unsubscribe", Value::string(&unsubscribe.topic)),
+                Upgrade(_) => ("upgrade", Value::object([])),
+                LimitsRequest(_) => ("limits", Value::object([])),
+                PingMessage(ping) => ("ping", Value::object([("nonce", Value::int(ping.nonce))])),
+                StreamEchoRequest(stream) => (
+                    "stream_echo",
+                    Value::object([
+                        ("content", Value::string(&stream.content)),
+                        ("count", Value::int(stream.count)),
+                    ]),
+                ),
+                StatusRequest(_) => ("status", Value::object([])),
+                PublishRequest(publish) => (
+                    "publish",
+                    Value::object([
+                        ("topic", Value::string(&publish.topic)),
+                        ("content", Value::string(&publish.content)),
+                    ]),
+                ),
+            };
+            fields.push((kind.to_string(), body));
+        }
+        if self.priority != 0 {
+            fields.push(("priority".to_string(), Value::int(self.priority)));
+        }
+        if !self.idempotency_key.is_empty() {
+            fields.push((
+                "idempotency_key".to_string(),
+                Value::string(&self.idempotency_key),
+            ));
+        }
+        Value::Object(fields).to_string()
+    }
+
+    fn from_json(text: &str) -> io::Result<Self> {
+        use client_message::Message as Kind;
+        let mut request = ClientMessage::default();
+        for (key, value) in Value::parse(text)?.into_object("message")? {
+            let message = match key.as_str() {
+                "priority" => {
+                    request.priority = value.to_int(&key)?;
+                    continue;
+                }
+                "idempotency_key" => {
+                    request.idempotency_key = value.into_string(&key)?;
+                    continue;
+                }
+                "echo" => Kind::EchoMessage(EchoMessage {
+                    content: value.into_string(&key)?,
+                }),
+                "add" => {
+                    let add = Fields::new(value, &key)?;
+                    Kind::AddRequest(AddRequest {
+                        a: add.int("a")?,
+                        b: add.int("b")?,
+                        request_id: add.int("request_id")?,
+                    })
+                }
+                "multiply" => {
+                    let multiply = Fields::new(value, &key)?;
+                    Kind::MultiplyRequest(MultiplyRequest {
+                        a: multiply.int("a")?,
+                        b: multiply.int("b")?,
+                    })
+                }
+                "subscribe" => Kind::Subscribe(Subscribe {
+                    topic: value.into_string(&key)?,
+                }),
+                "unsubscribe" => Kind::Unsubscribe(Unsubscribe {
+                    topic: value.into_string(&key)?,
+                }),
+                "upgrade" => {
+                    Fields::new(value, &key)?;
+                    Kind::Upgrade(Upgrade {})
+                }
+                "limits" => {
+                    Fields::new(value, &key)?;
+                    Kind::LimitsRequest(LimitsRequest {})
+                }
+                "ping" => Kind::PingMessage(PingMessage {
+                    nonce: Fields::new(value, &key)?.int("nonce")?,
+                }),
+                "stream_echo" => {
+                    let stream = Fields::new(value, &key)?;
+                    Kind::StreamEchoRequest(StreamEchoRequest {
+                        content: stream.string("content")?,
+                        count: stream.int("count")?,
+                    })
+                }
+                "status" => {
+                    Fields::new(value, &key)?;
+                    Kind::StatusRequest(StatusRequest {})
+                }
+                "publish" => {
+                    let publish = Fields::new(value, &key)?;
+                    Kind::PublishRequest(PublishRequest {
+                        topic: publish.string("topic")?,
+                        content: publish.string("content")?,
+                    })
+                }
+                _ => continue,
+            };
+            if request.message.replace(message).is_some() {
+                return Err(invalid("message has more than one kind"));
+            }
+        }
+        if request.message.is_none() {
+            return Err(invalid("message has no known kind"));
+        }
+        Ok(request)
+    }
+}
+
+impl JsonMessage for ServerMessage {
+    fn to_json(&self) -> String {
+        use server_message::Message::*;
+        let Some(ref message) = self.message else {
+            return Value::object([]).to_string();
+        };
+        let (kind, body) = match message {
+            EchoMessage(echo) => ("echo", Value::string(&echo.content)),
+            AddResponse(add) => (
+                "add",
+                Value::object([
+                    ("result", Value::int(add.result)),
+                    ("a", Value::int(add.a)),
+                    ("b", Value::int(add.b)),
+                    ("operation", Value::string(&add.operation)),
+                    ("request_id", Value::int(add.request_id)),
+                ]),
+            ),
+            ErrorResponse(error) => (
+                "error",
+                Value::object([
+                    ("code", Value::string(error.code().as_str_name())),
+                    ("message", Value::string(&error.message)),
+                ]),
+            ),
+            SubscribeAck(ack) => (
+                "subscribe_ack",
+                Value::object([
+                    ("topic", Value::string(&ack.topic)),
+                    ("subscribed", Value::Bool(ack.subscribed)),
+                ]),
+            ),
+            TopicMessage(message) => (
+                "topic_message",
+                Value::object([
+                    ("topic", Value::string(&message.topic)),
+                    ("content", Value::string(&message.content)),
+                ]),
+            ),
+            UpgradeAck(_) => ("upgrade_ack", Value::object([])),
+            LimitsResponse(limits) => (
+                "limits",
+                Value::object([("max_message_size", Value::int(limits.max_message_size))]),
+            ),
+            MultiplyResponse(multiply) => (
+                "multiply",
+                Value::object([("result", Value::int(multiply.result))]),
+            ),
+            Throttle(throttle) => (
+                "throttle",
+                Value::object([("retry_after_ms", Value::int(throttle.retry_after_ms))]),
+            ),
+            PongMessage(pong) => (
+                "pong",
+                Value::object([
+                    ("nonce", Value::int(pong.nonce)),
+                    ("active_connections", Value::int(pong.active_connections)),
+                    ("queue_depth", Value::int(pong.queue_depth)),
+                    ("error_rate", Value::float(pong.error_rate)),
+                ]),
+            ),
+            StatusResponse(status) => (
+                "status",
+                Value::object([
+                    ("uptime_secs", Value::int(status.uptime_secs)),
+                    ("active_connections", Value::int(status.active_connections)),
+                    ("total_requests", Value::int(status.total_requests)),
+                ]),
+            ),
+            PublishResponse(publish) => (
+                "publish",
+                Value::object([("delivered", Value::int(publish.delivered))]),
+            ),
+        };
+        Value::object([(kind, body)]).to_string()
+    }
+
+    fn from_json(text: &str) -> io::Result<Self> {
+        use server_message::Message as Kind;
+        let mut response = ServerMessage::default();
+        for (key, value) in Value::parse(text)?.into_object("message")? {
+            let message = match key.as_str() {
+                "echo" => Kind::EchoMessage(EchoMessage {
+                    content: value.into_string(&key)?,
+                }),
+                "add" => {
+                    let add = Fields::new(value, &key)?;
+                    Kind::AddResponse(AddResponse {
+                        result: add.int("result")?,
+                        a: add.int("a")?,
+                        b: add.int("b")?,
+                        operation: add.string("operation")?,
+                        request_id: add.int("request_id")?,
+                    })
+                }
+                "error" => {
+                    let error = Fields::new(value, &key)?;
+                    let code = error.string("code")?;
+                    let code = StatusCode::from_str_name(&code)
+                        .ok_or_else(|| invalid(format!("unknown status code `{}`", code)))?;
+                    Kind::ErrorResponse(ErrorResponse {
+                        code: code as i32,
+                        message: error.string("message")?,
+                    })
+                }
+                "subscribe_ack" => {
+                    let ack = Fields::new(value, &key)?;
+                    Kind::SubscribeAck(SubscribeAck {
+                        topic: ack.string("topic")?,
+                        subscribed: ack.bool("subscribed")?,
+                    })
+                }
+                "topic_message" => {
+                    let message = Fields::new(value, &key)?;
+                    Kind::TopicMessage(TopicMessage {
+                        topic: message.string("topic")?,
+                        content: message.string("content")?,
+                    })
+                }
+                "upgrade_ack" => {
+                    Fields::new(value, &key)?;
+                    Kind::UpgradeAck(UpgradeAck {})
+                }
+                "limits" => Kind::LimitsResponse(LimitsResponse {
+                    max_message_size: Fields::new(value, &key)?.int("max_message_size")?,
+                }),
+                "multiply" => Kind::MultiplyResponse(MultiplyResponse {
+                    result: Fields::new(value, &key)?.int("result")?,
+                }),
+                "throttle" => Kind::Throttle(Throttle {
+                    retry_after_ms: Fields::new(value, &key)?.int("retry_after_ms")?,
+                }),
+                "pong" => {
+                    let pong = Fields::new(value, &key)?;
+                    Kind::PongMessage(PongMessage {
+                        nonce: pong.int("nonce")?,
+                        active_connections: pong.int("active_connections")?,
+                        queue_depth: pong.int("queue_depth")?,
+                        error_rate: pong.float("error_rate")?,
+                    })
+                }
+                "status" => {
+                    let status = Fields::new(value, &key)?;
+                    Kind::StatusResponse(StatusResponse {
+                        uptime_secs: status.int("uptime_secs")?,
+                        active_connections: status.int("active_connections")?,
+                        total_requests: status.int("total_requests")?,
+                    })
+                }
+                "publish" => Kind::PublishResponse(PublishResponse {
+                    delivered: Fields::new(value, &key)?.int("delivered")?,
+                }),
+                _ => continue,
+            };
+            if response.message.replace(message).is_some() {
+                return Err(invalid("message has more than one kind"));
+            }
+        }
+        Ok(response)
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// A parsed JSON value. Numbers keep their source text so 64-bit integers
+/// convert without passing through `f64`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn int(n: impl ToString) -> Value {
+        Value::Number(n.to_string())
+    }
+
+    fn float(n: f32) -> Value {
+        Value::Number(n.to_string())
+    }
+
+    fn object<const N: usize>(fields: [(&str, Value); N]) -> Value {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    fn parse(text: &str) -> io::Result<Value> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn into_object(self, name: &str) -> io::Result<Vec<(String, Value)>> {
+        match self {
+            Value::Object(fields) => Ok(fields),
+            _ => Err(invalid(format!("`{}` must be an object", name))),
+        }
+    }
+
+    fn into_string(self, name: &str) -> io::Result<String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(invalid(format!("`{}` must be a string", name))),
+        }
+    }
+
+    fn to_int<T: TryFrom<i128>>(&self, name: &str) -> io::Result<T> {
+        let Value::Number(text) = self else {
+            return Err(invalid(format!("`{}` must be an integer", name)));
+        };
+        text.parse::<i128>()
+            .ok()
+            .and_then(|n| T::try_from(n).ok())
+            .ok_or_else(|| invalid(format!("`{}` is not an integer in range: {}", name, text)))
+    }
+
+    fn to_float(&self, name: &str) -> io::Result<f32> {
+        let Value::Number(text) = self else {
+            return Err(invalid(format!("`{}` must be a number", name)));
+        };
+        text.parse::<f32>()
+            .map_err(|_| invalid(format!("`{}` is not a number: {}", name, text)))
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => f.write_str(n),
+            Value::String(s) => write_json_string(f, s),
+            Value::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut impl std::fmt::Write, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// The fields of a message body, looked up by name. Absent fields take
+/// their protobuf default.
+struct Fields(Vec<(String, Value)>);
+
+impl Fields {
+    fn new(value: Value, name: &str) -> io::Result<Fields> {
+        value.into_object(name).map(Fields)
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn int<T: TryFrom<i128> + Default>(&self, name: &str) -> io::Result<T> {
+        self.get(name)
+            .map_or(Ok(T::default()), |value| value.to_int(name))
+    }
+
+    fn float(&self, name: &str) -> io::Result<f32> {
+        self.get(name)
+            .map_or(Ok(0.0), |value| value.to_float(name))
+    }
+
+    fn string(&self, name: &str) -> io::Result<String> {
+        self.get(name)
+            .map_or(Ok(String::new()), |value| value.clone().into_string(name))
+    }
+
+    fn bool(&self, name: &str) -> io::Result<bool> {
+        match self.get(name) {
+            None => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(_) => Err(invalid(format!("`{}` must be a boolean", name))),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> io::Error {
+        invalid(format!("invalid JSON at byte {}: {}", self.pos, what))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|b| b" \t\r\n".contains(b))
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected `{}`", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> io::Result<Value> {
+        if !self.text[self.pos..].starts_with(word.as_bytes()) {
+            return Err(self.error("unexpected character"));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_JSON_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        match self.peek() {
+            None => Err(self.error("unexpected end of input")),
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> io::Result<Value> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value(depth + 1)?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> io::Result<Value> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> io::Result<Value> {
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_digit() || b"+-.eE".contains(b))
+        {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        if text.parse::<f64>().is_err() {
+            return Err(self.error("malformed number"));
+        }
+        Ok(Value::Number(text.to_string()))
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("malformed \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let Some(&byte) = self.text.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.pos) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"))?
+                        }
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    let mut buf = [0u8; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                0x00..=0x1f => return Err(self.error("control character in string")),
+                _ => out.push(byte),
+            }
+        }
+        // The input is a `str` and escapes add whole characters, so `out`
+        // is valid UTF-8.
+        Ok(String::from_utf8(out).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_requests_round_trip() {
+        let requests = [
+            r#"{"echo":"hi \"there\"\n"}"#,
+            r#"{"add":{"a":-1,"b":2,"request_id":18446744073709551615},"priority":5}"#,
+            r#"{"ping":{"nonce":7},"idempotency_key":"k1"}"#,
+            r#"{"stream_echo":{"content":"x","count":3}}"#,
+        ];
+        for text in requests {
+            let request = ClientMessage::from_json(text).unwrap();
+            assert_eq!(request.to_json(), text);
+        }
+        let add = ClientMessage::from_json(r#" { "add" : { "b" : 2 } } "#).unwrap();
+        match add.message {
+            Some(client_message::Message::AddRequest(add)) => assert_eq!((add.a, add.b), (0, 2)),
+            other => panic!("Expected AddRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_responses_round_trip() {
+        let error = ServerMessage {
+            message: Some(server_message::Message::ErrorResponse(ErrorResponse {
+                code: StatusCode::InvalidArgument as i32,
+                message: "caf\u{e9}\u{1}".to_string(),
+            })),
+        };
+        let text = error.to_json();
+        assert_eq!(
+            text,
+            "{\"error\":{\"code\":\"INVALID_ARGUMENT\",\"message\":\"caf\u{e9}\\u0001\"}}"
+        );
+        assert_eq!(ServerMessage::from_json(&text).unwrap(), error);
+
+        let pong = ServerMessage {
+            message: Some(server_message::Message::PongMessage(PongMessage {
+                nonce: 3,
+                active_connections: 2,
+                queue_depth: 0,
+                error_rate: 0.25,
+            })),
+        };
+        let text = pong.to_json();
+        assert_eq!(
+            text,
+            r#"{"pong":{"nonce":3,"active_connections":2,"queue_depth":0,"error_rate":0.25}}"#
+        );
+        assert_eq!(ServerMessage::from_json(&text).unwrap(), pong);
+    }
+
+    #[test]
+    fn test_invalid_json_is_rejected() {
+        let invalid = [
+            "",
+            "{",
+            r#"{"echo":1}"#,
+            r#"{"add":{"a":2147483648}}"#,
+            r#"{"add":{"a":1.5}}"#,
+            r#"{"echo":"a","ping":{}}"#,
+            r#"{"unknown":{}}"#,
+            r#"{"echo":"a"} x"#,
+            &"[".repeat(100),
+        ];
+        for text in invalid {
+            let err = ClientMessage::from_json(text).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", text);
+        }
+        let unicode = ClientMessage::from_json(r#"{"echo":"é😀"}"#).unwrap();
+        assert_eq!(unicode.to_json(), "{\"echo\":\"\u{e9}\u{1f600}\"}");
+    }
+
+    #[test]
+    fn test_json_lines_are_framed_by_newlines() {
+        let request = ClientMessage::from_json(r#"{"echo":"hi"}"#).unwrap();
+        let mut wire = Vec::new();
+        Codec::Json
+            .write_frame::<ClientMessage, _>(&mut wire, &request.encode_to_vec(), false)
+            .unwrap();
+        assert_eq!(wire, b"{\"echo\":\"hi\"}\n");
+
+        let mut reader = Cursor::new(b"\r\n{\"echo\":\"hi\"}\r\n{\"echo\":\"cut".to_vec());
+        let line = Codec::Json.read_frame(&mut reader, false, 64).unwrap();
+        assert_eq!(Codec::Json.decode::<ClientMessage>(&line).unwrap(), request);
+        let err = Codec::Json.read_frame(&mut reader, false, 64).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut long = Cursor::new(b"{\"echo\":\"too long\"}\n".to_vec());
+        let err = Codec::Json.read_frame(&mut long, false, 8).unwrap_err();
+        assert!(framing::is_too_large(&err));
+        assert!(Codec::Json
+            .try_read_frame(&mut Cursor::new(b""), false, 8)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,597 @@
+//! Raw DEFLATE (RFC 1951) for compressed frames. The encoder finds repeats
+//! through hash chains and emits one fixed-Huffman block, which does well on
+//! the repetitive text frames are compressed for; the decoder accepts any
+//! valid stream, including ones from zlib or other encoders.
+use crate::framing::FrameTooLarge;
+use std::io::{self, ErrorKind};
+
+/// How far back a match may start; the most DEFLATE allows.
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Earlier positions tried per match before settling for the best so far.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const NO_POS: usize = usize::MAX;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which a dynamic block lists the code length code's lengths.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    out.bits(1, 1); // last block
+    out.bits(1, 2); // fixed Huffman codes
+
+    let mut head = vec![NO_POS; 1 << HASH_BITS];
+    let mut prev = vec![NO_POS; WINDOW_SIZE];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, dist) = longest_match(data, pos, &head, &prev);
+        let step = if len >= MIN_MATCH {
+            write_length(&mut out, len);
+            write_distance(&mut out, dist);
+            len
+        } else {
+            write_symbol(&mut out, data[pos] as u16);
+            1
+        };
+        for p in pos..pos + step {
+            if p + MIN_MATCH <= data.len() {
+                let h = hash(data, p);
+                prev[p % WINDOW_SIZE] = head[h];
+                head[h] = p;
+            }
+        }
+        pos += step;
+    }
+    write_symbol(&mut out, END_OF_BLOCK);
+    out.finish()
+}
+
+/// Inflates `data`, failing with `FrameTooLarge` as soon as the output
+/// would exceed `max_len`, so a small frame cannot expand without bound.
+pub(crate) fn decompress(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut input = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => copy_stored(&mut input, &mut out, max_len)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut input, &mut out, &literals, &distances, max_len)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut input)?;
+                inflate_block(&mut input, &mut out, &literals, &distances, max_len)?;
+            }
+            _ => return Err(invalid("reserved DEFLATE block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let key = u32::from_be_bytes([0, data[pos], data[pos + 1], data[pos + 2]]);
+    (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// The longest earlier repeat of the bytes at `pos`, as (length, distance);
+/// a length under `MIN_MATCH` means none worth encoding.
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max_len = MAX_MATCH.min(data.len() - pos);
+    let (mut best_len, mut best_dist) = (0, 0);
+    let mut candidate = head[hash(data, pos)];
+    for _ in 0..MAX_CHAIN {
+        if candidate == NO_POS || pos - candidate > WINDOW_SIZE {
+            break;
+        }
+        let len = data[candidate..]
+            .iter()
+            .zip(&data[pos..pos + max_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best_len {
+            (best_len, best_dist) = (len, pos - candidate);
+            if len == max_len {
+                break;
+            }
+        }
+        // Slots are reused once a position leaves the window; a link that
+        // does not lead further back belongs to a later position.
+        let next = prev[candidate % WINDOW_SIZE];
+        if next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    (best_len, best_dist)
+}
+
+/// Writes a literal/length symbol with the fixed Huffman code.
+fn write_symbol(out: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    out.huffman(code, len);
+}
+
+fn write_length(out: &mut BitWriter, len: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= len)
+        .unwrap();
+    write_symbol(out, 257 + index as u16);
+    out.bits(
+        (len - LENGTH_BASE[index] as usize) as u32,
+        LENGTH_EXTRA[index] as u32,
+    );
+}
+
+fn write_distance(out: &mut BitWriter, dist: usize) {
+    let index = DIST_BASE
+        .iter()
+        .rposition(|&base| base as usize <= dist)
+        .unwrap();
+    out.huffman(index as u16, 5);
+    out.bits(
+        (dist - DIST_BASE[index] as usize) as u32,
+        DIST_EXTRA[index] as u32,
+    );
+}
+
+fn copy_stored(input: &mut BitReader, out: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+    input.align();
+    let len = input.bits(16)? as usize;
+    let complement = input.bits(16)? as usize;
+    if len != !complement & 0xffff {
+        return Err(invalid("stored block length does not match its complement"));
+    }
+    check_len(out.len() + len, max_len)?;
+    out.extend_from_slice(input.take(len)?);
+    Ok(())
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    max_len: usize,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(input)?;
+        if symbol < END_OF_BLOCK {
+            check_len(out.len() + 1, max_len)?;
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        let index = (symbol - 257) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(invalid("invalid DEFLATE length symbol"));
+        }
+        let len = LENGTH_BASE[index] as usize + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = distances.decode(input)? as usize;
+        if index >= DIST_BASE.len() {
+            return Err(invalid("invalid DEFLATE distance symbol"));
+        }
+        let dist = DIST_BASE[index] as usize + input.bits(DIST_EXTRA[index] as u32)? as usize;
+        if dist > out.len() {
+            return Err(invalid(
+                "DEFLATE distance reaches before the start of the data",
+            ));
+        }
+        check_len(out.len() + len, max_len)?;
+        // Byte by byte: a match may overlap the bytes it produces.
+        let start = out.len() - dist;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    // Built from valid lengths, so these cannot fail.
+    let literals = Huffman::new(&lengths).unwrap();
+    let distances = Huffman::new(&[5; 30]).unwrap();
+    (literals, distances)
+}
+
+fn read_dynamic_codes(input: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_length_count = input.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(invalid("too many DEFLATE codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = input.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_code.decode(input)?;
+        if symbol < 16 {
+            lengths[index] = symbol as u8;
+            index += 1;
+            continue;
+        }
+        let (value, repeat) = match symbol {
+            16 if index == 0 => {
+                return Err(invalid("DEFLATE length repeat with nothing to repeat"))
+            }
+            16 => (lengths[index - 1], 3 + input.bits(2)? as usize),
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(invalid("DEFLATE code lengths overrun"));
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid("DEFLATE block has no end-of-block code"));
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    Ok((
+        Huffman::new(literal_lengths)?,
+        Huffman::new(distance_lengths)?,
+    ))
+}
+
+fn check_len(len: usize, max_len: usize) -> io::Result<()> {
+    if len > max_len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            FrameTooLarge { len, max: max_len },
+        ));
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// A canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    /// Number of codes of each bit length.
+    counts: [u16; 16],
+    /// Symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed DEFLATE code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid DEFLATE code"))
+    }
+}
+
+/// Packs bits least significant first, as DEFLATE stores them.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are stored most significant bit first.
+    fn huffman(&mut self, code: u16, len: u32) {
+        self.bits((code.reverse_bits() >> (16 - len)) as u32, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.count < count {
+            let byte = *self.data.get(self.pos).ok_or_else(truncated)?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << count) - 1) as u32;
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary. Whole bytes are only loaded when
+    /// needed, so any bits left belong to the current byte.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+fn truncated() -> io::Error {
+    invalid("DEFLATE data ends early")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::is_too_large;
+
+    #[test]
+    fn test_round_trip() {
+        let log: Vec<u8> = (0..2000)
+            .flat_map(|i| {
+                format!(
+                    "2024-01-01T00:00:{:02} INFO request {} handled\n",
+                    i % 60,
+                    i
+                )
+                .into_bytes()
+            })
+            .collect();
+        // A simple generator, so the data has no repeats to find.
+        let mut state = 0x2545_f491u32;
+        let noise: Vec<u8> = (0..5000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        for data in [
+            &b""[..],
+            b"a",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            &log,
+            &noise,
+        ] {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(compress(&log).len() < log.len() / 4);
+    }
+
+    /// Xorshift, so failures reproduce from the seed.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() as usize % n
+        }
+    }
+
+    /// Data mixing literals from a small alphabet, runs, and copies of
+    /// earlier output at any distance the window allows.
+    fn arbitrary_data(rng: &mut Rng, len: usize) -> Vec<u8> {
+        let alphabet = 1 + rng.below(256);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            match rng.below(4) {
+                0 if !data.is_empty() => {
+                    let distance = 1 + rng.below(data.len().min(40_000));
+                    let start = data.len() - distance;
+                    for i in 0..rng.below(300) {
+                        data.push(data[start + i % distance]);
+                    }
+                }
+                1 => {
+                    let byte = rng.below(alphabet) as u8;
+                    let run = rng.below(600);
+                    data.resize(data.len() + run, byte);
+                }
+                _ => data.push(rng.below(alphabet) as u8),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn test_round_trip_arbitrary_data() {
+        let mut rng = Rng(0x9e37_79b9);
+        for case in 0..300 {
+            let len = match case % 3 {
+                0 => rng.below(64),
+                1 => rng.below(4096),
+                _ => rng.below(70_000),
+            };
+            let data = arbitrary_data(&mut rng, len);
+            let compressed = compress(&data);
+            let inflated = decompress(&compressed, data.len())
+                .unwrap_or_else(|e| panic!("case {} ({} bytes): {}", case, len, e));
+            assert!(inflated == data, "case {} ({} bytes) did not round-trip", case, len);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_input_never_panics_or_overruns() {
+        let mut rng = Rng(0x2545_f491);
+        for _ in 0..2000 {
+            let len = 1 + rng.below(2000);
+            let data = arbitrary_data(&mut rng, len);
+            let mut compressed = compress(&data);
+            match rng.below(3) {
+                0 => compressed.truncate(rng.below(compressed.len())),
+                1 => {
+                    for _ in 0..1 + rng.below(4) {
+                        let i = rng.below(compressed.len());
+                        compressed[i] ^= 1 << rng.below(8);
+                    }
+                }
+                _ => compressed.iter_mut().for_each(|b| *b = rng.next() as u8),
+            }
+            let max_len = rng.below(4000);
+            if let Ok(inflated) = decompress(&compressed, max_len) {
+                assert!(inflated.len() <= max_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decodes_zlib_streams() {
+        // zlib's raw DEFLATE of the text: at level 0, a stored block.
+        let stored = [
+            0x01, 0x18, 0x00, 0xe7, 0xff, b'h', b'e', b'l', b'l', b'o', b' ', b'h', b'e', b'l',
+            b'l', b'o', b' ', b'h', b'e', b'l', b'l', b'o', b' ', b'h', b'e', b'l', b'l', b'o',
+            b'\n',
+        ];
+        assert_eq!(
+            decompress(&stored, 1024).unwrap(),
+            b"hello hello hello hello\n"
+        );
+
+        // At level 9, a dynamic Huffman block.
+        let dynamic = [
+            0x25, 0x8c, 0xb1, 0x0d, 0x00, 0x40, 0x08, 0x02, 0x67, 0x25, 0x57, 0xb0, 0x00, 0xfb,
+            0xe7, 0xc1, 0xb7, 0x51, 0xe0, 0x04, 0x59, 0xe9, 0xa0, 0x74, 0x2b, 0xa6, 0x46, 0x27,
+            0x44, 0xd4, 0x36, 0xa4, 0x84, 0xb0, 0x5c, 0xe5, 0x31, 0xe7, 0x9f, 0x19, 0x57, 0x2e,
+            0x00, 0x0d, 0xf4, 0xd8, 0x1e, 0xeb, 0x49, 0xa6, 0x0a, 0x2c, 0xfc, 0x9d, 0x72, 0x6b,
+            0xf6, 0x0a, 0x0f,
+        ];
+        let text = "cagattttcatattatgcagaaaatctacttcgcctgatacgagtcggttatcttcggatactgtatagtc\
+                    ccacctggtgatcctatgcttgtgagtacccagaaaatagcgacggacc";
+        assert_eq!(decompress(&dynamic, 1024).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let compressed = compress(&[b'x'; 10_000]);
+        assert!(compressed.len() < 100);
+        let err = decompress(&compressed, 9_999).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(is_too_large(&err));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let compressed = compress(b"some text to compress");
+        let err = decompress(&compressed[..compressed.len() - 2], 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // Block type 3 is reserved.
+        assert!(decompress(&[0x07], 1024).is_err());
+    }
+}
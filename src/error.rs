@@ -0,0 +1,150 @@
+use crate::framing::{self, FrameTooLarge};
+use crate::message::StatusCode;
+use std::{error::Error, fmt, io};
+
+/// Failure of a request/response exchange, sorted by what the caller can do
+/// about it: a message rejected before sending, or one that arrived whole
+/// but did not decode, leaves the connection usable, while `NotConnected`
+/// and `Io` mean it must be reconnected. An incoming `MessageTooLarge` is
+/// the exception: its payload was never read, so the client closes the
+/// connection. `Server` is an `ErrorResponse` from the server.
+///
+/// Every fallible `Client`, `ClientPool` and `Server` method returns it.
+/// Code that needs an `io::Error` can convert it with `From`; converting
+/// back recovers the variant.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A message of `len` bytes is over the `max` the receiving side
+    /// accepts.
+    MessageTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A message arrived whole but could not be decoded.
+    DecodeFailed(String),
+    /// There is no open connection.
+    NotConnected,
+    Io(io::Error),
+    Server {
+        code: StatusCode,
+        message: String,
+    },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::MessageTooLarge { len, max } => {
+                write!(
+                    f,
+                    "message of {} bytes exceeds the limit of {} bytes",
+                    len, max
+                )
+            }
+            ProtocolError::DecodeFailed(reason) => write!(f, "invalid message: {}", reason),
+            ProtocolError::NotConnected => f.write_str("no active connection"),
+            ProtocolError::Io(e) => write!(f, "transport error: {}", e),
+            ProtocolError::Server { code, message } => {
+                write!(f, "server error {}: {}", code.as_str_name(), message)
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Sorts an I/O error into the variant it reports: an oversized frame, a
+/// frame that did not decode (`InvalidData`), a missing connection, or any
+/// other transport failure. A missing sync marker is `InvalidData` too, but
+/// the stream is misaligned, so it stays `Io`. A `ProtocolError` converted
+/// to `io::Error` is unwrapped again, so server errors survive the round
+/// trip.
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<ProtocolError>()) {
+            return *e.into_inner().unwrap().downcast::<ProtocolError>().unwrap();
+        }
+        if framing::is_too_large(&e) {
+            let too_large = e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<FrameTooLarge>())
+                .unwrap();
+            return ProtocolError::MessageTooLarge {
+                len: too_large.len,
+                max: too_large.max,
+            };
+        }
+        if framing::is_desynced(&e) {
+            return ProtocolError::Io(e);
+        }
+        match e.kind() {
+            io::ErrorKind::NotConnected => ProtocolError::NotConnected,
+            io::ErrorKind::InvalidData => ProtocolError::DecodeFailed(e.to_string()),
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
+
+/// Transport errors convert back unchanged, the other variants to the error
+/// kind `From<io::Error>` sorts into them, and server errors become `Other`.
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::MessageTooLarge { len, max } => {
+                io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge { len, max })
+            }
+            ProtocolError::DecodeFailed(reason) => {
+                io::Error::new(io::ErrorKind::InvalidData, reason)
+            }
+            ProtocolError::NotConnected => {
+                io::Error::new(io::ErrorKind::NotConnected, "No active connection")
+            }
+            ProtocolError::Io(e) => e,
+            other => io::Error::other(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(e: ProtocolError) -> ProtocolError {
+        ProtocolError::from(io::Error::from(e))
+    }
+
+    #[test]
+    fn test_every_variant_survives_io_round_trip() {
+        match round_trip(ProtocolError::MessageTooLarge { len: 100, max: 64 }) {
+            ProtocolError::MessageTooLarge { len, max } => assert_eq!((len, max), (100, 64)),
+            other => panic!("Expected MessageTooLarge, got {:?}", other),
+        }
+        match round_trip(ProtocolError::DecodeFailed("bad tag".to_string())) {
+            ProtocolError::DecodeFailed(reason) => assert_eq!(reason, "bad tag"),
+            other => panic!("Expected DecodeFailed, got {:?}", other),
+        }
+        assert!(matches!(round_trip(ProtocolError::NotConnected), ProtocolError::NotConnected));
+        match round_trip(ProtocolError::Io(io::ErrorKind::ConnectionReset.into())) {
+            ProtocolError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            other => panic!("Expected Io, got {:?}", other),
+        }
+        let server = ProtocolError::Server {
+            code: StatusCode::InvalidArgument,
+            message: "no such topic".to_string(),
+        };
+        match round_trip(server) {
+            ProtocolError::Server { code, message } => {
+                assert_eq!(code, StatusCode::InvalidArgument);
+                assert_eq!(message, "no such topic");
+            }
+            other => panic!("Expected Server, got {:?}", other),
+        }
+    }
+}
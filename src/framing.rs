@@ -0,0 +1,536 @@
+//! Wire framing shared by the client and server: each message is a 4-byte
+//! big-endian length followed by the encoded protobuf payload, optionally
+//! preceded by `FRAME_SYNC_MARKER`. The top bit of the length marks a
+//! DEFLATE-compressed payload; readers inflate those transparently.
+use crate::deflate;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// Optional prefix written before every frame's length. Checking it lets a
+/// reader detect a desynchronised stream at the next frame instead of
+/// misreading payload bytes as lengths indefinitely.
+pub const FRAME_SYNC_MARKER: [u8; 4] = *b"OTF1";
+
+/// Length prefix of a RESET control frame, which has no payload. It is
+/// larger than any frame either side accepts, so it cannot be mistaken for
+/// one. On receiving RESET a peer discards its framing state and replies
+/// with a RESET of its own; the bytes after that reply start a fresh frame.
+pub const RESET_FRAME_LEN: u32 = u32::MAX;
+
+/// Set in a frame's length prefix when the payload is compressed; the rest
+/// of the prefix is then the compressed length. No frame is large enough to
+/// set it otherwise, so plain frames read the same as before.
+pub const COMPRESSED_FRAME_FLAG: u32 = 1 << 31;
+
+/// Error payload returned by `read_frame` and `try_read_frame` when the peer
+/// sent a RESET control frame. The error kind is `Interrupted`: whatever was
+/// in flight is lost and may be retried.
+#[derive(Debug)]
+pub struct ResetRequested;
+
+impl fmt::Display for ResetRequested {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("peer reset the framing; resend any unanswered request")
+    }
+}
+
+impl Error for ResetRequested {}
+
+/// Whether `e` reports a RESET control frame from the peer.
+pub fn is_reset(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<ResetRequested>())
+}
+
+/// Error payload returned by `read_frame` and `try_read_frame` when a frame
+/// is longer than the caller's limit. The error kind is `InvalidData`.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Message size {} exceeds maximum allowed {} bytes",
+            self.len, self.max
+        )
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+/// Whether `e` reports a frame over the reader's size limit.
+pub fn is_too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<FrameTooLarge>())
+}
+
+/// Error payload returned by `read_frame` and `try_read_frame` when the
+/// connection closed part-way through a frame, e.g. after two bytes of a
+/// length prefix. The error kind is `UnexpectedEof`. A close before the
+/// first byte is not an error; see `try_read_frame`.
+#[derive(Debug)]
+pub struct FrameTruncated {
+    /// Bytes received of the part being read: the header, or the payload.
+    pub read: usize,
+    pub expected: usize,
+}
+
+impl fmt::Display for FrameTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connection closed mid-frame after {} of {} bytes",
+            self.read, self.expected
+        )
+    }
+}
+
+impl Error for FrameTruncated {}
+
+/// Whether `e` reports a connection that closed part-way through a frame.
+pub fn is_truncated(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<FrameTruncated>())
+}
+
+/// Error payload returned by `read_frame` and `try_read_frame` when a
+/// frame's sync marker is missing. The error kind is `InvalidData`. The
+/// reader has lost track of frame boundaries, so unless the peer supports
+/// RESET the stream must be closed.
+#[derive(Debug)]
+pub struct FrameDesynced {
+    pub marker: [u8; 4],
+}
+
+impl fmt::Display for FrameDesynced {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame sync marker mismatch: got {:02x?}", self.marker)
+    }
+}
+
+impl Error for FrameDesynced {}
+
+/// Whether `e` reports a frame whose sync marker was missing.
+pub fn is_desynced(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<FrameDesynced>())
+}
+
+/// How many read timeouts a frame that has started may hit before the
+/// reader gives up on it. The streams read here block for their read
+/// timeout on each attempt, so a stalled frame is abandoned after about
+/// this many timeouts rather than held open indefinitely.
+pub(crate) const MID_FRAME_STALL_LIMIT: u32 = 2;
+
+/// Error payload returned by `read_frame` and `try_read_frame` when the
+/// peer stopped sending part-way through a frame, e.g. after two bytes of a
+/// length prefix, for `MID_FRAME_STALL_LIMIT` read timeouts. The error kind
+/// is `TimedOut`. The rest of the frame may still arrive, so the stream is
+/// misaligned and must be closed.
+#[derive(Debug)]
+pub struct FrameStalled {
+    /// Bytes received of the frame before it stalled.
+    pub read: usize,
+}
+
+impl fmt::Display for FrameStalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer stalled mid-frame after {} bytes", self.read)
+    }
+}
+
+impl Error for FrameStalled {}
+
+/// Whether `e` reports a peer that stalled part-way through a frame.
+pub fn is_stalled(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<FrameStalled>())
+}
+
+/// Writes a RESET control frame.
+pub fn write_reset<W: Write>(writer: &mut W, sync: bool) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_SYNC_MARKER.len() + 4);
+    if sync {
+        frame.extend_from_slice(&FRAME_SYNC_MARKER);
+    }
+    frame.extend_from_slice(&RESET_FRAME_LEN.to_be_bytes());
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Discards input up to and including the next RESET length prefix, giving
+/// up with `InvalidData` after `limit` bytes. Payload bytes that happen to
+/// contain the prefix would end the scan early, so this is a best-effort
+/// recovery rather than a guarantee.
+pub fn skip_to_reset<R: Read>(reader: &mut R, limit: usize) -> io::Result<()> {
+    let sentinel = RESET_FRAME_LEN.to_be_bytes();
+    let mut matched = 0;
+    let mut byte = [0u8; 1];
+    for _ in 0..limit {
+        fill(reader, &mut byte, false)?;
+        matched = if byte[0] == sentinel[matched] {
+            matched + 1
+        } else if byte[0] == sentinel[0] {
+            1
+        } else {
+            0
+        };
+        if matched == sentinel.len() {
+            return Ok(());
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "No RESET frame found while resynchronising",
+    ))
+}
+
+/// Writes one frame with a single `write_all`, so Nagle's algorithm cannot
+/// hold the payload back behind an unacknowledged length prefix.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8], sync: bool) -> io::Result<()> {
+    write_prefixed(writer, payload.len() as u32, payload, sync)
+}
+
+/// Like `write_frame`, but DEFLATE-compresses the payload and flags the
+/// frame as compressed. A payload that does not shrink is sent as a plain
+/// frame instead.
+pub fn write_compressed_frame<W: Write>(
+    writer: &mut W,
+    payload: &[u8],
+    sync: bool,
+) -> io::Result<()> {
+    let compressed = deflate::compress(payload);
+    if compressed.len() >= payload.len() {
+        return write_frame(writer, payload, sync);
+    }
+    let len = compressed.len() as u32 | COMPRESSED_FRAME_FLAG;
+    write_prefixed(writer, len, &compressed, sync)
+}
+
+fn write_prefixed<W: Write>(
+    writer: &mut W,
+    prefix: u32,
+    payload: &[u8],
+    sync: bool,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_SYNC_MARKER.len() + 4 + payload.len());
+    if sync {
+        frame.extend_from_slice(&FRAME_SYNC_MARKER);
+    }
+    frame.extend_from_slice(&prefix.to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads one frame, rejecting payloads longer than `max_len` with
+/// `InvalidData` before allocating for them. A clean close between frames is
+/// reported as `UnexpectedEof`; use `try_read_frame` to tell it apart from a
+/// frame cut short.
+pub fn read_frame<R: Read>(reader: &mut R, sync: bool, max_len: usize) -> io::Result<Vec<u8>> {
+    try_read_frame(reader, sync, max_len)?
+        .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Connection closed"))
+}
+
+/// Like `read_frame`, but returns `Ok(None)` when the peer closed the
+/// connection cleanly before the first byte of a frame. A close part-way
+/// through a frame is still an `UnexpectedEof` error.
+///
+/// Compressed frames are returned inflated. `max_len` applies to both the
+/// compressed and the inflated size; inflating stops as soon as it is
+/// exceeded.
+pub fn try_read_frame<R: Read>(
+    reader: &mut R,
+    sync: bool,
+    max_len: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; FRAME_SYNC_MARKER.len() + 4];
+    let header = if sync { &mut header[..] } else { &mut header[FRAME_SYNC_MARKER.len()..] };
+    if !fill(reader, header, true)? {
+        return Ok(None);
+    }
+
+    let (marker, len_buf) = header.split_at(header.len() - 4);
+    if sync && marker != FRAME_SYNC_MARKER {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            FrameDesynced {
+                marker: marker.try_into().unwrap(),
+            },
+        ));
+    }
+
+    let message_len = u32::from_be_bytes(len_buf.try_into().unwrap());
+    if message_len == RESET_FRAME_LEN {
+        return Err(io::Error::new(ErrorKind::Interrupted, ResetRequested));
+    }
+    let compressed = message_len & COMPRESSED_FRAME_FLAG != 0;
+    let message_len = (message_len & !COMPRESSED_FRAME_FLAG) as usize;
+    if message_len > max_len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            FrameTooLarge {
+                len: message_len,
+                max: max_len,
+            },
+        ));
+    }
+
+    let mut buffer = vec![0; message_len];
+    fill(reader, &mut buffer, false)?;
+    if compressed {
+        return deflate::decompress(&buffer, max_len).map(Some);
+    }
+    Ok(Some(buffer))
+}
+
+/// Fills `buf` completely, returning `Ok(false)` only if `at_boundary` and
+/// the reader hit EOF before yielding any byte.
+///
+/// `Interrupted` is always retried. `WouldBlock` and `TimedOut` (how read
+/// timeouts surface) are returned while nothing has been read, so an idle
+/// connection still times out. Once a frame is under way they are retried,
+/// since a brief stall should not cost the connection, but only up to
+/// `MID_FRAME_STALL_LIMIT` times; after that `FrameStalled` is returned.
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8], at_boundary: bool) -> io::Result<bool> {
+    let mut filled = 0;
+    let mut stalls = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if at_boundary && filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    FrameTruncated {
+                        read: filled,
+                        expected: buf.len(),
+                    },
+                ))
+            }
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(ref e)
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && !(at_boundary && filled == 0) =>
+            {
+                stalls += 1;
+                if stalls >= MID_FRAME_STALL_LIMIT {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        FrameStalled { read: filled },
+                    ));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replays a script of read results, one per `read` call.
+    struct ScriptedReader {
+        script: VecDeque<io::Result<Vec<u8>>>,
+    }
+
+    impl ScriptedReader {
+        fn new(script: Vec<io::Result<Vec<u8>>>) -> Self {
+            ScriptedReader {
+                script: script.into(),
+            }
+        }
+    }
+
+    impl Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.script.pop_front() {
+                Some(Ok(mut chunk)) => {
+                    let n = chunk.len().min(buf.len());
+                    buf[..n].copy_from_slice(&chunk[..n]);
+                    if n < chunk.len() {
+                        self.script.push_front(Ok(chunk.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, payload, false).unwrap();
+        wire
+    }
+
+    #[test]
+    fn test_clean_eof_between_frames_is_none() {
+        let mut reader = ScriptedReader::new(vec![Ok(frame(b"one"))]);
+        assert_eq!(try_read_frame(&mut reader, false, 1024).unwrap().unwrap(), b"one");
+        assert!(try_read_frame(&mut reader, false, 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_frame_names_the_limit() {
+        let mut reader = ScriptedReader::new(vec![Ok(frame(&[0; 100]))]);
+        let err = try_read_frame(&mut reader, false, 64).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("100"), "{}", err);
+        assert!(err.to_string().contains("64 bytes"), "{}", err);
+        assert!(is_too_large(&err));
+    }
+
+    #[test]
+    fn test_eof_mid_frame_is_unexpected_eof() {
+        let wire = frame(b"truncated");
+        let mut reader = ScriptedReader::new(vec![Ok(wire[..6].to_vec())]);
+        let err = try_read_frame(&mut reader, false, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(is_truncated(&err));
+        assert!(err.to_string().contains("2 of 9 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_eof_mid_length_prefix_is_truncated() {
+        let mut reader = ScriptedReader::new(vec![Ok(vec![0, 0])]);
+        let err = try_read_frame(&mut reader, false, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(is_truncated(&err));
+        assert!(err.to_string().contains("2 of 4 bytes"), "{}", err);
+
+        let mut reader = ScriptedReader::new(vec![]);
+        assert!(try_read_frame(&mut reader, false, 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_interrupted_is_retried() {
+        let wire = frame(b"hello");
+        let mut reader = ScriptedReader::new(vec![
+            Err(ErrorKind::Interrupted.into()),
+            Ok(wire[..2].to_vec()),
+            Err(ErrorKind::Interrupted.into()),
+            Ok(wire[2..].to_vec()),
+        ]);
+        assert_eq!(try_read_frame(&mut reader, false, 1024).unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_would_block_retried_only_mid_frame() {
+        let wire = frame(b"hello");
+        let mut reader = ScriptedReader::new(vec![
+            Err(ErrorKind::WouldBlock.into()),
+            Ok(wire[..6].to_vec()),
+            Err(ErrorKind::WouldBlock.into()),
+            Ok(wire[6..].to_vec()),
+        ]);
+        // Idle at a frame boundary: surfaced so read timeouts still work.
+        let err = try_read_frame(&mut reader, false, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        // Part-way through a frame: retried until the frame completes.
+        assert_eq!(try_read_frame(&mut reader, false, 1024).unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_stalled_prefix_times_out() {
+        let wire = frame(b"hello");
+        let mut script = vec![Ok(wire[..2].to_vec())];
+        script.extend((0..100).map(|_| Err(ErrorKind::WouldBlock.into())));
+        let mut reader = ScriptedReader::new(script);
+        let err = try_read_frame(&mut reader, false, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(is_stalled(&err));
+        assert!(err.to_string().contains("after 2 bytes"), "{}", err);
+        // Gave up after the limit instead of draining the whole script.
+        assert_eq!(reader.script.len(), 100 - MID_FRAME_STALL_LIMIT as usize);
+    }
+
+    #[test]
+    fn test_reset_frame_is_reported() {
+        let mut wire = Vec::new();
+        write_reset(&mut wire, true).unwrap();
+        write_frame(&mut wire, b"after", true).unwrap();
+
+        let mut reader = &wire[..];
+        let err = read_frame(&mut reader, true, 1024).unwrap_err();
+        assert!(is_reset(&err));
+        assert_eq!(read_frame(&mut reader, true, 1024).unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_skip_to_reset() {
+        let mut wire = vec![0xff, 0x00, 0xff, 0xff, 0xff, 0x12];
+        write_reset(&mut wire, false).unwrap();
+        write_frame(&mut wire, b"after", false).unwrap();
+
+        let mut reader = &wire[..];
+        skip_to_reset(&mut reader, 1024).unwrap();
+        assert_eq!(read_frame(&mut reader, false, 1024).unwrap(), b"after");
+
+        let mut garbage = &[0u8; 16][..];
+        let err = skip_to_reset(&mut garbage, 8).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compressed_frame_round_trip() {
+        let text = b"the same line, over and over\n".repeat(100);
+        let mut wire = Vec::new();
+        write_compressed_frame(&mut wire, &text, true).unwrap();
+        assert!(wire.len() < text.len() / 4);
+        // Too short to shrink, so sent plain.
+        write_compressed_frame(&mut wire, b"hi", true).unwrap();
+        assert_eq!(&wire[wire.len() - 6..], b"\0\0\0\x02hi");
+
+        let mut reader = &wire[..];
+        assert_eq!(read_frame(&mut reader, true, text.len()).unwrap(), text);
+        assert_eq!(read_frame(&mut reader, true, 1024).unwrap(), b"hi");
+
+        // The limit applies to the inflated size too.
+        let mut reader = &wire[..];
+        let err = read_frame(&mut reader, true, text.len() - 1).unwrap_err();
+        assert!(is_too_large(&err));
+    }
+
+    #[test]
+    fn test_other_errors_propagate() {
+        let wire = frame(b"hello");
+        let mut reader = ScriptedReader::new(vec![
+            Ok(wire[..3].to_vec()),
+            Err(ErrorKind::ConnectionReset.into()),
+        ]);
+        let err = try_read_frame(&mut reader, false, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_sync_marker_round_trip() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello", true).unwrap();
+        write_frame(&mut wire, b"world", true).unwrap();
+
+        let mut reader = &wire[..];
+        assert_eq!(read_frame(&mut reader, true, 1024).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader, true, 1024).unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_desync_detected_at_next_frame() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello", true).unwrap();
+        // A frame written without its marker leaves the reader misaligned.
+        write_frame(&mut wire, b"world", false).unwrap();
+
+        let mut reader = &wire[..];
+        assert!(read_frame(&mut reader, true, 1024).is_ok());
+        let err = read_frame(&mut reader, true, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(is_desynced(&err));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::message::client_message::Message as ClientMessageEnum;
+use crate::message::server_message::Message as ServerMessageEnum;
+use crate::message::{AddResponse, ErrorResponse, MultiplyResponse, ServerMessage, StatusCode};
+use log::{info, warn};
+use std::{cell::Cell, collections::HashMap, fmt, sync::Arc};
+
+// Status codes the server answers with:
+//
+// - `InvalidArgument`: the request is well-framed but its content is
+//   invalid, e.g. an echo that is not UTF-8 or an add that overflows.
+// - `Unimplemented`: no handler is registered for the request variant.
+// - `Unavailable`: the request was shed because the server is overloaded.
+//   Safe to retry later.
+// - `ResourceExhausted`: the connection was refused because the server
+//   already has as many open connections as it allows, or the request was
+//   rejected because its connection exceeded its rate limit.
+// - `Internal`: handling the request failed on the server's side.
+
+thread_local! {
+    /// The connection whose request is being handled on this thread.
+    static SERVING: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The id of the connection whose request the calling handler is serving,
+/// as it appears in the server's log lines. `None` outside the server.
+pub fn current_connection() -> Option<u64> {
+    SERVING.get()
+}
+
+/// Marks `id` as the connection being served on this thread until the
+/// returned guard is dropped.
+pub(crate) fn serving(id: u64) -> ServingGuard {
+    ServingGuard(SERVING.replace(Some(id)))
+}
+
+pub(crate) struct ServingGuard(Option<u64>);
+
+impl Drop for ServingGuard {
+    fn drop(&mut self) {
+        SERVING.set(self.0);
+    }
+}
+
+/// Names the connection being served in handler log lines.
+struct Current;
+
+impl fmt::Display for Current {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match current_connection() {
+            Some(id) => write!(f, "client {}", id),
+            None => f.write_str("unknown client"),
+        }
+    }
+}
+
+/// The kind of a client request, used as the dispatch key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Echo,
+    Add,
+    Subscribe,
+    Unsubscribe,
+    Upgrade,
+    Limits,
+    Multiply,
+    Ping,
+    StreamEcho,
+    Status,
+    Publish,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 11] = [
+        MessageKind::Echo,
+        MessageKind::Add,
+        MessageKind::Subscribe,
+        MessageKind::Unsubscribe,
+        MessageKind::Upgrade,
+        MessageKind::Limits,
+        MessageKind::Multiply,
+        MessageKind::Ping,
+        MessageKind::StreamEcho,
+        MessageKind::Status,
+        MessageKind::Publish,
+    ];
+
+    pub fn of(message: &ClientMessageEnum) -> Self {
+        match message {
+            ClientMessageEnum::EchoMessage(_) => MessageKind::Echo,
+            ClientMessageEnum::AddRequest(_) => MessageKind::Add,
+            ClientMessageEnum::Subscribe(_) => MessageKind::Subscribe,
+            ClientMessageEnum::Unsubscribe(_) => MessageKind::Unsubscribe,
+            ClientMessageEnum::Upgrade(_) => MessageKind::Upgrade,
+            ClientMessageEnum::LimitsRequest(_) => MessageKind::Limits,
+            ClientMessageEnum::MultiplyRequest(_) => MessageKind::Multiply,
+            ClientMessageEnum::PingMessage(_) => MessageKind::Ping,
+            ClientMessageEnum::StreamEchoRequest(_) => MessageKind::StreamEcho,
+            ClientMessageEnum::StatusRequest(_) => MessageKind::Status,
+            ClientMessageEnum::PublishRequest(_) => MessageKind::Publish,
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MessageKind::Echo => "echo",
+            MessageKind::Add => "add",
+            MessageKind::Subscribe => "subscribe",
+            MessageKind::Unsubscribe => "unsubscribe",
+            MessageKind::Upgrade => "upgrade",
+            MessageKind::Limits => "limits",
+            MessageKind::Multiply => "multiply",
+            MessageKind::Ping => "ping",
+            MessageKind::StreamEcho => "stream echo",
+            MessageKind::Status => "status",
+            MessageKind::Publish => "publish",
+        };
+        f.write_str(name)
+    }
+}
+
+pub type Handler = Arc<dyn Fn(ClientMessageEnum) -> ServerMessage + Send + Sync>;
+
+/// Produces the response to an application request. The server answers
+/// protocol requests (subscriptions, publishes, upgrades, limits, pings,
+/// status and stream echoes) itself; every other request is passed here.
+///
+/// `HandlerRegistry`, the default, dispatches on `MessageKind`. Implement
+/// this directly to serve requests some other way, e.g. from a handler that
+/// carries its own state.
+pub trait MessageHandler {
+    fn handle(&self, message: ClientMessageEnum) -> ServerMessage;
+}
+
+/// Maps each `MessageKind` to the function that serves it. Kinds without a
+/// handler are answered with an `ErrorResponse` instead of being dropped, so
+/// forward-compatible clients get a clean "unsupported" reply.
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<MessageKind, Handler>,
+}
+
+impl HandlerRegistry {
+    /// Creates a registry with no handlers. Use `HandlerRegistry::builtin`
+    /// for the built-in echo/add behaviour.
+    pub fn new() -> Self {
+        HandlerRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Creates a registry serving the built-in echo, add and multiply
+    /// requests.
+    pub fn builtin() -> Self {
+        let mut registry = HandlerRegistry::new();
+        registry.register(MessageKind::Echo, echo);
+        registry.register(MessageKind::Add, add);
+        registry.register(MessageKind::Multiply, multiply);
+        registry
+    }
+
+    pub fn register<F>(&mut self, kind: MessageKind, handler: F) -> &mut Self
+    where
+        F: Fn(ClientMessageEnum) -> ServerMessage + Send + Sync + 'static,
+    {
+        self.handlers.insert(kind, Arc::new(handler));
+        self
+    }
+
+    pub fn dispatch(&self, message: ClientMessageEnum) -> ServerMessage {
+        let kind = MessageKind::of(&message);
+        match self.handlers.get(&kind) {
+            Some(handler) => handler(message),
+            None => {
+                warn!("No handler registered for {} request from {}", kind, Current);
+                error_response(
+                    StatusCode::Unimplemented,
+                    format!("Unsupported operation: {}", kind),
+                )
+            }
+        }
+    }
+}
+
+impl MessageHandler for HandlerRegistry {
+    fn handle(&self, message: ClientMessageEnum) -> ServerMessage {
+        self.dispatch(message)
+    }
+}
+
+pub fn error_response(code: StatusCode, message: impl Into<String>) -> ServerMessage {
+    ServerMessage {
+        message: Some(ServerMessageEnum::ErrorResponse(ErrorResponse {
+            code: code.into(),
+            message: message.into(),
+        })),
+    }
+}
+
+/// Built-in echo handler: returns the message unchanged.
+pub fn echo(message: ClientMessageEnum) -> ServerMessage {
+    match message {
+        ClientMessageEnum::EchoMessage(echo) => {
+            info!("Handling echo message from {}: {}", Current, echo.content);
+            ServerMessage {
+                message: Some(ServerMessageEnum::EchoMessage(echo)),
+            }
+        }
+        other => mismatched(MessageKind::Echo, &other),
+    }
+}
+
+/// Built-in add handler: returns the sum of both operands, or
+/// `InvalidArgument` if it does not fit in an `i32`.
+pub fn add(message: ClientMessageEnum) -> ServerMessage {
+    match message {
+        ClientMessageEnum::AddRequest(req) => {
+            info!("Handling add request from {}: {} + {}", Current, req.a, req.b);
+            let Some(result) = req.a.checked_add(req.b) else {
+                warn!("Add request from {} overflows: {} + {}", Current, req.a, req.b);
+                return error_response(StatusCode::InvalidArgument, "Integer overflow");
+            };
+            ServerMessage {
+                message: Some(ServerMessageEnum::AddResponse(AddResponse {
+                    result,
+                    a: req.a,
+                    b: req.b,
+                    operation: "add".to_string(),
+                    request_id: req.request_id,
+                })),
+            }
+        }
+        other => mismatched(MessageKind::Add, &other),
+    }
+}
+
+/// Built-in multiply handler: returns the product of both operands as an
+/// `i64`, which cannot overflow.
+pub fn multiply(message: ClientMessageEnum) -> ServerMessage {
+    match message {
+        ClientMessageEnum::MultiplyRequest(req) => {
+            info!("Handling multiply request from {}: {} * {}", Current, req.a, req.b);
+            ServerMessage {
+                message: Some(ServerMessageEnum::MultiplyResponse(MultiplyResponse {
+                    result: i64::from(req.a) * i64::from(req.b),
+                })),
+            }
+        }
+        other => mismatched(MessageKind::Multiply, &other),
+    }
+}
+
+fn mismatched(expected: MessageKind, message: &ClientMessageEnum) -> ServerMessage {
+    warn!(
+        "{} handler received a {} request from {}",
+        expected,
+        MessageKind::of(message),
+        Current
+    );
+    error_response(
+        StatusCode::Unimplemented,
+        format!("Unsupported operation: {}", MessageKind::of(message)),
+    )
+}
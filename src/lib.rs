@@ -1,5 +1,14 @@
 pub mod server;
 pub mod client;
+pub mod codec;
+mod deflate;
+pub mod error;
+pub mod framing;
+pub mod handler;
+pub mod metrics;
+mod transport;
+#[cfg(feature = "socks5")]
+pub mod socks5;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
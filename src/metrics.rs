@@ -0,0 +1,336 @@
+use crate::handler::MessageKind;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Inclusive upper bounds, in bytes, of the message size histogram buckets.
+/// Frames larger than the last bound land in a final overflow bucket.
+pub const SIZE_BUCKET_BOUNDS: [usize; 7] = [64, 256, 1024, 4096, 16384, 65536, 262144];
+
+/// Number of histogram buckets, including the overflow bucket.
+pub const SIZE_BUCKETS: usize = SIZE_BUCKET_BOUNDS.len() + 1;
+
+/// Index of the histogram bucket counting a frame of `len` payload bytes.
+pub fn size_bucket(len: usize) -> usize {
+    SIZE_BUCKET_BOUNDS
+        .iter()
+        .position(|&bound| len <= bound)
+        .unwrap_or(SIZE_BUCKET_BOUNDS.len())
+}
+
+#[derive(Default)]
+pub(crate) struct SizeHistogram {
+    buckets: [AtomicU64; SIZE_BUCKETS],
+}
+
+impl SizeHistogram {
+    pub(crate) fn record(&self, len: usize) {
+        Metrics::increment(&self.buckets[size_bucket(len)]);
+    }
+
+    pub(crate) fn snapshot(&self) -> [u64; SIZE_BUCKETS] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+}
+
+/// Inclusive upper bounds of the request latency histogram buckets.
+/// Requests slower than the last bound land in a final overflow bucket.
+pub const LATENCY_BUCKET_BOUNDS: [Duration; 5] = [
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Number of latency histogram buckets, including the overflow bucket.
+pub const LATENCY_BUCKETS: usize = LATENCY_BUCKET_BOUNDS.len() + 1;
+
+/// Index of the latency histogram bucket counting a request handled in
+/// `elapsed`.
+pub fn latency_bucket(elapsed: Duration) -> usize {
+    LATENCY_BUCKET_BOUNDS
+        .iter()
+        .position(|&bound| elapsed <= bound)
+        .unwrap_or(LATENCY_BUCKET_BOUNDS.len())
+}
+
+#[derive(Default)]
+pub(crate) struct RequestStats {
+    count: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl RequestStats {
+    fn record(&self, elapsed: Duration) {
+        Metrics::increment(&self.count);
+        Metrics::increment(&self.latency[latency_bucket(elapsed)]);
+    }
+
+    fn snapshot(&self) -> RequestMetrics {
+        RequestMetrics {
+            count: self.count.load(Ordering::Relaxed),
+            latency: std::array::from_fn(|i| self.latency[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Requests of one kind handled so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestMetrics {
+    pub count: u64,
+    /// Requests per handling-time bucket (see `LATENCY_BUCKET_BOUNDS`).
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+/// How far back the error rate in `PongMessage` looks.
+pub const ERROR_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+const ERROR_RATE_SLOTS: usize = ERROR_RATE_WINDOW.as_secs() as usize;
+
+/// Requests and error responses over the last `ERROR_RATE_WINDOW`, counted
+/// in one-second slots that are reused as time moves on.
+pub(crate) struct RecentOutcomes {
+    started: Instant,
+    /// Per slot: the second since `started` it counts, then its requests
+    /// and errors.
+    slots: Mutex<[(u64, u64, u64); ERROR_RATE_SLOTS]>,
+}
+
+impl Default for RecentOutcomes {
+    fn default() -> Self {
+        RecentOutcomes {
+            started: Instant::now(),
+            slots: Mutex::new([(0, 0, 0); ERROR_RATE_SLOTS]),
+        }
+    }
+}
+
+impl RecentOutcomes {
+    fn record(&self, failed: bool) {
+        let second = self.started.elapsed().as_secs();
+        let mut slots = self.slots.lock().unwrap();
+        let slot = &mut slots[second as usize % ERROR_RATE_SLOTS];
+        if slot.0 != second {
+            *slot = (second, 0, 0);
+        }
+        slot.1 += 1;
+        slot.2 += failed as u64;
+    }
+
+    /// Share of recent requests answered with an error; zero if there were
+    /// none.
+    pub(crate) fn error_rate(&self) -> f32 {
+        let now = self.started.elapsed().as_secs();
+        let (requests, errors) = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&(second, _, _)| now - second < ERROR_RATE_SLOTS as u64)
+            .fold((0, 0), |(requests, errors), &(_, r, e)| (requests + r, errors + e));
+        if requests == 0 {
+            0.0
+        } else {
+            errors as f32 / requests as f32
+        }
+    }
+}
+
+/// Why the server closed a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// The client closed the connection between requests.
+    ClientClosed,
+    /// No request arrived within the read timeout.
+    Timeout,
+    /// A frame was misaligned: its sync marker was missing.
+    Malformed,
+    /// A frame was larger than the server's maximum message size.
+    Oversized,
+    /// The client reset the connection or vanished part-way through a frame.
+    ConnectionLost,
+    /// Any other I/O failure, or the handler serving the connection panicked.
+    Error,
+    /// The server was shutting down.
+    Shutdown,
+    /// The connection was served its maximum number of requests.
+    RequestLimit,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 8] = [
+        CloseReason::ClientClosed,
+        CloseReason::Timeout,
+        CloseReason::Malformed,
+        CloseReason::Oversized,
+        CloseReason::ConnectionLost,
+        CloseReason::Error,
+        CloseReason::Shutdown,
+        CloseReason::RequestLimit,
+    ];
+
+    /// Whether this is an expected way for a connection to end: the peer
+    /// leaving, however abruptly, the server shutting down, or the
+    /// connection being recycled after its last request.
+    pub fn is_normal(self) -> bool {
+        matches!(
+            self,
+            CloseReason::ClientClosed
+                | CloseReason::ConnectionLost
+                | CloseReason::Shutdown
+                | CloseReason::RequestLimit
+        )
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CloseReason::ClientClosed => "client closed",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Malformed => "malformed request",
+            CloseReason::Oversized => "oversized message",
+            CloseReason::ConnectionLost => "connection lost",
+            CloseReason::Error => "error",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::RequestLimit => "request limit",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Counters updated by the server as it runs. Read them through
+/// `Server::metrics`, which returns a `ServerMetrics` snapshot.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) queue_depth_warnings: AtomicU64,
+    pub(crate) slow_requests: AtomicU64,
+    pub(crate) shed_requests: AtomicU64,
+    pub(crate) rate_limited_requests: AtomicU64,
+    pub(crate) write_disconnects: AtomicU64,
+    pub(crate) throttles_sent: AtomicU64,
+    pub(crate) rejected_connections: AtomicU64,
+    pub(crate) closes: [AtomicU64; CloseReason::ALL.len()],
+    pub(crate) requests: [RequestStats; MessageKind::ALL.len()],
+    pub(crate) recent_outcomes: RecentOutcomes,
+    /// Jobs waiting for a worker, across all pools.
+    pub(crate) queued_jobs: AtomicU64,
+    pub(crate) inbound_sizes: SizeHistogram,
+    pub(crate) outbound_sizes: SizeHistogram,
+    pub(crate) bytes_read: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) audits: AtomicU64,
+    pub(crate) last_audit: Mutex<Option<ResourceAudit>>,
+}
+
+impl Metrics {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_close(&self, reason: CloseReason) {
+        Metrics::increment(&self.closes[reason as usize]);
+    }
+
+    /// Counts a handled request, and whether it was answered with an error.
+    pub(crate) fn record_request(&self, kind: MessageKind, elapsed: Duration, failed: bool) {
+        self.requests[kind as usize].record(elapsed);
+        self.recent_outcomes.record(failed);
+    }
+
+    /// Requests handled so far, of every kind.
+    pub(crate) fn total_requests(&self) -> u64 {
+        self.requests
+            .iter()
+            .map(|stats| stats.count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub(crate) fn requests_snapshot(&self) -> HashMap<MessageKind, RequestMetrics> {
+        MessageKind::ALL
+            .iter()
+            .map(|&kind| (kind, self.requests[kind as usize].snapshot()))
+            .collect()
+    }
+
+    /// Counts a received frame of `len` payload bytes.
+    pub(crate) fn record_inbound(&self, len: usize) {
+        self.inbound_sizes.record(len);
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a sent frame of `len` payload bytes.
+    pub(crate) fn record_outbound(&self, len: usize) {
+        self.outbound_sizes.record(len);
+        self.bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn closes_snapshot(&self) -> HashMap<CloseReason, u64> {
+        CloseReason::ALL
+            .iter()
+            .map(|&reason| (reason, self.closes[reason as usize].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Point-in-time view of the server's counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerMetrics {
+    /// Jobs waiting for a worker when the snapshot was taken.
+    pub queue_depth: usize,
+    /// Times the job queue grew past one of its warning thresholds.
+    pub queue_depth_warnings: u64,
+    /// Requests that took longer than the slow-request threshold to handle.
+    pub slow_requests: u64,
+    /// Requests answered with `Unavailable` because they waited too long for a
+    /// worker.
+    pub shed_requests: u64,
+    /// Requests answered with `ResourceExhausted` because their connection
+    /// exceeded its rate limit.
+    pub rate_limited_requests: u64,
+    /// Responses cut short because the client closed or reset the
+    /// connection while they were being written.
+    pub write_disconnects: u64,
+    /// `Throttle` hints sent to clients because the queue was backed up.
+    pub throttles_sent: u64,
+    /// Connections turned away because `max_connections` were already open.
+    pub rejected_connections: u64,
+    /// Closed connections by reason; every reason is present.
+    pub closes: HashMap<CloseReason, u64>,
+    /// Requests handled, by kind; every kind is present. Replayed responses
+    /// to repeated idempotency keys and shed requests are not counted.
+    pub requests: HashMap<MessageKind, RequestMetrics>,
+    /// Received frames per size bucket (see `SIZE_BUCKET_BOUNDS`).
+    pub inbound_sizes: [u64; SIZE_BUCKETS],
+    /// Sent frames per size bucket, including topic pushes.
+    pub outbound_sizes: [u64; SIZE_BUCKETS],
+    /// Payload bytes received, excluding frame headers.
+    pub bytes_read: u64,
+    /// Payload bytes sent, excluding frame headers.
+    pub bytes_written: u64,
+    /// Resource audits run so far (see `Server::with_resource_audit`).
+    pub audits: u64,
+    /// The most recent resource audit, if any has run.
+    pub last_audit: Option<ResourceAudit>,
+}
+
+/// Resource usage recorded by one periodic audit. Numbers that keep growing
+/// across audits while traffic is steady point at a leak.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceAudit {
+    /// Connections in the connection registry.
+    pub open_connections: usize,
+    /// Jobs waiting for a worker.
+    pub queue_depth: usize,
+    /// Topics with at least one subscriber.
+    pub topics: usize,
+    /// Subscriptions across all topics.
+    pub subscriptions: usize,
+}
@@ -1,259 +1,2852 @@
-use crate::message::{ClientMessage, ServerMessage, EchoMessage, AddRequest, AddResponse};
+use crate::codec::Codec;
+use crate::error::ProtocolError;
+use crate::framing;
+use crate::handler::{self, HandlerRegistry, MessageHandler, MessageKind};
+use crate::metrics::{CloseReason, Metrics, ResourceAudit, ServerMetrics};
+use crate::transport::{ListenAddr, Listener, PeerAddr, Stream};
 use crate::message::client_message::Message as ClientMessageEnum;
 use crate::message::server_message::Message as ServerMessageEnum;
-use log::{error, info, warn};
+use crate::message::{
+    ClientMessage, EchoMessage, LimitsResponse, PongMessage, PublishRequest, PublishResponse,
+    ServerMessage, StatusCode, StatusResponse, StreamEchoRequest, SubscribeAck, Throttle,
+    TopicMessage, UpgradeAck,
+};
+use log::{debug, error, info, warn};
 use prost::Message;
 use std::{
-    io::{self, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
+    any::Any,
+    cell::Cell,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-const MAX_MESSAGE_SIZE: usize = 1024 * 1024; 
+/// Default for the largest request payload a connection accepts.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Default for the most echoes one `StreamEchoRequest` may ask for.
+const MAX_STREAM_ECHO_COUNT: u32 = 1000;
+/// Default for how long a connection may sit idle before it is closed.
 const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default for how long a single write may block on a peer that is not
+/// reading before the connection is closed.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default number of workers serving connections.
 const THREAD_POOL_SIZE: usize = 4;
+const WORKER_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+const QUEUE_DEPTH_THRESHOLDS: [usize; 2] = [100, 1000];
+/// errno values for listener failures `run` handles specially; Linux and
+/// macOS agree on them.
+const EBADF: i32 = 9;
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+/// How often `shutdown_graceful` checks whether connections have drained.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Queue wait that earns a job one level of priority.
+const PRIORITY_AGING_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a response is kept for retries carrying the same idempotency key.
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(60);
+/// Most responses kept for idempotent retries; the oldest are evicted first.
+const IDEMPOTENCY_CAPACITY: usize = 1024;
+/// Longest the accept loop blocks sending the error to a rejected connection.
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longest the accept loop waits for a connection before its periodic work,
+/// such as audits, gets a turn.
+const ACCEPT_WAIT_INTERVAL: Duration = Duration::from_millis(100);
 
 struct ThreadPool {
     workers: Vec<Worker>,
-    sender: crossbeam_channel::Sender<ThreadPoolMessage>,
+    queue: Arc<JobQueue>,
+    join_timeout: Duration,
 }
 
 struct Worker {
+    id: usize,
     thread: Option<JoinHandle<()>>,
 }
 
-enum ThreadPoolMessage {
-    NewJob(Job),
-    Terminate,
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A job waiting for a worker, ordered by `rank`: lower ranks run first and
+/// equal ranks run in submission order.
+///
+/// With aging, a job's effective priority grows by one level per aging
+/// interval spent queued. Every queued job ages at the same rate, so the
+/// order between two jobs never changes while they wait and can be fixed at
+/// submission: the rank is the submission time minus `priority` intervals,
+/// i.e. a job is served as if it had been submitted that much earlier. A
+/// low-priority job is therefore overtaken only by jobs submitted less than
+/// `priority` intervals after it and cannot starve.
+struct QueuedJob {
+    rank: i128,
+    seq: u64,
+    queued_at: Instant,
+    job: Job,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank == other.rank && self.seq == other.seq
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    /// `BinaryHeap` pops the greatest element, so lower ranks compare greater.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .rank
+            .cmp(&self.rank)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: BinaryHeap<QueuedJob>,
+    /// Per-worker queues for connection jobs in pinned mode; empty otherwise.
+    pinned: Vec<BinaryHeap<QueuedJob>>,
+    next_seq: u64,
+    shutdown: bool,
+    /// Ascending queue depths that trigger an overload warning.
+    thresholds: Vec<usize>,
+    /// How many of `thresholds` the depth is currently at or above.
+    level: usize,
+    /// Wait that earns a queued job one priority level; zero disables aging
+    /// so higher priorities always run first.
+    aging: Duration,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.jobs.len() + self.pinned.iter().map(BinaryHeap::len).sum::<usize>()
+    }
+
+    /// Takes the best job `worker` may run: the head of the shared queue or
+    /// of its own pinned queue, whichever ranks first.
+    fn take(&mut self, worker: usize) -> Option<QueuedJob> {
+        let own = self.pinned.get_mut(worker);
+        match (self.jobs.peek(), own.as_ref().and_then(|own| own.peek())) {
+            (Some(shared), Some(pinned)) if pinned > shared => own.and_then(BinaryHeap::pop),
+            (Some(_), _) => self.jobs.pop(),
+            (None, _) => own.and_then(BinaryHeap::pop),
+        }
+    }
+
+    fn check_depth(&mut self, metrics: &Metrics) {
+        let depth = self.len();
+        while self.level < self.thresholds.len() && depth >= self.thresholds[self.level] {
+            warn!(
+                "Job queue depth {} reached warning threshold {}",
+                depth, self.thresholds[self.level]
+            );
+            Metrics::increment(&metrics.queue_depth_warnings);
+            self.level += 1;
+        }
+        while self.level > 0 && depth < self.thresholds[self.level - 1] {
+            self.level -= 1;
+            info!(
+                "Job queue depth {} recovered below threshold {}",
+                depth, self.thresholds[self.level]
+            );
+        }
+    }
+}
+
+/// Priority queue shared by the pool's workers.
+struct JobQueue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+    metrics: Arc<Metrics>,
+    /// Reference point for aging ranks.
+    epoch: Instant,
+}
+
+impl JobQueue {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        JobQueue {
+            state: Mutex::new(QueueState {
+                thresholds: QUEUE_DEPTH_THRESHOLDS.to_vec(),
+                aging: PRIORITY_AGING_INTERVAL,
+                ..QueueState::default()
+            }),
+            available: Condvar::new(),
+            metrics,
+            epoch: Instant::now(),
+        }
+    }
+
+    fn set_aging(&self, interval: Duration) {
+        self.state.lock().unwrap().aging = interval;
+    }
+
+    fn set_thresholds(&self, thresholds: &[usize]) {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        let mut state = self.state.lock().unwrap();
+        state.thresholds = thresholds;
+        state.level = 0;
+    }
+
+    /// Routes connection jobs to a fixed worker per connection from now on.
+    fn set_pinned(&self, workers: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.pinned = (0..workers).map(|_| BinaryHeap::new()).collect();
+    }
+
+    fn depth(&self) -> usize {
+        self.state.lock().unwrap().len()
+    }
+
+    /// Queues a job. Jobs serving a connection pass its id as `connection`,
+    /// which in pinned mode sends them to that connection's worker. Returns
+    /// false once the pool is shutting down, in which case the job is dropped.
+    fn push(&self, priority: u8, connection: Option<u64>, job: Job) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown {
+            return false;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let rank = if state.aging.is_zero() {
+            -(priority as i128)
+        } else {
+            let head_start = priority as i128 * state.aging.as_nanos() as i128;
+            self.epoch.elapsed().as_nanos() as i128 - head_start
+        };
+        let queued = QueuedJob {
+            rank,
+            seq,
+            queued_at: Instant::now(),
+            job,
+        };
+        match connection {
+            Some(id) if !state.pinned.is_empty() => {
+                let worker = pinned_worker(id, state.pinned.len());
+                state.pinned[worker].push(queued);
+                // Only one worker may take this job, so wake them all.
+                self.available.notify_all();
+            }
+            _ => {
+                state.jobs.push(queued);
+                self.available.notify_one();
+            }
+        }
+        state.check_depth(&self.metrics);
+        Metrics::increment(&self.metrics.queued_jobs);
+        true
+    }
+
+    /// Blocks until a job `worker` may run is available. Returns `None` once
+    /// the pool is shutting down and every job it could run has been handed
+    /// out.
+    fn pop(&self, worker: usize) -> Option<QueuedJob> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.take(worker) {
+                state.check_depth(&self.metrics);
+                self.metrics.queued_jobs.fetch_sub(1, Ordering::Relaxed);
+                return Some(queued);
+            }
+            if state.shutdown {
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.available.notify_all();
+    }
+}
+
+thread_local! {
+    /// How long the job running on this worker waited in the queue.
+    static JOB_QUEUE_WAIT: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// The worker that serves `connection` in pinned mode.
+fn pinned_worker(connection: u64, workers: usize) -> usize {
+    (connection % workers as u64) as usize
+}
 
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        let (sender, receiver) = crossbeam_channel::unbounded();
-        let receiver = Arc::new(Mutex::new(receiver));
-        
+    pub fn new(size: usize, metrics: Arc<Metrics>) -> ThreadPool {
+        ThreadPool::named("worker", size, metrics)
+    }
+
+    /// Like `new`, with worker threads named `<name>-<n>`.
+    fn named(name: &str, size: usize, metrics: Arc<Metrics>) -> ThreadPool {
+        let queue = Arc::new(JobQueue::new(metrics));
+
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(name, id, Arc::clone(&queue)));
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool {
+            workers,
+            queue,
+            join_timeout: WORKER_JOIN_TIMEOUT,
+        }
+    }
+
+    /// A new pool of `size` workers whose queue has this pool's settings.
+    fn resized(&self, size: usize) -> ThreadPool {
+        let pool = ThreadPool::new(size, Arc::clone(&self.queue.metrics));
+        {
+            let from = self.queue.state.lock().unwrap();
+            let mut to = pool.queue.state.lock().unwrap();
+            to.thresholds = from.thresholds.clone();
+            to.aging = from.aging;
+            if !from.pinned.is_empty() {
+                to.pinned = (0..size).map(|_| BinaryHeap::new()).collect();
+            }
+        }
+        pool
     }
 
+    #[cfg(test)]
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(ThreadPoolMessage::NewJob(job)).unwrap();
+        self.execute_with_priority(0, f);
+    }
+
+    #[cfg(test)]
+    pub fn execute_with_priority<F>(&self, priority: u8, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.push(priority, None, Box::new(f));
+    }
+
+    /// Queues a job serving `connection`; see `JobQueue::push`.
+    fn execute_for<F>(&self, connection: u64, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.push(0, Some(connection), Box::new(f));
+    }
+
+    /// Returns a handle that lets running jobs queue follow-up jobs.
+    fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            queue: Arc::clone(&self.queue),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct PoolHandle {
+    queue: Arc<JobQueue>,
+}
+
+impl PoolHandle {
+    /// Queues a job. Returns false if the pool has shut down, in which case
+    /// the job is dropped.
+    #[cfg(test)]
+    fn execute_with_priority<F>(&self, priority: u8, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.push(priority, None, Box::new(f))
+    }
+
+    fn depth(&self) -> usize {
+        self.queue.depth()
+    }
+
+    /// Queues a job serving `connection`; see `JobQueue::push`.
+    fn execute_for<F>(&self, connection: u64, priority: u8, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.push(priority, Some(connection), Box::new(f))
     }
 }
 
 impl Drop for ThreadPool {
+    /// Lets workers finish the jobs already queued, then joins them for at
+    /// most `join_timeout` in total. A worker stuck in a long job is detached
+    /// rather than hanging shutdown, and a worker that already panicked is
+    /// logged rather than re-panicking here (a panic in drop during
+    /// unwinding would abort the process).
     fn drop(&mut self) {
-        for _ in &self.workers {
-            self.sender.send(ThreadPoolMessage::Terminate).unwrap();
-        }
+        self.queue.shutdown();
 
+        let deadline = Instant::now() + self.join_timeout;
         for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            let Some(thread) = worker.thread.take() else {
+                continue;
+            };
+
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            if !thread.is_finished() {
+                warn!(
+                    "Worker {} did not stop within {:?}; detaching it",
+                    worker.id, self.join_timeout
+                );
+                continue;
+            }
+
+            if thread.join().is_err() {
+                error!("Worker {} terminated with a panic", worker.id);
             }
         }
     }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<crossbeam_channel::Receiver<ThreadPoolMessage>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            
-            match message {
-                ThreadPoolMessage::NewJob(job) => {
-                    info!("Worker {} got a job; executing.", id);
-                    job();
-                }
-                ThreadPoolMessage::Terminate => {
-                    info!("Worker {} was told to terminate.", id);
-                    break;
+    /// Spawns a worker that runs jobs from `queue` until it shuts down. A
+    /// job that panics is logged and the worker moves on to the next one, so
+    /// a failing handler costs its connection but not pool capacity.
+    fn new(name: &str, id: usize, queue: Arc<JobQueue>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("{}-{}", name, id))
+            .spawn(move || loop {
+                match queue.pop(id) {
+                    Some(queued) => {
+                        info!("Worker {} got a job; executing.", id);
+                        JOB_QUEUE_WAIT.set(queued.queued_at.elapsed());
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(queued.job)) {
+                            error!("Worker {} job panicked: {}", id, panic_message(&payload));
+                        }
+                    }
+                    None => {
+                        info!("Worker {} was told to terminate.", id);
+                        break;
+                    }
                 }
-            }
-        });
+            })
+            .expect("failed to spawn worker thread");
 
         Worker {
+            id,
             thread: Some(thread),
         }
     }
 }
 
-struct Client {
-    stream: TcpStream,
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
 }
 
-impl Client {
-    pub fn new(stream: TcpStream) -> io::Result<Self> {
-        stream.set_read_timeout(Some(READ_TIMEOUT))?;
-        stream.set_nodelay(true)?;
-        Ok(Client { stream })
+/// When a connection's unread requests arrived, for shedding those that
+/// waited too long for a worker. Positions are offsets into the bytes the
+/// connection has received. The log is sampled when the connection is
+/// queued and, while it waits, by the accept loop. A request that was not
+/// seen before its turn started counts as having just arrived.
+#[derive(Default)]
+struct ArrivalLog {
+    /// Bytes read from the socket so far.
+    read: AtomicU64,
+    /// Whether the connection is waiting for a worker.
+    waiting: AtomicBool,
+    /// Offsets received by a given time, increasing in both.
+    marks: Mutex<VecDeque<(u64, Instant)>>,
+}
+
+impl ArrivalLog {
+    /// Notes that everything `stream` has received so far had arrived by
+    /// now.
+    fn sample(&self, stream: &Stream) {
+        let Ok(pending) = stream.pending_bytes() else {
+            return;
+        };
+        let offset = self.read.load(Ordering::SeqCst) + pending as u64;
+        let mut marks = self.marks.lock().unwrap();
+        let last = marks.back().map_or(0, |&(offset, _)| offset);
+        if offset > last {
+            marks.push_back((offset, Instant::now()));
+        }
     }
 
-    fn read_message(&mut self) -> io::Result<Vec<u8>> {
-        let mut len_buf = [0u8; 4];
-        self.stream.read_exact(&mut len_buf)?;
-        
-        let message_len = u32::from_be_bytes(len_buf) as usize;
-        if message_len > MAX_MESSAGE_SIZE {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Message size exceeds maximum allowed",
-            ));
+    /// When the request ending at `offset` was first seen, if it was seen
+    /// while the connection waited. Marks for earlier requests are dropped.
+    fn arrival(&self, offset: u64) -> Option<Instant> {
+        let mut marks = self.marks.lock().unwrap();
+        while marks.front().is_some_and(|&(end, _)| end < offset) {
+            marks.pop_front();
         }
+        marks.front().map(|&(_, seen)| seen)
+    }
+}
+
+/// Counts the bytes read through it into an `ArrivalLog`, so the offset at
+/// which each request ends is known whatever its framing or encoding.
+struct CountingReader {
+    inner: Stream,
+    arrivals: Arc<ArrivalLog>,
+}
 
-        let mut buffer = vec![0; message_len];
-        self.stream.read_exact(&mut buffer)?;
-        Ok(buffer)
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.arrivals.read.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
     }
+}
 
-    fn write_message(&mut self, payload: &[u8]) -> io::Result<()> {
-        let len = payload.len() as u32;
-        self.stream.write_all(&len.to_be_bytes())?;
-        self.stream.write_all(payload)?;
-        self.stream.flush()
-    }
-
-    pub fn handle(&mut self) -> io::Result<bool> {
-        self.stream.set_nonblocking(false)?;
-        match self.read_message() {
-            Ok(buffer) => {
-                match ClientMessage::decode(&buffer[..]) {
-                    Ok(client_msg) => {
-                        if let Some(message) = client_msg.message {
-                            let response = match message {
-                                ClientMessageEnum::EchoMessage(echo) => {
-                                    info!("Handling echo message: {}", echo.content);
-                                    self.handle_echo(echo)
-                                }
-                                ClientMessageEnum::AddRequest(add) => {
-                                    info!("Handling add request: {} + {}", add.a, add.b);
-                                    self.handle_add(add)
-                                }
-                            }?;
-                            
-                            let encoded = response.encode_to_vec();
-                            self.write_message(&encoded)?;
-                            Ok(true)
-                        } else {
-                            warn!("Received empty message");
-                            Ok(true)
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to decode message: {}", e);
-                        Ok(false)
-                    }
+struct RegisteredConnection {
+    stream: Stream,
+    arrivals: Arc<ArrivalLog>,
+}
+
+/// Tracks a handle to every open connection so `Server::stop` can close them
+/// instead of leaving handlers blocked in `read` until the read timeout.
+#[derive(Default)]
+struct ConnectionRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, RegisteredConnection>>,
+    /// Set once the server starts closing connections, so handlers can tell
+    /// a shutdown from the client hanging up.
+    closing: AtomicBool,
+}
+
+impl ConnectionRegistry {
+    fn register(&self, stream: &Stream, arrivals: &Arc<ArrivalLog>) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let connection = RegisteredConnection {
+            stream: stream.try_clone()?,
+            arrivals: Arc::clone(arrivals),
+        };
+        self.streams.lock().unwrap().insert(id, connection);
+        Ok(id)
+    }
+
+    /// Samples the arrival log of every connection waiting for a worker.
+    fn sample_arrivals(&self) {
+        for connection in self.streams.lock().unwrap().values() {
+            if connection.arrivals.waiting.load(Ordering::SeqCst) {
+                connection.arrivals.sample(&connection.stream);
+            }
+        }
+    }
+
+    fn unregister(&self, id: u64) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Closes the read side of every connection: handlers blocked waiting
+    /// for a request see EOF, while responses being written still go out.
+    fn shutdown_reads(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        for connection in self.streams.lock().unwrap().values() {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    fn shutdown_all(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        let streams = self.streams.lock().unwrap();
+        if !streams.is_empty() {
+            info!("Closing {} open connection(s)", streams.len());
+        }
+        for connection in streams.values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Subscribers per topic, keyed by connection id. Each subscriber is the
+/// connection's shared writer so `Server::publish` can push frames from any
+/// thread without interleaving with that connection's responses.
+#[derive(Default)]
+struct TopicRegistry {
+    topics: Mutex<HashMap<String, HashMap<u64, SharedWriter>>>,
+}
+
+impl TopicRegistry {
+    fn subscribe(&self, topic: &str, connection_id: u64, writer: &SharedWriter) {
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .insert(connection_id, Arc::clone(writer));
+    }
+
+    fn unsubscribe(&self, topic: &str, connection_id: u64) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(subscribers) = topics.get_mut(topic) else {
+            return false;
+        };
+        let removed = subscribers.remove(&connection_id).is_some();
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+        removed
+    }
+
+    fn remove_connection(&self, connection_id: u64) {
+        let mut topics = self.topics.lock().unwrap();
+        topics.retain(|_, subscribers| {
+            subscribers.remove(&connection_id);
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Number of topics and of subscriptions across them.
+    fn counts(&self) -> (usize, usize) {
+        let topics = self.topics.lock().unwrap();
+        (topics.len(), topics.values().map(HashMap::len).sum())
+    }
+
+    fn subscribers(&self, topic: &str) -> Vec<(u64, SharedWriter)> {
+        self.topics
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .map(|(id, writer)| (*id, Arc::clone(writer)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Lets identical requests that are in flight at the same time share one
+/// handler run and its response. Requests are keyed by their encoded bytes,
+/// so only exact duplicates are merged.
+#[derive(Default)]
+struct RequestCoalescer {
+    in_flight: Mutex<HashMap<Vec<u8>, Arc<InFlight>>>,
+}
+
+#[derive(Default)]
+struct InFlight {
+    response: Mutex<Option<ServerMessage>>,
+    done: Condvar,
+}
+
+impl RequestCoalescer {
+    /// Runs `compute` unless an identical request is already being handled,
+    /// in which case its response is awaited and shared instead.
+    fn run<F>(&self, key: Vec<u8>, compute: F) -> ServerMessage
+    where
+        F: FnOnce() -> ServerMessage,
+    {
+        let leader = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(entry) => Err(Arc::clone(entry)),
+                None => {
+                    let entry = Arc::new(InFlight::default());
+                    in_flight.insert(key.clone(), Arc::clone(&entry));
+                    Ok(entry)
                 }
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    Ok(false)
-                } else {
-                    Err(e)
+        };
+
+        match leader {
+            Ok(entry) => {
+                let mut completion = Completion {
+                    coalescer: self,
+                    key,
+                    entry,
+                    response: None,
+                };
+                let response = compute();
+                completion.response = Some(response.clone());
+                response
+            }
+            Err(entry) => {
+                let mut response = entry.response.lock().unwrap();
+                while response.is_none() {
+                    response = entry.done.wait(response).unwrap();
                 }
+                response.clone().unwrap()
             }
         }
     }
+}
 
-    fn handle_echo(&mut self, msg: EchoMessage) -> io::Result<ServerMessage> {
-        Ok(ServerMessage {
-            message: Some(ServerMessageEnum::EchoMessage(msg))
-        })
+/// Publishes the leader's response to waiting duplicates when dropped, or an
+/// internal error if the handler panicked, so they never wait forever.
+struct Completion<'a> {
+    coalescer: &'a RequestCoalescer,
+    key: Vec<u8>,
+    entry: Arc<InFlight>,
+    response: Option<ServerMessage>,
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        self.coalescer.in_flight.lock().unwrap().remove(&self.key);
+        let response = self.response.take().unwrap_or_else(|| {
+            handler::error_response(StatusCode::Internal, "Coalesced request failed")
+        });
+        *self.entry.response.lock().unwrap() = Some(response);
+        self.entry.done.notify_all();
     }
+}
 
-    fn handle_add(&mut self, req: AddRequest) -> io::Result<ServerMessage> {
-        let result = req.a + req.b;
-        Ok(ServerMessage {
-            message: Some(ServerMessageEnum::AddResponse(AddResponse {
-                result,
-            }))
-        })
+/// Responses to requests that carried an idempotency key, so a retry of a
+/// request that was already handled gets the original response instead of
+/// running the handler again. Keys are shared across connections, since a
+/// retry usually arrives on a new one. A retry that arrives while the
+/// original is still being handled is not deduplicated.
+#[derive(Default)]
+struct IdempotencyCache {
+    responses: Mutex<HashMap<String, (Instant, ServerMessage)>>,
+}
+
+impl IdempotencyCache {
+    fn get(&self, key: &str) -> Option<ServerMessage> {
+        let responses = self.responses.lock().unwrap();
+        match responses.get(key) {
+            Some((stored, response)) if stored.elapsed() < IDEMPOTENCY_TTL => {
+                Some(response.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: String, response: ServerMessage) {
+        let mut responses = self.responses.lock().unwrap();
+        if responses.len() >= IDEMPOTENCY_CAPACITY {
+            responses.retain(|_, (stored, _)| stored.elapsed() < IDEMPOTENCY_TTL);
+        }
+        if responses.len() >= IDEMPOTENCY_CAPACITY {
+            let oldest = responses
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                responses.remove(&oldest);
+            }
+        }
+        responses.insert(key, (Instant::now(), response));
     }
 }
 
-pub struct Server {
-    listener: TcpListener,
-    is_running: Arc<AtomicBool>,
-    thread_pool: ThreadPool,
+/// Tunables fixed before `run`.
+#[derive(Clone, Default)]
+struct ServerConfig {
+    /// Requests served per turn before a connection yields its worker;
+    /// zero means unlimited.
+    max_messages_per_turn: usize,
+    /// Requests per second each connection may make; zero means unlimited.
+    max_requests_per_second: u32,
+    /// Requests served before a connection is closed; zero means unlimited.
+    max_requests_per_connection: usize,
+    /// Most responses a single `StreamEchoRequest` may ask for.
+    max_stream_echo_count: u32,
+    /// Wire encoding of requests and responses.
+    codec: Codec,
+    /// Whether frames carry `FRAME_SYNC_MARKER`.
+    frame_sync: bool,
+    /// Answer malformed frames with a RESET control frame instead of
+    /// closing the connection or replying with an error.
+    reset_on_malformed: bool,
+    /// Requests taking longer than this to handle are logged and counted.
+    slow_request_threshold: Option<Duration>,
+    /// Requests that waited longer than this for a worker are shed.
+    queue_wait_budget: Option<Duration>,
+    /// Share one handler run between identical concurrent requests.
+    coalesce_requests: bool,
+    /// How often `run` audits and logs its resource usage.
+    audit_interval: Option<Duration>,
+    /// Bind a new listener if the current one's descriptor is closed.
+    rebind_listener: bool,
+    /// Idle time after which a connection is closed; `None` never closes
+    /// idle connections.
+    read_timeout: Option<Duration>,
+    /// How long a write may block on a peer that stops reading before the
+    /// connection is closed; `None` waits indefinitely.
+    write_timeout: Option<Duration>,
+    /// Largest request payload accepted; larger frames close the connection.
+    max_message_size: usize,
+    /// Queue depth at which clients are sent a `Throttle`, and the delay it
+    /// asks for.
+    backpressure: Option<(usize, Duration)>,
+    /// At most this many connections accepted per interval; `None` accepts
+    /// as fast as they arrive.
+    accept_rate: Option<(usize, Duration)>,
+    /// Most connections open at once; `None` is unlimited.
+    max_connections: Option<usize>,
+    /// TCP keepalive idle time and probe interval for accepted connections;
+    /// `None` leaves keepalive off.
+    keepalive: Option<(Duration, Duration)>,
 }
 
-impl Server {
-    pub fn new(addr: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        listener.set_nonblocking(true)?;
-        
-        Ok(Server {
-            listener,
-            is_running: Arc::new(AtomicBool::new(false)),
-            thread_pool: ThreadPool::new(THREAD_POOL_SIZE),
+impl ServerConfig {
+    /// Writes one response, given its protobuf encoding, in the configured
+    /// codec and framing.
+    fn write_frame<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        self.codec.write_frame::<ServerMessage, _>(writer, payload, self.frame_sync)
+    }
+}
+
+/// Token bucket limiting one connection's requests: `rate` per second on
+/// average, in bursts of up to `rate`.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available.
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Fixed-window limiter for the accept loop. Connections over the limit are
+/// left in the listener's backlog until the next window opens.
+struct AcceptLimiter {
+    limit: usize,
+    interval: Duration,
+    window_start: Instant,
+    accepted: usize,
+}
+
+impl AcceptLimiter {
+    fn new(limit: usize, interval: Duration) -> Self {
+        AcceptLimiter {
+            limit: limit.max(1),
+            interval,
+            window_start: Instant::now(),
+            accepted: 0,
+        }
+    }
+
+    /// Blocks until another connection may be accepted in the current window.
+    fn wait(&mut self) {
+        if self.window_start.elapsed() >= self.interval {
+            self.window_start = Instant::now();
+            self.accepted = 0;
+        }
+        if self.accepted >= self.limit {
+            thread::sleep(self.interval.saturating_sub(self.window_start.elapsed()));
+            self.window_start = Instant::now();
+            self.accepted = 0;
+        }
+    }
+
+    fn record(&mut self) {
+        self.accepted += 1;
+    }
+}
+
+/// State shared between the accept loop and every connection handler.
+struct ServerState {
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+    handlers: Arc<dyn MessageHandler + Send + Sync>,
+    connections: ConnectionRegistry,
+    topics: TopicRegistry,
+    coalescer: RequestCoalescer,
+    idempotency: IdempotencyCache,
+    /// Request kinds served on a dedicated pool instead of the default one.
+    routes: HashMap<MessageKind, PoolHandle>,
+    /// When the server was created; uptime in status responses counts from
+    /// here.
+    started: Instant,
+}
+
+impl ServerState {
+    /// Writes a `TopicMessage` to each subscriber of `topic`, returning how
+    /// many it reached.
+    fn publish(&self, topic: &str, content: &str) -> usize {
+        let payload = ServerMessage {
+            message: Some(ServerMessageEnum::TopicMessage(TopicMessage {
+                topic: topic.to_string(),
+                content: content.to_string(),
+            })),
+        }
+        .encode_to_vec();
+
+        let mut delivered = 0;
+        for (connection_id, writer) in self.topics.subscribers(topic) {
+            let mut writer = writer.lock().unwrap();
+            match self.config.write_frame(&mut *writer, &payload) {
+                Ok(()) => {
+                    self.metrics.record_outbound(payload.len());
+                    delivered += 1;
+                }
+                Err(e) => warn!("Failed to publish to client {}: {}", connection_id, e),
+            }
+        }
+        delivered
+    }
+}
+
+type SharedWriter = Arc<Mutex<Stream>>;
+
+struct Client {
+    id: u64,
+    addr: PeerAddr,
+    /// Reads are buffered, so a frame's length prefix and payload usually
+    /// take one `read` call. Writes go through `writer`, one call per frame.
+    stream: BufReader<CountingReader>,
+    arrivals: Arc<ArrivalLog>,
+    writer: SharedWriter,
+    state: Arc<ServerState>,
+    /// End of the delay this connection was last asked to observe.
+    throttled_until: Option<Instant>,
+    /// Set when the server limits each connection's request rate.
+    rate_limiter: Option<RateLimiter>,
+    /// Requests handled so far, across turns.
+    requests_served: usize,
+    /// Why the connection is ending, once known; logged and counted on drop.
+    close_reason: Option<CloseReason>,
+}
+
+impl Client {
+    /// Prepares an accepted stream and registers it with the server. The
+    /// registration is undone when the `Client` is dropped.
+    pub fn new(stream: Stream, addr: PeerAddr, state: Arc<ServerState>) -> io::Result<Self> {
+        // Accepted sockets may inherit the listener's non-blocking mode.
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(state.config.read_timeout)?;
+        stream.set_write_timeout(state.config.write_timeout)?;
+        if let Some(tcp) = stream.as_tcp() {
+            tcp.set_nodelay(true)?;
+            if let Some((idle, interval)) = state.config.keepalive {
+                set_keepalive(tcp, idle, interval)?;
+            }
+        }
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let arrivals = Arc::new(ArrivalLog::default());
+        let id = state.connections.register(&stream, &arrivals)?;
+        info!("Client {} connected from {}", id, addr);
+        let rate = state.config.max_requests_per_second;
+        Ok(Client {
+            id,
+            addr,
+            stream: BufReader::new(CountingReader {
+                inner: stream,
+                arrivals: Arc::clone(&arrivals),
+            }),
+            arrivals,
+            writer,
+            state,
+            throttled_until: None,
+            rate_limiter: (rate > 0).then(|| RateLimiter::new(rate)),
+            requests_served: 0,
+            close_reason: None,
         })
     }
 
-    pub fn run(&self) -> io::Result<()> {
-        self.is_running.store(true, Ordering::SeqCst);
-        info!("Server running on {}", self.listener.local_addr()?);
+    /// Reads the next frame; `Ok(None)` means the client closed the
+    /// connection cleanly between frames.
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let sync = self.state.config.frame_sync;
+        let max_len = self.state.config.max_message_size;
+        let frame = self.state.config.codec.try_read_frame(&mut self.stream, sync, max_len)?;
+        if let Some(ref buffer) = frame {
+            self.state.metrics.record_inbound(buffer.len());
+        }
+        Ok(frame)
+    }
 
-        while self.is_running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr);
-                    let is_running = Arc::clone(&self.is_running);
-                    
-                    self.thread_pool.execute(move || {
-                        if let Ok(mut client) = Client::new(stream) {
-                            while is_running.load(Ordering::SeqCst) {
-                                match client.handle() {
-                                    Ok(true) => continue,
-                                    Ok(false) => break,
-                                    Err(e) => {
-                                        error!("Error handling client: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        info!("Client {} disconnected", addr);
-                    });
+    fn write_message(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.state.config.write_frame(&mut *writer, payload)?;
+        self.state.metrics.record_outbound(payload.len());
+        Ok(())
+    }
+
+    /// Reads and decodes the next request. `Ok(None)` means the client closed
+    /// the connection cleanly between frames; a misaligned frame or a
+    /// connection dropped part-way through a frame is an error. A frame that
+    /// arrives intact but does not decode is answered with an
+    /// `InvalidArgument` error and reading carries on.
+    ///
+    /// With `reset_on_malformed`, undecodable or misaligned frames are
+    /// answered with RESET instead and reading carries on from the
+    /// resynchronised stream. JSON lines cannot be reset and are always
+    /// answered with an error.
+    fn read_request(&mut self) -> io::Result<Option<ClientMessage>> {
+        let codec = self.state.config.codec;
+        let reset = self.state.config.reset_on_malformed && codec == Codec::Protobuf;
+        loop {
+            let buffer = match self.read_message() {
+                Ok(Some(buffer)) => buffer,
+                Ok(None) => return Ok(None),
+                // A client-initiated reset between frames needs no reply.
+                Err(ref e) if framing::is_reset(e) => continue,
+                Err(e) if reset && e.kind() == ErrorKind::InvalidData => {
+                    warn!("Client {} is out of sync ({}); sending RESET", self.id, e);
+                    self.send_reset()?;
+                    let limit = self.state.config.max_message_size;
+                    framing::skip_to_reset(&mut self.stream, limit)?;
+                    continue;
                 }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(100));
+                Err(e) => return Err(e),
+            };
+            match codec.decode::<ClientMessage>(&buffer) {
+                Ok(request) => return Ok(Some(request)),
+                Err(_) if codec == Codec::Protobuf && has_invalid_utf8_echo(&buffer) => {
+                    warn!("Client {} sent an echo that is not valid UTF-8", self.id);
+                    let response = handler::error_response(
+                        StatusCode::InvalidArgument,
+                        "Echo content is not valid UTF-8",
+                    );
+                    self.write_message(&response.encode_to_vec())?;
+                }
+                Err(e) if reset => {
+                    // The frame boundary is intact, so no resync is needed.
+                    warn!(
+                        "Failed to decode message from client {}: {}; sending RESET",
+                        self.id, e
+                    );
+                    self.send_reset()?;
                 }
                 Err(e) => {
-                    error!("Accept error: {}", e);
-                    break;
+                    warn!("Failed to decode message from client {}: {}", self.id, e);
+                    let response = handler::error_response(
+                        StatusCode::InvalidArgument,
+                        format!("Failed to decode message: {}", e),
+                    );
+                    self.write_message(&response.encode_to_vec())?;
                 }
             }
         }
-
-        info!("Server stopped");
-        Ok(())
     }
 
-    pub fn stop(&self) {
-        if self.is_running.load(Ordering::SeqCst) {
-            self.is_running.store(false, Ordering::SeqCst);
-            // Connect to self to unblock accept
-            if let Ok(addr) = self.listener.local_addr() {
-                let _ = TcpStream::connect(addr);
-            }
-            info!("Shutdown signal sent");
-        } else {
-            warn!("Server already stopped or not running");
+    /// Marks the connection as waiting for a worker, noting which requests
+    /// have already arrived. Call before queuing it.
+    fn park(&self) {
+        if self.state.config.queue_wait_budget.is_some() {
+            self.arrivals.waiting.store(true, Ordering::SeqCst);
+            self.arrivals.sample(&self.stream.get_ref().inner);
         }
     }
-}
\ No newline at end of file
+
+    /// How long the request just read waited for a worker, if it arrived
+    /// while the connection was queued.
+    fn queue_wait(&self) -> Option<Duration> {
+        let end = self.arrivals.read.load(Ordering::SeqCst) - self.stream.buffer().len() as u64;
+        self.arrivals.arrival(end).map(|seen| seen.elapsed())
+    }
+
+    /// The response rejecting the next request if it would exceed the
+    /// connection's rate limit.
+    fn check_rate_limit(&mut self) -> Option<ServerMessage> {
+        if self.rate_limiter.as_mut()?.try_acquire() {
+            return None;
+        }
+        warn!("Client {} exceeded its request rate limit", self.id);
+        Metrics::increment(&self.state.metrics.rate_limited_requests);
+        Some(handler::error_response(
+            StatusCode::ResourceExhausted,
+            "rate limited",
+        ))
+    }
+
+    /// Whether the connection has been served `max_requests_per_connection`
+    /// requests and should be closed.
+    fn request_limit_reached(&self) -> bool {
+        let limit = self.state.config.max_requests_per_connection;
+        limit != 0 && self.requests_served >= limit
+    }
+
+    /// Answers `request`, which waited `waited` for a worker, with
+    /// `Unavailable` instead of handling it.
+    fn shed(&mut self, request: &ClientMessage, waited: Duration) -> io::Result<()> {
+        let kind = request.message.as_ref().map(MessageKind::of);
+        warn!(
+            "Shedding {:?} request from client {}: waited {:?} for a worker",
+            kind, self.id, waited
+        );
+        Metrics::increment(&self.state.metrics.shed_requests);
+        let response = handler::error_response(StatusCode::Unavailable, "overloaded");
+        self.write_message(&response.encode_to_vec())
+    }
+
+    /// Sends a `Throttle` ahead of the next response if the queue is backed
+    /// up past the backpressure threshold. A connection is throttled at most
+    /// once per retry interval.
+    fn signal_backpressure(&mut self, queue_depth: usize) -> io::Result<()> {
+        let Some((threshold, retry_after)) = self.state.config.backpressure else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if queue_depth < threshold || self.throttled_until.is_some_and(|until| now < until) {
+            return Ok(());
+        }
+        info!(
+            "Throttling client {} for {:?}: {} job(s) queued",
+            self.id, retry_after, queue_depth
+        );
+        self.throttled_until = Some(now + retry_after);
+        Metrics::increment(&self.state.metrics.throttles_sent);
+        let throttle = ServerMessage {
+            message: Some(ServerMessageEnum::Throttle(Throttle {
+                retry_after_ms: retry_after.as_millis().min(u32::MAX as u128) as u32,
+            })),
+        };
+        self.write_message(&throttle.encode_to_vec())
+    }
+
+    /// Logs a failure to write a response. A peer that closed or reset the
+    /// connection mid-response has simply gone away, so that is logged at
+    /// info level and counted in `write_disconnects` rather than reported as
+    /// an error. A peer that stopped reading until the write timed out is
+    /// closed as a timeout.
+    fn report_write_error(&mut self, e: &io::Error) {
+        if is_disconnect(e) {
+            info!("Client {} disconnected mid-response: {}", self.id, e);
+            Metrics::increment(&self.state.metrics.write_disconnects);
+            self.close_reason = Some(CloseReason::ConnectionLost);
+        } else if is_timeout(e) {
+            warn!("Client {} stopped reading; write timed out", self.id);
+            self.close_reason = Some(CloseReason::Timeout);
+        } else {
+            error!("Error handling client {}: {}", self.id, e);
+            self.close_reason = Some(CloseReason::Error);
+        }
+    }
+
+    /// Records why reading the next request ended the connection. Failures
+    /// caused by the server closing connections count as a shutdown.
+    fn read_ended(&mut self, result: io::Result<()>) {
+        let reason = if self.state.connections.closing.load(Ordering::SeqCst) {
+            CloseReason::Shutdown
+        } else {
+            match result {
+                Ok(()) => CloseReason::ClientClosed,
+                Err(ref e) => read_close_reason(e),
+            }
+        };
+        if let Err(e) = result {
+            match reason {
+                CloseReason::Error => error!("Error handling client {}: {}", self.id, e),
+                CloseReason::Malformed | CloseReason::Oversized => {
+                    warn!("Client {} sent a bad frame: {}", self.id, e)
+                }
+                CloseReason::ConnectionLost if framing::is_truncated(&e) => {
+                    warn!("Client {} sent a truncated frame: {}", self.id, e)
+                }
+                _ => {}
+            }
+        }
+        self.close_reason = Some(reason);
+    }
+
+    fn send_reset(&mut self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        framing::write_reset(&mut *writer, self.state.config.frame_sync)
+    }
+
+    /// Handles a decoded request and writes its response.
+    fn respond(&mut self, request: ClientMessage) -> io::Result<()> {
+        let Some(message) = request.message else {
+            warn!("Client {} sent an empty message", self.id);
+            return Ok(());
+        };
+        // Checked before the idempotency cache, so a rejection is not
+        // replayed to a retry.
+        if let Some(rejection) = self.check_rate_limit() {
+            return self.write_message(&rejection.encode_to_vec());
+        }
+        self.requests_served += 1;
+        if let ClientMessageEnum::StreamEchoRequest(stream) = message {
+            for response in self.stream_echo(stream) {
+                self.write_message(&response.encode_to_vec())?;
+            }
+            return Ok(());
+        }
+
+        let response = if request.idempotency_key.is_empty() {
+            self.handle(message)
+        } else if let Some(response) = self.state.idempotency.get(&request.idempotency_key) {
+            info!(
+                "Client {} repeated idempotency key {}; replaying the response",
+                self.id, request.idempotency_key
+            );
+            response
+        } else {
+            let response = self.handle(message);
+            self.state
+                .idempotency
+                .insert(request.idempotency_key, response.clone());
+            response
+        };
+        let encoded = response.encode_to_vec();
+        self.write_message(&encoded)
+    }
+
+    fn handle(&mut self, message: ClientMessageEnum) -> ServerMessage {
+        let kind = MessageKind::of(&message);
+        let start = Instant::now();
+        let _serving = handler::serving(self.id);
+        let response = match message {
+            ClientMessageEnum::Subscribe(sub) => self.handle_subscribe(sub.topic),
+            ClientMessageEnum::Unsubscribe(unsub) => self.handle_unsubscribe(unsub.topic),
+            // Only reached once already streaming; acknowledge again.
+            ClientMessageEnum::Upgrade(_) => upgrade_ack(),
+            ClientMessageEnum::LimitsRequest(_) => ServerMessage {
+                message: Some(ServerMessageEnum::LimitsResponse(LimitsResponse {
+                    max_message_size: self.state.config.max_message_size as u32,
+                })),
+            },
+            ClientMessageEnum::PingMessage(ping) => self.handle_ping(ping.nonce),
+            ClientMessageEnum::StatusRequest(_) => self.handle_status(),
+            ClientMessageEnum::PublishRequest(publish) => self.handle_publish(publish),
+            other if self.state.config.coalesce_requests => {
+                let mut key = Vec::with_capacity(other.encoded_len());
+                other.encode(&mut key);
+                let handlers = &self.state.handlers;
+                self.state.coalescer.run(key, || handlers.handle(other))
+            }
+            other => self.state.handlers.handle(other),
+        };
+
+        let elapsed = start.elapsed();
+        debug!("Client {} {} request handled in {:?}", self.id, kind, elapsed);
+        let failed = matches!(response.message, Some(ServerMessageEnum::ErrorResponse(_)));
+        self.state.metrics.record_request(kind, elapsed, failed);
+        if let Some(threshold) = self.state.config.slow_request_threshold {
+            if elapsed > threshold {
+                warn!(
+                    "Slow {} request from client {}: handled in {:?} (threshold {:?})",
+                    kind, self.id, elapsed, threshold
+                );
+                Metrics::increment(&self.state.metrics.slow_requests);
+            }
+        }
+        response
+    }
+
+    /// Acknowledges an `Upgrade` and hands the connection to a dedicated
+    /// reader and writer thread, releasing its pool worker. From then on
+    /// replies are queued to the writer as they are produced, so the reader
+    /// never waits on a slow peer and server pushes can interleave freely.
+    fn upgrade(mut self) -> io::Result<()> {
+        self.write_message(&upgrade_ack().encode_to_vec())?;
+        // Streams may idle indefinitely; `stop` still closes them through the
+        // connection registry.
+        self.stream.get_ref().inner.set_read_timeout(None)?;
+        info!("Client {} upgraded to streaming", self.id);
+
+        let (outbound, queued) = mpsc::channel::<ServerMessage>();
+        let writer = Arc::clone(&self.writer);
+        let state = Arc::clone(&self.state);
+        let id = self.id;
+        thread::Builder::new()
+            .name(format!("duplex-writer-{}", self.id))
+            .spawn(move || {
+                for message in queued {
+                    let payload = message.encode_to_vec();
+                    let mut writer = writer.lock().unwrap();
+                    if let Err(e) = state.config.write_frame(&mut *writer, &payload) {
+                        if is_disconnect(&e) {
+                            info!("Client {} disconnected mid-response: {}", id, e);
+                            Metrics::increment(&state.metrics.write_disconnects);
+                        } else if is_timeout(&e) {
+                            warn!("Client {} stopped reading; write timed out", id);
+                        } else {
+                            error!("Error writing to streaming client {}: {}", id, e);
+                        }
+                        break;
+                    }
+                    state.metrics.record_outbound(payload.len());
+                }
+            })?;
+        thread::Builder::new()
+            .name(format!("duplex-reader-{}", self.id))
+            .spawn(move || self.read_duplex(outbound))?;
+        Ok(())
+    }
+
+    fn read_duplex(mut self, outbound: mpsc::Sender<ServerMessage>) {
+        loop {
+            if self.request_limit_reached() {
+                info!("Client {} reached its request limit; closing", self.id);
+                self.close_reason = Some(CloseReason::RequestLimit);
+                break;
+            }
+            let request = match self.read_request() {
+                Ok(Some(request)) => request,
+                Ok(None) => {
+                    self.read_ended(Ok(()));
+                    break;
+                }
+                Err(e) => {
+                    self.read_ended(Err(e));
+                    break;
+                }
+            };
+            let Some(message) = request.message else {
+                warn!("Client {} sent an empty message", self.id);
+                continue;
+            };
+            let responses = if let Some(rejection) = self.check_rate_limit() {
+                vec![rejection]
+            } else {
+                self.requests_served += 1;
+                match message {
+                    ClientMessageEnum::StreamEchoRequest(stream) => self.stream_echo(stream),
+                    other => vec![self.handle(other)],
+                }
+            };
+            if responses.into_iter().any(|response| outbound.send(response).is_err()) {
+                break;
+            }
+        }
+    }
+
+    /// Answers a `StreamEchoRequest` with `count` echoes, or with a single
+    /// `InvalidArgument` error if `count` is over the configured maximum.
+    /// Not coalesced or cached for idempotent retries, unlike single
+    /// responses.
+    fn stream_echo(&mut self, request: StreamEchoRequest) -> Vec<ServerMessage> {
+        let start = Instant::now();
+        let max = self.state.config.max_stream_echo_count;
+        let rejected = request.count > max;
+        let responses = if rejected {
+            warn!(
+                "Client {} asked for {} echoes; the maximum is {}",
+                self.id, request.count, max
+            );
+            vec![handler::error_response(
+                StatusCode::InvalidArgument,
+                format!("Stream echo count {} exceeds maximum {}", request.count, max),
+            )]
+        } else {
+            let echo = ServerMessage {
+                message: Some(ServerMessageEnum::EchoMessage(EchoMessage {
+                    content: request.content,
+                })),
+            };
+            vec![echo; request.count as usize]
+        };
+        self.state
+            .metrics
+            .record_request(MessageKind::StreamEcho, start.elapsed(), rejected);
+        responses
+    }
+
+    /// Answers a ping with the server's current load.
+    fn handle_ping(&self, nonce: u64) -> ServerMessage {
+        let metrics = &self.state.metrics;
+        ServerMessage {
+            message: Some(ServerMessageEnum::PongMessage(PongMessage {
+                nonce,
+                active_connections: self.state.connections.len() as u32,
+                queue_depth: metrics.queued_jobs.load(Ordering::Relaxed) as u32,
+                error_rate: metrics.recent_outcomes.error_rate(),
+            })),
+        }
+    }
+
+    fn handle_status(&self) -> ServerMessage {
+        let state = &self.state;
+        ServerMessage {
+            message: Some(ServerMessageEnum::StatusResponse(StatusResponse {
+                uptime_secs: state.started.elapsed().as_secs(),
+                active_connections: state.connections.len() as u32,
+                total_requests: state.metrics.total_requests(),
+            })),
+        }
+    }
+
+    fn handle_publish(&self, publish: PublishRequest) -> ServerMessage {
+        let delivered = self.state.publish(&publish.topic, &publish.content);
+        info!(
+            "Client {} published to {} ({} subscribers)",
+            self.id, publish.topic, delivered
+        );
+        ServerMessage {
+            message: Some(ServerMessageEnum::PublishResponse(PublishResponse {
+                delivered: delivered as u32,
+            })),
+        }
+    }
+
+    fn handle_subscribe(&mut self, topic: String) -> ServerMessage {
+        info!("Client {} subscribed to {}", self.id, topic);
+        self.state.topics.subscribe(&topic, self.id, &self.writer);
+        ServerMessage {
+            message: Some(ServerMessageEnum::SubscribeAck(SubscribeAck {
+                topic,
+                subscribed: true,
+            })),
+        }
+    }
+
+    fn handle_unsubscribe(&mut self, topic: String) -> ServerMessage {
+        if self.state.topics.unsubscribe(&topic, self.id) {
+            info!("Client {} unsubscribed from {}", self.id, topic);
+        }
+        ServerMessage {
+            message: Some(ServerMessageEnum::SubscribeAck(SubscribeAck {
+                topic,
+                subscribed: false,
+            })),
+        }
+    }
+}
+
+/// `ClientMessage` with the echo content read as raw bytes, so an echo
+/// rejected by the UTF-8 check in the generated decoder can be told apart
+/// from a frame that is not a `ClientMessage` at all.
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawEchoRequest {
+    #[prost(message, optional, tag = "1")]
+    echo_message: Option<RawEcho>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawEcho {
+    #[prost(bytes = "vec", tag = "1")]
+    content: Vec<u8>,
+}
+
+fn has_invalid_utf8_echo(buffer: &[u8]) -> bool {
+    RawEchoRequest::decode(buffer)
+        .ok()
+        .and_then(|request| request.echo_message)
+        .is_some_and(|echo| std::str::from_utf8(&echo.content).is_err())
+}
+
+/// Whether `e` means the peer closed or reset the connection, which is how
+/// clients normally leave rather than a server fault.
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+/// Whether `e` is a socket timeout. Depending on the platform an expired
+/// read or write timeout surfaces as `WouldBlock` or `TimedOut`.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Classifies a read failure that ended a connection.
+fn read_close_reason(e: &io::Error) -> CloseReason {
+    if framing::is_too_large(e) {
+        return CloseReason::Oversized;
+    }
+    match e.kind() {
+        ErrorKind::InvalidData => CloseReason::Malformed,
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => CloseReason::Timeout,
+        ErrorKind::UnexpectedEof
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe => CloseReason::ConnectionLost,
+        _ => CloseReason::Error,
+    }
+}
+
+/// Enables TCP keepalive on `stream`: after `idle` without traffic the
+/// kernel probes the peer every `interval` and resets the connection if it
+/// stops answering, so reads on a dead peer fail instead of waiting for the
+/// read timeout. Both times are rounded up to whole seconds.
+#[cfg(unix)]
+fn set_keepalive(stream: &TcpStream, idle: Duration, interval: Duration) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    const KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+    let seconds = |d: Duration| (d.as_secs() + u64::from(d.subsec_nanos() > 0)).max(1);
+    let fd = stream.as_raw_fd();
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    setsockopt(fd, libc::IPPROTO_TCP, KEEPIDLE, seconds(idle) as libc::c_int)?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, seconds(interval) as libc::c_int)
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration, _interval: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "TCP keepalive tuning is only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `fd` is an open socket and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Ports below this need elevated privileges to bind on most Unix systems.
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Replaces the bare `PermissionDenied` from binding a privileged port with
+/// a message saying what to do about it. Other errors pass through.
+fn describe_bind_error(addr: &str, port: u16, e: io::Error) -> io::Error {
+    match e.kind() {
+        ErrorKind::PermissionDenied if port < FIRST_UNPRIVILEGED_PORT => io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Permission denied binding {}: ports below {} are privileged. \
+                 Run with the needed capability (e.g. CAP_NET_BIND_SERVICE) \
+                 or choose a port of {} or above",
+                addr, FIRST_UNPRIVILEGED_PORT, FIRST_UNPRIVILEGED_PORT
+            ),
+        ),
+        _ => e,
+    }
+}
+
+/// Resolves a listen address given as `host:port`, `[ipv6]:port` or
+/// `:port`, the last meaning every IPv4 interface. A missing or malformed
+/// port or a host that does not resolve fails with `InvalidInput` naming
+/// the part at fault, rather than with whatever the resolver reports.
+fn resolve_listen_addr(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidInput, message);
+    let (host, port) = addr.rsplit_once(':').ok_or_else(|| {
+        invalid(format!(
+            "listen address `{}` has no port; expected host:port or :port",
+            addr
+        ))
+    })?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid(format!("invalid port `{}` in listen address `{}`", port, addr)))?;
+    let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ipv6) => ipv6,
+        None if host.is_empty() => "0.0.0.0",
+        None => host,
+    };
+    let resolved: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| {
+            invalid(format!(
+                "cannot resolve host `{}` in listen address `{}`: {}",
+                host, addr, e
+            ))
+        })?
+        .collect();
+    if resolved.is_empty() {
+        return Err(invalid(format!(
+            "host `{}` in listen address `{}` has no addresses",
+            host, addr
+        )));
+    }
+    Ok(resolved)
+}
+
+fn upgrade_ack() -> ServerMessage {
+    ServerMessage {
+        message: Some(ServerMessageEnum::UpgradeAck(UpgradeAck {})),
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.state.topics.remove_connection(self.id);
+        self.state.connections.unregister(self.id);
+        // A connection dropped without a recorded reason was either being
+        // served by a handler that panicked or abandoned by a pool that is
+        // shutting down.
+        let reason = self.close_reason.unwrap_or(if thread::panicking() {
+            CloseReason::Error
+        } else {
+            CloseReason::Shutdown
+        });
+        self.state.metrics.record_close(reason);
+        if reason.is_normal() {
+            info!("Client {} ({}) disconnected: {}", self.id, self.addr, reason);
+        } else {
+            warn!("Client {} ({}) disconnected: {}", self.id, self.addr, reason);
+        }
+    }
+}
+
+/// Serves one turn of a connection: requests are handled until the client
+/// disconnects or `max_messages_per_turn` is reached, in which case the
+/// connection is requeued behind whatever else is waiting for a worker.
+/// Once `max_requests_per_connection` requests have been handled the
+/// connection is closed instead.
+///
+/// A request carrying a non-zero priority is not handled inline; the rest of
+/// the turn is queued at that priority instead, so it competes with other
+/// waiting work. Either way only one turn per connection is ever queued or
+/// running, so requests are still answered in order.
+///
+/// With a queue wait budget, each request that waited longer than the
+/// budget for a worker is answered with `Unavailable` instead of being
+/// served late; see `ArrivalLog` for how its wait is known.
+fn serve_turn(mut client: Client, pool: PoolHandle, is_running: Arc<AtomicBool>) {
+    let limit = client.state.config.max_messages_per_turn;
+    let budget = client.state.config.queue_wait_budget;
+    let mut handled = 0;
+    client.arrivals.waiting.store(false, Ordering::SeqCst);
+
+    while is_running.load(Ordering::SeqCst) {
+        if client.request_limit_reached() {
+            info!("Client {} reached its request limit; closing", client.id);
+            client.close_reason = Some(CloseReason::RequestLimit);
+            break;
+        }
+        if limit != 0 && handled == limit {
+            let next_pool = pool.clone();
+            let id = client.id;
+            client.park();
+            if !pool.execute_for(id, 0, move || serve_turn(client, next_pool, is_running)) {
+                warn!("Thread pool is shut down; dropping client {}", id);
+            }
+            return;
+        }
+
+        let request = match client.read_request() {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                client.read_ended(Ok(()));
+                break;
+            }
+            Err(e) => {
+                client.read_ended(Err(e));
+                break;
+            }
+        };
+
+        let waited = client.queue_wait().filter(|&waited| budget.is_some_and(|b| waited > b));
+        if let Some(waited) = waited {
+            match client.shed(&request, waited) {
+                Ok(()) => handled += 1,
+                Err(e) => {
+                    client.report_write_error(&e);
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if matches!(request.message, Some(ClientMessageEnum::Upgrade(_))) {
+            let id = client.id;
+            if let Err(e) = client.upgrade() {
+                error!("Failed to upgrade client {}: {}", id, e);
+            }
+            return;
+        }
+
+        let kind = request.message.as_ref().map(MessageKind::of);
+        if let Some(route) = kind.and_then(|kind| client.state.routes.get(&kind)).cloned() {
+            let priority = request.priority.min(u8::MAX as u32) as u8;
+            let next_pool = pool.clone();
+            let id = client.id;
+            client.park();
+            let scheduled = route.execute_for(id, priority, move || {
+                serve_routed(client, request, next_pool, is_running)
+            });
+            if !scheduled {
+                warn!("Dedicated pool is shut down; dropping client {}", id);
+            }
+            return;
+        }
+
+        if request.priority > 0 {
+            let priority = request.priority.min(u8::MAX as u32) as u8;
+            let next_pool = pool.clone();
+            let id = client.id;
+            client.park();
+            let scheduled = pool.execute_for(id, priority, move || {
+                resume_turn(client, request, next_pool, is_running)
+            });
+            if !scheduled {
+                warn!("Thread pool is shut down; dropping client {}", id);
+            }
+            return;
+        }
+
+        let result = client
+            .signal_backpressure(pool.depth())
+            .and_then(|()| client.respond(request));
+        match result {
+            Ok(()) => handled += 1,
+            Err(e) => {
+                client.report_write_error(&e);
+                break;
+            }
+        }
+    }
+}
+
+/// Answers a request that waited in the queue, then carries on serving its
+/// connection.
+fn resume_turn(
+    mut client: Client,
+    request: ClientMessage,
+    pool: PoolHandle,
+    is_running: Arc<AtomicBool>,
+) {
+    match answer_queued(&mut client, request, &pool) {
+        Ok(()) => serve_turn(client, pool, is_running),
+        Err(e) => client.report_write_error(&e),
+    }
+}
+
+/// Answers a request on the dedicated pool its kind is routed to, then
+/// hands the connection back to the default pool.
+fn serve_routed(
+    mut client: Client,
+    request: ClientMessage,
+    pool: PoolHandle,
+    is_running: Arc<AtomicBool>,
+) {
+    if let Err(e) = answer_queued(&mut client, request, &pool) {
+        client.report_write_error(&e);
+        return;
+    }
+    let next_pool = pool.clone();
+    let id = client.id;
+    client.park();
+    if !pool.execute_for(id, 0, move || serve_turn(client, next_pool, is_running)) {
+        warn!("Thread pool is shut down; dropping client {}", id);
+    }
+}
+
+/// Responds to a request that waited in a queue, or sheds it if it waited
+/// past the queue wait budget.
+fn answer_queued(
+    client: &mut Client,
+    request: ClientMessage,
+    pool: &PoolHandle,
+) -> io::Result<()> {
+    let waited = JOB_QUEUE_WAIT.get();
+    let stale = client
+        .state
+        .config
+        .queue_wait_budget
+        .is_some_and(|budget| waited > budget);
+    if stale {
+        client.shed(&request, waited)
+    } else {
+        client.signal_backpressure(pool.depth())?;
+        client.respond(request)
+    }
+}
+
+pub struct Server {
+    /// One per bound address, in `listen_addrs` order. An entry is `None`
+    /// once its descriptor was found closed externally and could not be
+    /// replaced.
+    listeners: Mutex<Vec<Option<Listener>>>,
+    listen_addrs: Vec<ListenAddr>,
+    /// The TCP addresses among `listen_addrs`.
+    local_addrs: Vec<SocketAddr>,
+    is_running: Arc<AtomicBool>,
+    thread_pool: ThreadPool,
+    /// Pools added with `with_dedicated_pool`.
+    dedicated_pools: Vec<ThreadPool>,
+    state: Arc<ServerState>,
+}
+
+/// Collects the core server settings, checks them and binds the server.
+/// Every setting but the address has a default:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use task::server::ServerBuilder;
+/// let server = ServerBuilder::new()
+///     .addr("127.0.0.1:8080")
+///     .thread_pool_size(16)
+///     .read_timeout(Duration::from_secs(10))
+///     .max_connections(1000)
+///     .build()?;
+/// # Ok::<(), task::error::ProtocolError>(())
+/// ```
+///
+/// The resulting `Server` takes the remaining `with_*` options as usual.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    addrs: Vec<String>,
+    thread_pool_size: usize,
+    read_timeout: Option<Duration>,
+    max_message_size: usize,
+    max_connections: Option<usize>,
+    max_requests_per_connection: usize,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            addrs: Vec::new(),
+            thread_pool_size: THREAD_POOL_SIZE,
+            read_timeout: Some(READ_TIMEOUT),
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_connections: None,
+            max_requests_per_connection: 0,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder::default()
+    }
+
+    /// Adds an address to listen on; call again to listen on several. Takes
+    /// `host:port`, `[ipv6]:port`, or `:port` for every IPv4 interface.
+    pub fn addr(mut self, addr: &str) -> Self {
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    /// Workers serving connections. Defaults to 4.
+    pub fn thread_pool_size(mut self, size: usize) -> Self {
+        self.thread_pool_size = size;
+        self
+    }
+
+    /// Idle time after which a connection is closed. Defaults to 30
+    /// seconds; see `no_read_timeout` to keep idle connections open.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Never closes idle connections.
+    pub fn no_read_timeout(mut self) -> Self {
+        self.read_timeout = None;
+        self
+    }
+
+    /// Largest request payload accepted, in bytes. Defaults to 1 MiB.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Most connections open at once. Unlimited by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Requests served on one connection before the server closes it, so
+    /// clients reconnect and connections stay short-lived. Unlimited by
+    /// default.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.max_requests_per_connection = max;
+        self
+    }
+
+    /// Checks the settings and binds every address. Invalid settings fail
+    /// with an `InvalidInput` `ProtocolError::Io` naming the setting, before
+    /// anything is bound.
+    pub fn build(self) -> Result<Server, ProtocolError> {
+        self.validate()?;
+        let addrs = self
+            .addrs
+            .iter()
+            .map(|addr| Ok((addr.as_str(), resolve_listen_addr(addr)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut server = Server::bind(&addrs, self.thread_pool_size)?;
+        let config = &mut server.state_mut().config;
+        config.read_timeout = self.read_timeout;
+        config.max_message_size = self.max_message_size;
+        config.max_connections = self.max_connections;
+        config.max_requests_per_connection = self.max_requests_per_connection;
+        Ok(server)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.addrs.is_empty() {
+            return Err(invalid_setting("no listen address given".to_string()));
+        }
+        check_thread_pool_size(self.thread_pool_size)?;
+        check_read_timeout(self.read_timeout)?;
+        check_max_message_size(self.max_message_size)?;
+        check_max_connections(self.max_connections)
+    }
+}
+
+// Setting checks shared by `ServerBuilder` and the matching `Server::with_*`
+// setters, so neither path accepts what the other rejects.
+
+fn invalid_setting(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn check_thread_pool_size(size: usize) -> io::Result<()> {
+    if size == 0 {
+        return Err(invalid_setting("thread pool size must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+fn check_read_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(invalid_setting("read timeout must be non-zero".to_string()));
+    }
+    Ok(())
+}
+
+fn check_max_message_size(bytes: usize) -> io::Result<()> {
+    // The length prefix's top bit flags compressed frames.
+    let largest_frame = (framing::COMPRESSED_FRAME_FLAG - 1) as usize;
+    if bytes == 0 || bytes > largest_frame {
+        return Err(invalid_setting(format!(
+            "max message size must be between 1 and {} bytes, got {}",
+            largest_frame, bytes
+        )));
+    }
+    Ok(())
+}
+
+fn check_max_connections(max: Option<usize>) -> io::Result<()> {
+    if max == Some(0) {
+        return Err(invalid_setting("max connections must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+impl Server {
+    /// Binds `addr` with the default settings; see `ServerBuilder` to change
+    /// them up front.
+    pub fn new(addr: &str) -> Result<Self, ProtocolError> {
+        ServerBuilder::new().addr(addr).build()
+    }
+
+    /// Like `new`, but listens on every address in `addrs` at once, e.g. an
+    /// IPv4 and an IPv6 address. Connections from all of them are served
+    /// alike. Fails if `addrs` is empty or any address cannot be bound.
+    pub fn bind_all(addrs: &[&str]) -> Result<Self, ProtocolError> {
+        addrs
+            .iter()
+            .fold(ServerBuilder::new(), |builder, addr| builder.addr(addr))
+            .build()
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Listens on a Unix domain socket at `path` instead of a TCP address,
+    /// with the default settings. Requests are framed and served exactly as
+    /// over TCP; TCP-only options such as keepalive do not apply. A socket
+    /// file left at `path` by a server that is gone is replaced, and the
+    /// file is removed when the server is dropped.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<std::path::Path>) -> Result<Self, ProtocolError> {
+        let listener = ListenAddr::Unix(path.as_ref().to_path_buf()).bind()?;
+        Ok(Server::from_listeners(vec![listener], THREAD_POOL_SIZE)?)
+    }
+
+    /// Binds each address to the first of its resolved socket addresses that
+    /// can be bound.
+    fn bind(addrs: &[(&str, Vec<SocketAddr>)], thread_pool_size: usize) -> io::Result<Self> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for (addr, resolved) in addrs {
+            let listener = TcpListener::bind(&resolved[..])
+                .map_err(|e| describe_bind_error(addr, resolved[0].port(), e))?;
+            listener.set_nonblocking(true)?;
+            listeners.push(Listener::Tcp(listener));
+        }
+        Server::from_listeners(listeners, thread_pool_size)
+    }
+
+    fn from_listeners(listeners: Vec<Listener>, thread_pool_size: usize) -> io::Result<Self> {
+        let listen_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+        let local_addrs = listen_addrs
+            .iter()
+            .filter_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(*addr),
+                #[cfg(unix)]
+                ListenAddr::Unix(_) => None,
+            })
+            .collect();
+        let metrics = Arc::new(Metrics::default());
+
+        Ok(Server {
+            listeners: Mutex::new(listeners.into_iter().map(Some).collect()),
+            listen_addrs,
+            local_addrs,
+            is_running: Arc::new(AtomicBool::new(false)),
+            thread_pool: ThreadPool::new(thread_pool_size, Arc::clone(&metrics)),
+            dedicated_pools: Vec::new(),
+            state: Arc::new(ServerState {
+                config: ServerConfig {
+                    read_timeout: Some(READ_TIMEOUT),
+                    write_timeout: Some(WRITE_TIMEOUT),
+                    max_message_size: MAX_MESSAGE_SIZE,
+                    max_stream_echo_count: MAX_STREAM_ECHO_COUNT,
+                    ..ServerConfig::default()
+                },
+                metrics,
+                handlers: Arc::new(HandlerRegistry::builtin()),
+                connections: ConnectionRegistry::default(),
+                topics: TopicRegistry::default(),
+                coalescer: RequestCoalescer::default(),
+                idempotency: IdempotencyCache::default(),
+                routes: HashMap::new(),
+                started: Instant::now(),
+            }),
+        })
+    }
+
+    /// Replaces the built-in echo/add handlers. Requests whose kind has no
+    /// registered handler are answered with an unsupported-operation error.
+    pub fn with_handlers(self, handlers: HandlerRegistry) -> Self {
+        self.with_message_handler(Arc::new(handlers))
+    }
+
+    /// Serves application requests with `handler` instead of a
+    /// `HandlerRegistry`; see `MessageHandler` for which requests reach it.
+    pub fn with_message_handler(mut self, handler: Arc<dyn MessageHandler + Send + Sync>) -> Self {
+        self.state_mut().handlers = handler;
+        self
+    }
+
+    /// Sets how long a connection may wait for its next request before the
+    /// server closes it; `None` lets idle connections stay open. Defaults to
+    /// 30 seconds. Fails with an `InvalidInput` `ProtocolError::Io` for a
+    /// zero timeout, as `ServerBuilder::read_timeout` does.
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Result<Self, ProtocolError> {
+        check_read_timeout(timeout)?;
+        self.state_mut().config.read_timeout = timeout;
+        Ok(self)
+    }
+
+    /// Sets how long a single write may block on a client that has stopped
+    /// reading before the server closes the connection, freeing the worker
+    /// serving it; `None` waits indefinitely. Defaults to 30 seconds.
+    pub fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.state_mut().config.write_timeout = timeout;
+        self
+    }
+
+    /// Sets the largest request payload, in bytes, the server accepts. A
+    /// connection announcing a larger frame is closed (or reset, with
+    /// `with_reset_on_malformed`). Reported to clients through
+    /// `LimitsResponse`. Defaults to 1 MiB. Fails with an `InvalidInput`
+    /// `ProtocolError::Io` for sizes `ServerBuilder` rejects.
+    pub fn with_max_message_size(mut self, bytes: usize) -> Result<Self, ProtocolError> {
+        check_max_message_size(bytes)?;
+        self.state_mut().config.max_message_size = bytes;
+        Ok(self)
+    }
+
+    /// Serves requests of the given kinds on their own pool of `workers`
+    /// threads, named `<name>-<n>`, so a slow kind of request cannot tie up
+    /// the default pool that serves everything else. A connection moves to
+    /// the dedicated pool for one such request and then returns to the
+    /// default pool; it is still served one request at a time. Queue options
+    /// such as pinning and aging apply to the default pool only. A kind
+    /// routed twice goes to the pool added last.
+    pub fn with_dedicated_pool(
+        mut self,
+        name: &str,
+        workers: usize,
+        kinds: &[MessageKind],
+    ) -> Self {
+        let pool = ThreadPool::named(name, workers.max(1), Arc::clone(&self.state.metrics));
+        let handle = pool.handle();
+        let routes = &mut self.state_mut().routes;
+        for &kind in kinds {
+            routes.insert(kind, handle.clone());
+        }
+        self.dedicated_pools.push(pool);
+        self
+    }
+
+    /// Asks clients to slow down while the server is backed up: once
+    /// `queue_depth` or more jobs are waiting for a worker, responses are
+    /// preceded by a `Throttle` telling the client to hold further requests
+    /// for `retry_after`. Each connection is throttled at most once per
+    /// `retry_after`. Requests are still served. Disabled by default.
+    pub fn with_backpressure(mut self, queue_depth: usize, retry_after: Duration) -> Self {
+        self.state_mut().config.backpressure = Some((queue_depth.max(1), retry_after));
+        self
+    }
+
+    /// Sets the most echoes a single `StreamEchoRequest` may ask for; larger
+    /// counts are rejected with `InvalidArgument`. Defaults to 1000.
+    pub fn with_max_stream_echo_count(mut self, count: u32) -> Self {
+        self.state_mut().config.max_stream_echo_count = count;
+        self
+    }
+
+    /// Caps how many requests one connection is served before its worker is
+    /// handed to the next queued connection, so a busy client cannot starve
+    /// the others. Zero (the default) means unlimited.
+    pub fn with_max_messages_per_turn(mut self, limit: usize) -> Self {
+        self.state_mut().config.max_messages_per_turn = limit;
+        self
+    }
+
+    /// Closes each connection once it has been served `max` requests, with
+    /// close reason `RequestLimit`. Requests rejected by the rate limit do
+    /// not count. Zero (the default) means unlimited.
+    pub fn with_max_requests_per_connection(mut self, max: usize) -> Self {
+        self.state_mut().config.max_requests_per_connection = max;
+        self
+    }
+
+    /// Limits each connection to `requests_per_second` requests per second
+    /// on average, allowing bursts of as many. Requests over the limit are
+    /// answered with a `ResourceExhausted` "rate limited" error instead of
+    /// being handled, and counted in `rate_limited_requests`. Zero (the
+    /// default) means unlimited.
+    pub fn with_rate_limit(mut self, requests_per_second: u32) -> Self {
+        self.state_mut().config.max_requests_per_second = requests_per_second;
+        self
+    }
+
+    /// Expects every inbound frame to start with `FRAME_SYNC_MARKER` and adds
+    /// it to outbound frames. A connection whose marker does not match is
+    /// closed instead of reading misaligned frames. Clients must enable the
+    /// same option.
+    pub fn with_frame_sync(mut self, enabled: bool) -> Self {
+        self.state_mut().config.frame_sync = enabled;
+        self
+    }
+
+    /// Selects the wire encoding: length-prefixed protobuf (the default) or
+    /// newline-delimited JSON, for tooling without protobuf support. See
+    /// `codec` for the JSON form of each message. Clients must use the same
+    /// codec; frame sync and `with_reset_on_malformed` only apply to
+    /// protobuf.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.state_mut().config.codec = codec;
+        self
+    }
+
+    /// Enables TCP keepalive on accepted connections, so a peer that
+    /// vanished without closing (e.g. behind an expired NAT mapping) is
+    /// detected after `idle` plus a few `interval` probes rather than only
+    /// when the read timeout fires. Off by default. Unix only: elsewhere
+    /// every accepted connection fails to set up.
+    pub fn with_tcp_keepalive(mut self, idle: Duration, interval: Duration) -> Self {
+        self.state_mut().config.keepalive = Some((idle, interval));
+        self
+    }
+
+    /// Caps the number of connections open at once. A connection accepted
+    /// at the cap is sent a `ResourceExhausted` error and closed, and counted
+    /// in `rejected_connections`. Fails with an `InvalidInput`
+    /// `ProtocolError::Io` if `max` is zero.
+    pub fn with_max_connections(mut self, max: usize) -> Result<Self, ProtocolError> {
+        check_max_connections(Some(max))?;
+        self.state_mut().config.max_connections = Some(max);
+        Ok(self)
+    }
+
+    /// Accepts at most `connections` new connections per `interval`, leaving
+    /// the rest queued in the listener backlog so a burst of connects is
+    /// ramped onto the pool instead of arriving all at once.
+    pub fn with_accept_rate(mut self, connections: usize, interval: Duration) -> Self {
+        self.state_mut().config.accept_rate = Some((connections, interval));
+        self
+    }
+
+    /// Answers undecodable or misaligned frames with a RESET control frame
+    /// instead of an error response or closing the connection respectively.
+    /// After a misaligned frame the server
+    /// discards input until the client echoes the RESET, so only clients
+    /// that understand RESET (such as `client::Client`) should be served
+    /// with this enabled.
+    pub fn with_reset_on_malformed(mut self, enabled: bool) -> Self {
+        self.state_mut().config.reset_on_malformed = enabled;
+        self
+    }
+
+    /// Logs a warning naming the message kind, and bumps `slow_requests`,
+    /// for every request whose handling takes longer than `threshold`.
+    /// Disabled by default.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.state_mut().config.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets how many workers serve connections; defaults to 4. Options
+    /// already applied to the pool's queue, such as pinning and aging, are
+    /// kept. Fails with an `InvalidInput` `ProtocolError::Io` if `size` is
+    /// zero.
+    pub fn with_thread_pool_size(mut self, size: usize) -> Result<Self, ProtocolError> {
+        check_thread_pool_size(size)?;
+        self.thread_pool = self.thread_pool.resized(size);
+        Ok(self)
+    }
+
+    /// Debug mode: every job serving a connection runs on the same worker,
+    /// chosen from the connection id, instead of on whichever worker is
+    /// free. Worker threads are named `worker-<n>`, so a bug that only shows
+    /// up on one worker can be reproduced and traced. Costs throughput, as a
+    /// busy worker's connections wait even while others are idle.
+    pub fn with_pinned_workers(self, enabled: bool) -> Self {
+        if enabled {
+            self.thread_pool.queue.set_pinned(self.thread_pool.workers.len());
+        }
+        self
+    }
+
+    /// Sheds load once requests wait too long for a worker: a request that
+    /// spent more than `budget` queued is answered with an `Unavailable`
+    /// "overloaded" error instead of being handled, and counted in `shed_requests`.
+    /// Disabled by default.
+    pub fn with_queue_wait_budget(mut self, budget: Duration) -> Self {
+        self.state_mut().config.queue_wait_budget = Some(budget);
+        self
+    }
+
+    /// Lets identical requests that arrive while one of them is being
+    /// handled share that handler run and its response, instead of each
+    /// running the handler. Only safe with handlers whose result depends on
+    /// the request alone. Disabled by default.
+    pub fn with_request_coalescing(mut self, enabled: bool) -> Self {
+        self.state_mut().config.coalesce_requests = enabled;
+        self
+    }
+
+    /// Audits the server's resource usage every `interval` while `run` is
+    /// accepting: open connections, queue depth and topic subscriptions are
+    /// logged and exposed through `ServerMetrics::last_audit`, so leaks show
+    /// up as numbers that keep growing. Does not change behaviour. Disabled
+    /// by default.
+    pub fn with_resource_audit(mut self, interval: Duration) -> Self {
+        self.state_mut().config.audit_interval = Some(interval);
+        self
+    }
+
+    /// Binds a fresh listener on the same address if the listening socket
+    /// is closed from elsewhere in the process, instead of `run` returning
+    /// an error. Disabled by default.
+    pub fn with_listener_rebind(mut self, enabled: bool) -> Self {
+        self.state_mut().config.rebind_listener = enabled;
+        self
+    }
+
+    /// Sets how long a queued request must wait to gain one priority level.
+    /// Aging bounds how long sustained high-priority traffic can hold back
+    /// lower-priority requests: a request at priority `p` only overtakes
+    /// requests queued less than `p` intervals before it. Zero disables
+    /// aging. Defaults to 100ms.
+    pub fn with_priority_aging(self, interval: Duration) -> Self {
+        self.thread_pool.queue.set_aging(interval);
+        self
+    }
+
+    /// Sets the job queue depths at which an overload warning is logged (and
+    /// `queue_depth_warnings` bumped); recovering below a threshold is logged
+    /// at info level. Defaults to 100 and 1000.
+    pub fn with_queue_depth_thresholds(self, thresholds: &[usize]) -> Self {
+        self.thread_pool.queue.set_thresholds(thresholds);
+        self
+    }
+
+    /// The address the server is listening on, including the port the OS
+    /// picked when bound to port 0. Available before and after `run`. With
+    /// `bind_all`, the first of `local_addrs`. Fails with an `Unsupported`
+    /// `ProtocolError::Io` for a server listening on a Unix socket.
+    pub fn local_addr(&self) -> Result<SocketAddr, ProtocolError> {
+        self.local_addrs.first().copied().ok_or_else(|| {
+            io::Error::new(ErrorKind::Unsupported, "server is not listening on TCP").into()
+        })
+    }
+
+    /// Every address the server is listening on, in the order given to
+    /// `bind_all`.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Whether `run` is accepting connections: false before it is called
+    /// and once `stop` or a listener failure has made it return.
+    pub fn is_running(&self) -> bool {
+        self.is_running.load(Ordering::SeqCst)
+    }
+
+    /// Configuration happens before `run`, while nothing else holds the state.
+    fn state_mut(&mut self) -> &mut ServerState {
+        Arc::get_mut(&mut self.state).expect("server state is only shared once running")
+    }
+
+    pub fn run(&self) -> Result<(), ProtocolError> {
+        self.run_with_ready(|| {})
+    }
+
+    /// Like `run`, but calls `ready` once, as soon as the server is running
+    /// and about to accept. Listeners are bound when the server is created,
+    /// so connections made from then on are served; `ready` is the point
+    /// to start clients instead of sleeping. It runs on the calling thread
+    /// and delays accepting until it returns.
+    pub fn run_with_ready(&self, ready: impl FnOnce()) -> Result<(), ProtocolError> {
+        self.is_running.store(true, Ordering::SeqCst);
+        let addrs: Vec<String> = self.listen_addrs.iter().map(ToString::to_string).collect();
+        info!("Server running on {}", addrs.join(", "));
+        ready();
+
+        let mut limiter = self
+            .state
+            .config
+            .accept_rate
+            .map(|(limit, interval)| AcceptLimiter::new(limit, interval));
+        let mut last_audit = Instant::now();
+        let mut next_listener = 0;
+
+        while self.is_running.load(Ordering::SeqCst) {
+            if self.state.config.queue_wait_budget.is_some() {
+                self.state.connections.sample_arrivals();
+            }
+            if let Some(interval) = self.state.config.audit_interval {
+                if last_audit.elapsed() >= interval {
+                    self.audit();
+                    last_audit = Instant::now();
+                }
+            }
+            if let Some(limiter) = limiter.as_mut() {
+                limiter.wait();
+            }
+            match self.accept(&mut next_listener) {
+                Ok((stream, addr)) => {
+                    if let Some(limiter) = limiter.as_mut() {
+                        limiter.record();
+                    }
+                    if self.at_connection_limit() {
+                        self.reject(stream, addr);
+                        continue;
+                    }
+                    let client = match Client::new(stream, addr, Arc::clone(&self.state)) {
+                        Ok(client) => client,
+                        Err(e) => {
+                            error!("Failed to set up client {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+                    let is_running = Arc::clone(&self.is_running);
+                    let pool = self.thread_pool.handle();
+                    client.park();
+
+                    // A connection is owned by exactly one job, which reads,
+                    // handles and answers its requests one at a time. That is
+                    // what keeps responses in request order; any finer-grained
+                    // scheduling must still never run two requests from the
+                    // same connection concurrently.
+                    self.thread_pool
+                        .execute_for(client.id, move || serve_turn(client, pool, is_running));
+                }
+                Err((_, ref e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.wait_for_connection(ACCEPT_WAIT_INTERVAL);
+                }
+                // The peer gave up before the connection was accepted.
+                Err((_, ref e)) if e.kind() == ErrorKind::ConnectionAborted => {}
+                Err((_, ref e)) if e.kind() == ErrorKind::Interrupted => {}
+                Err((_, ref e)) if matches!(e.raw_os_error(), Some(EMFILE | ENFILE)) => {
+                    warn!("Out of file descriptors accepting a connection ({}); retrying", e);
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err((index, e)) => {
+                    let e = match self.recover_listener(index, e) {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    };
+                    let addr = &self.listen_addrs[index];
+                    error!("Listener on {} failed: {}; shutting down", addr, e);
+                    self.is_running.store(false, Ordering::SeqCst);
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("listener on {} failed: {}", addr, e),
+                    )
+                    .into());
+                }
+            }
+        }
+
+        info!("Server stopped");
+        Ok(())
+    }
+
+    fn at_connection_limit(&self) -> bool {
+        self.state
+            .config
+            .max_connections
+            .is_some_and(|max| self.state.connections.len() >= max)
+    }
+
+    /// Turns away a connection accepted at the connection limit. The error
+    /// is best effort: the connection is closed whether or not it is sent.
+    fn reject(&self, mut stream: Stream, addr: PeerAddr) {
+        warn!(
+            "Rejecting client {}: {} connection(s) already open",
+            addr,
+            self.state.connections.len()
+        );
+        Metrics::increment(&self.state.metrics.rejected_connections);
+        let response = handler::error_response(
+            StatusCode::ResourceExhausted,
+            "Too many open connections",
+        );
+        let sent = stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT)))
+            .and_then(|()| self.state.config.write_frame(&mut stream, &response.encode_to_vec()));
+        if let Err(e) = sent {
+            info!("Failed to send rejection to {}: {}", addr, e);
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    /// Accepts a connection from the first listener with one waiting,
+    /// starting at `next` and moving it past that listener so busy addresses
+    /// take turns. Fails with `WouldBlock` if no listener has a connection
+    /// waiting; any other failure comes with the index of the listener that
+    /// reported it.
+    fn accept(&self, next: &mut usize) -> Result<(Stream, PeerAddr), (usize, io::Error)> {
+        let listeners = self.listeners.lock().unwrap();
+        for offset in 0..listeners.len() {
+            let index = (*next + offset) % listeners.len();
+            let result = match &listeners[index] {
+                Some(listener) => listener.accept(),
+                None => Err(io::Error::new(ErrorKind::NotConnected, "listener is closed")),
+            };
+            match result {
+                Ok(accepted) => {
+                    *next = (index + 1) % listeners.len();
+                    return Ok(accepted);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err((index, e)),
+            }
+        }
+        Err((*next, io::Error::from(ErrorKind::WouldBlock)))
+    }
+
+    /// Waits up to `timeout` for a listener to have a connection to accept,
+    /// returning as soon as one does. `stop` connects to every listener, so
+    /// it wakes this too.
+    #[cfg(unix)]
+    fn wait_for_connection(&self, timeout: Duration) {
+        use std::os::fd::AsRawFd;
+        let mut fds: Vec<libc::pollfd> = self
+            .listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|listener| libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = timeout.as_millis() as libc::c_int;
+        // Errors, including a closed descriptor, surface from the next
+        // `accept` instead.
+        // SAFETY: `fds` is valid for `fds.len()` entries.
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    }
+
+    #[cfg(not(unix))]
+    fn wait_for_connection(&self, timeout: Duration) {
+        thread::sleep(timeout);
+    }
+
+    /// Handles a failed `accept` on listener `index`. A listener whose
+    /// descriptor was closed from elsewhere is given up without closing it
+    /// again, since the descriptor number may already belong to something
+    /// else, and replaced if `with_listener_rebind` is set. Other failures
+    /// are returned.
+    fn recover_listener(&self, index: usize, e: io::Error) -> io::Result<()> {
+        if e.raw_os_error() != Some(EBADF) {
+            return Err(e);
+        }
+        let addr = &self.listen_addrs[index];
+        let mut listeners = self.listeners.lock().unwrap();
+        if let Some(listener) = listeners[index].take() {
+            listener.abandon();
+        }
+        warn!("Listener on {} was closed externally", addr);
+        if !self.state.config.rebind_listener {
+            return Err(e);
+        }
+
+        listeners[index] = Some(addr.bind()?);
+        info!("Rebound listener on {}", addr);
+        Ok(())
+    }
+
+    /// Records and logs the server's current resource usage.
+    fn audit(&self) {
+        let (topics, subscriptions) = self.state.topics.counts();
+        let audit = ResourceAudit {
+            open_connections: self.state.connections.len(),
+            queue_depth: self.thread_pool.queue.depth(),
+            topics,
+            subscriptions,
+        };
+        info!(
+            "Resource audit: {} open connection(s), {} queued job(s), {} topic(s) with {} subscription(s)",
+            audit.open_connections, audit.queue_depth, audit.topics, audit.subscriptions
+        );
+        let metrics = &self.state.metrics;
+        *metrics.last_audit.lock().unwrap() = Some(audit);
+        Metrics::increment(&metrics.audits);
+    }
+
+    pub fn metrics(&self) -> ServerMetrics {
+        let metrics = &self.state.metrics;
+        ServerMetrics {
+            queue_depth: self.thread_pool.queue.depth(),
+            queue_depth_warnings: metrics.queue_depth_warnings.load(Ordering::Relaxed),
+            slow_requests: metrics.slow_requests.load(Ordering::Relaxed),
+            shed_requests: metrics.shed_requests.load(Ordering::Relaxed),
+            rate_limited_requests: metrics.rate_limited_requests.load(Ordering::Relaxed),
+            write_disconnects: metrics.write_disconnects.load(Ordering::Relaxed),
+            throttles_sent: metrics.throttles_sent.load(Ordering::Relaxed),
+            rejected_connections: metrics.rejected_connections.load(Ordering::Relaxed),
+            closes: metrics.closes_snapshot(),
+            requests: metrics.requests_snapshot(),
+            inbound_sizes: metrics.inbound_sizes.snapshot(),
+            outbound_sizes: metrics.outbound_sizes.snapshot(),
+            bytes_read: metrics.bytes_read.load(Ordering::Relaxed),
+            bytes_written: metrics.bytes_written.load(Ordering::Relaxed),
+            audits: metrics.audits.load(Ordering::Relaxed),
+            last_audit: *metrics.last_audit.lock().unwrap(),
+        }
+    }
+
+    /// Pushes `content` to every connection subscribed to `topic` and returns
+    /// how many subscribers it was delivered to. Clients can do the same
+    /// with a `PublishRequest`.
+    pub fn publish(&self, topic: &str, content: &str) -> usize {
+        self.state.publish(topic, content)
+    }
+
+    pub fn stop(&self) {
+        if self.stop_accepting() {
+            // Unblock handlers waiting on idle clients; their peers see EOF.
+            self.state.connections.shutdown_all();
+            info!("Shutdown signal sent");
+        }
+    }
+
+    /// Like `stop`, but lets requests already being handled finish: new
+    /// connections are no longer accepted and idle connections are closed,
+    /// while a connection in the middle of a request gets up to `timeout` to
+    /// write its response. Connections still busy after that are closed as
+    /// `stop` would, with a warning.
+    pub fn shutdown_graceful(&self, timeout: Duration) {
+        if !self.stop_accepting() {
+            return;
+        }
+        self.state.connections.shutdown_reads();
+
+        let deadline = Instant::now() + timeout;
+        while self.state.connections.len() > 0 && Instant::now() < deadline {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        let remaining = self.state.connections.len();
+        if remaining > 0 {
+            warn!(
+                "{} connection(s) still busy after {:?}; closing them",
+                remaining, timeout
+            );
+        }
+        self.state.connections.shutdown_all();
+        info!("Graceful shutdown complete");
+    }
+
+    /// Makes `run` return. Returns false if the server was not running.
+    fn stop_accepting(&self) -> bool {
+        if !self.is_running.swap(false, Ordering::SeqCst) {
+            warn!("Server already stopped or not running");
+            return false;
+        }
+        // Connect to self to unblock accept
+        for addr in &self.listen_addrs {
+            addr.poke();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_pool_drop_is_bounded_with_stuck_and_panicked_workers() {
+        let mut pool = ThreadPool::new(2, Arc::default());
+        pool.join_timeout = Duration::from_millis(200);
+
+        pool.execute(|| thread::sleep(Duration::from_secs(5)));
+        pool.execute(|| panic!("simulated handler failure"));
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        drop(pool);
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "Pool drop took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = ThreadPool::new(1, Arc::default());
+        let (done_tx, done_rx) = mpsc::channel();
+
+        pool.execute(|| panic!("simulated handler failure"));
+        pool.execute(move || done_tx.send(thread::current().name().map(String::from)).unwrap());
+
+        let ran_on = done_rx
+            .recv_timeout(Duration::from_secs(2))
+            .expect("Job after a panic never ran");
+        assert_eq!(ran_on.as_deref(), Some("worker-0"));
+    }
+
+    #[test]
+    fn test_higher_priority_jobs_run_first() {
+        let pool = ThreadPool::new(1, Arc::default());
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (order_tx, order_rx) = mpsc::channel();
+
+        // Saturate the only worker so the next jobs have to queue.
+        pool.execute(move || {
+            let _ = gate_rx.recv();
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        for (priority, label) in [(1, "low"), (0, "default"), (9, "high")] {
+            let order_tx = order_tx.clone();
+            pool.execute_with_priority(priority, move || {
+                order_tx.send(label).unwrap();
+            });
+        }
+        gate_tx.send(()).unwrap();
+
+        let order: Vec<_> = (0..3)
+            .map(|_| order_rx.recv_timeout(Duration::from_secs(2)).unwrap())
+            .collect();
+        assert_eq!(order, vec!["high", "low", "default"]);
+    }
+
+    #[test]
+    fn test_aging_prevents_low_priority_starvation() {
+        let pool = ThreadPool::new(1, Arc::default());
+        pool.queue.set_aging(Duration::from_millis(1));
+        let (served_tx, served_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // Keep the only worker busy with a steady stream of priority-255
+        // jobs, always a few ahead of it.
+        let feeder = {
+            let handle = pool.handle();
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    while handle.queue.depth() < 4 {
+                        handle.execute_with_priority(u8::MAX, || {
+                            thread::sleep(Duration::from_millis(1))
+                        });
+                    }
+                    thread::sleep(Duration::from_micros(200));
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        pool.execute_with_priority(0, move || served_tx.send(start.elapsed()).unwrap());
+
+        // Without aging this would wait until the feeder stops. With 1ms
+        // aging it overtakes jobs submitted 255ms or more after it.
+        let waited = served_rx.recv_timeout(Duration::from_secs(2));
+        stop.store(true, Ordering::SeqCst);
+        feeder.join().unwrap();
+        let waited = waited.expect("Low-priority job starved");
+        assert!(waited < Duration::from_secs(1), "Low-priority job waited {:?}", waited);
+    }
+
+    #[test]
+    fn test_pinned_connections_stay_on_one_worker() {
+        let pool = ThreadPool::new(4, Arc::default());
+        pool.queue.set_pinned(4);
+        let (ran_on_tx, ran_on_rx) = mpsc::channel();
+
+        for round in 0..5 {
+            for connection in 0..8u64 {
+                let ran_on_tx = ran_on_tx.clone();
+                pool.execute_for(connection, move || {
+                    let name = thread::current().name().unwrap().to_string();
+                    ran_on_tx.send((connection, name)).unwrap();
+                    // Vary job lengths so a free-for-all would mix workers up.
+                    thread::sleep(Duration::from_millis((connection + round) % 3));
+                });
+            }
+        }
+
+        let mut workers: HashMap<u64, String> = HashMap::new();
+        for _ in 0..40 {
+            let (connection, name) = ran_on_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert_eq!(name, format!("worker-{}", pinned_worker(connection, 4)));
+            let first = workers.entry(connection).or_insert_with(|| name.clone());
+            assert_eq!(*first, name, "Connection {} moved between workers", connection);
+        }
+    }
+
+    #[test]
+    fn test_thread_pool_size_keeps_queue_settings() {
+        let err = Server::new("127.0.0.1:0").unwrap().with_thread_pool_size(0).err();
+        match err {
+            Some(ProtocolError::Io(e)) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            other => panic!("Expected InvalidInput, got {:?}", other),
+        }
+
+        let server = Server::new("127.0.0.1:0")
+            .unwrap()
+            .with_pinned_workers(true)
+            .with_priority_aging(Duration::from_millis(7))
+            .with_queue_depth_thresholds(&[5])
+            .with_thread_pool_size(16)
+            .unwrap();
+        assert_eq!(server.thread_pool.workers.len(), 16);
+        let state = server.thread_pool.queue.state.lock().unwrap();
+        assert_eq!(state.pinned.len(), 16);
+        assert_eq!(state.aging, Duration::from_millis(7));
+        assert_eq!(state.thresholds, vec![5]);
+    }
+
+    #[test]
+    fn test_arrival_log_dates_each_request_by_its_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = Stream::Tcp(listener.accept().unwrap().0);
+        let log = ArrivalLog::default();
+
+        writer.write_all(&[0; 10]).unwrap();
+        while stream.pending_bytes().unwrap() < 10 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        log.sample(&stream);
+        let first = log.arrival(10).expect("First request was sampled");
+
+        thread::sleep(Duration::from_millis(20));
+        log.sample(&stream);
+        assert_eq!(log.marks.lock().unwrap().len(), 1, "Nothing new arrived");
+
+        writer.write_all(&[0; 5]).unwrap();
+        while stream.pending_bytes().unwrap() < 15 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        log.sample(&stream);
+        assert_eq!(log.arrival(4), Some(first));
+        let second = log.arrival(15).expect("Second request was sampled");
+        assert!(second.duration_since(first) >= Duration::from_millis(20));
+        assert_eq!(log.arrival(16), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tcp_keepalive_is_applied_to_accepted_connections() {
+        use std::os::fd::AsRawFd;
+
+        fn getsockopt(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    stream.as_raw_fd(),
+                    level,
+                    name,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(result, 0, "{}", io::Error::last_os_error());
+            value
+        }
+
+        let server = Arc::new(
+            Server::new("127.0.0.1:0")
+                .unwrap()
+                .with_tcp_keepalive(Duration::from_secs(7), Duration::from_millis(1500)),
+        );
+        let runner = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.run())
+        };
+        let _client = TcpStream::connect(server.local_addrs[0]).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while server.state.connections.len() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        {
+            let streams = server.state.connections.streams.lock().unwrap();
+            let accepted = streams.values().next().expect("Connection was not accepted");
+            let accepted = accepted.stream.as_tcp().unwrap();
+            assert_eq!(getsockopt(accepted, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+            assert_eq!(getsockopt(accepted, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 7);
+            assert_eq!(getsockopt(accepted, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 2);
+        }
+
+        server.stop();
+        runner.join().unwrap().unwrap();
+    }
+}
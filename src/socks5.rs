@@ -0,0 +1,111 @@
+//! Minimal SOCKS5 client handshake (RFC 1928): no authentication, CONNECT
+//! command only. The returned stream is positioned after the proxy's reply,
+//! ready for normal framing.
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Connects to `proxy` and asks it to open a tunnel to `host:port`. The
+/// host is sent as an address when it parses as one, otherwise as a domain
+/// name for the proxy to resolve. `timeout` bounds the connect and each read
+/// of the handshake.
+pub fn connect(proxy: SocketAddr, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    handshake(&mut stream, host, port)?;
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+fn handshake<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    stream.write_all(&[VERSION, 1, NO_AUTH])?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != VERSION {
+        return Err(invalid_data("Proxy is not a SOCKS5 server"));
+    }
+    if choice[1] != NO_AUTH {
+        let reason = if choice[1] == NO_ACCEPTABLE_METHODS {
+            "Proxy requires authentication"
+        } else {
+            "Proxy selected an unsupported authentication method"
+        };
+        return Err(io::Error::new(ErrorKind::PermissionDenied, reason));
+    }
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = host.as_bytes();
+            if name.is_empty() || name.len() > u8::MAX as usize {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "Invalid target host"));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name);
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("Malformed SOCKS5 reply"));
+    }
+    if reply[1] != 0x00 {
+        return Err(reply_error(reply[1]));
+    }
+
+    // Skip the bound address; the tunnel is usable without it.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(invalid_data("Unknown address type in SOCKS5 reply")),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn reply_error(code: u8) -> io::Error {
+    let (kind, message) = match code {
+        0x02 => (ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        0x03 => (ErrorKind::Other, "network unreachable"),
+        0x04 => (ErrorKind::Other, "host unreachable"),
+        0x05 => (ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (ErrorKind::TimedOut, "TTL expired"),
+        0x07 => (ErrorKind::Unsupported, "command not supported"),
+        0x08 => (ErrorKind::Unsupported, "address type not supported"),
+        _ => (ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {}", message))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,304 @@
+//! Streams and listeners over TCP or, on Unix, a Unix domain socket. The
+//! framing and connection handling are the same for both; only setup
+//! differs, e.g. TCP options do not apply to Unix sockets.
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Reads without consuming, like `TcpStream::peek`, but fails with `WouldBlock` instead of waiting for data.
+    /// On Unix the socket's blocking mode is left alone, so a clone of the
+    /// stream writing on another thread is unaffected.
+    pub(crate) fn peek_nonblocking(&self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        {
+            self.recv_flags(buf, libc::MSG_PEEK | libc::MSG_DONTWAIT)
+        }
+        #[cfg(not(unix))]
+        {
+            let Stream::Tcp(s) = self;
+            s.set_nonblocking(true)?;
+            let result = s.peek(buf);
+            s.set_nonblocking(false)?;
+            result
+        }
+    }
+
+    /// Bytes received but not yet read, without reading them or changing
+    /// the socket's blocking mode.
+    pub(crate) fn pending_bytes(&self) -> io::Result<usize> {
+        #[cfg(unix)]
+        {
+            let mut pending: libc::c_int = 0;
+            // SAFETY: FIONREAD writes one `c_int` through the pointer, and
+            // the descriptor stays open for the duration of the call.
+            if unsafe { libc::ioctl(self.raw_fd(), libc::FIONREAD, &mut pending) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(pending.max(0) as usize)
+        }
+        #[cfg(not(unix))]
+        {
+            let mut probe = vec![0u8; 64 * 1024];
+            match self.peek_nonblocking(&mut probe) {
+                Ok(n) => Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+                Err(e) => Err(e),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> std::os::fd::RawFd {
+        use std::os::fd::AsRawFd;
+        match self {
+            Stream::Tcp(s) => s.as_raw_fd(),
+            Stream::Unix(s) => s.as_raw_fd(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn recv_flags(&self, buf: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes and the
+        // descriptor stays open for the duration of the call.
+        let n = unsafe { libc::recv(self.raw_fd(), buf.as_mut_ptr().cast(), buf.len(), flags) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// The TCP stream, for options that only TCP has.
+    pub(crate) fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Tcp(s) => Some(s),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Where an accepted connection came from. Unix socket clients are usually
+/// unnamed, so they are not told apart here.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PeerAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            PeerAddr::Unix => f.write_str("unix socket peer"),
+        }
+    }
+}
+
+/// An address a server listens on, once bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Binds a non-blocking listener. A Unix socket file left behind by a
+    /// listener that is gone is replaced; one still accepting connections,
+    /// or any other file, makes binding fail.
+    pub(crate) fn bind(&self) -> io::Result<Listener> {
+        let listener = match self {
+            ListenAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                let stale = std::fs::symlink_metadata(path)
+                    .is_ok_and(|meta| meta.file_type().is_socket())
+                    && UnixStream::connect(path).is_err();
+                if stale {
+                    std::fs::remove_file(path)?;
+                }
+                Listener::Unix(UnixListener::bind(path)?, path.clone())
+            }
+        };
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    /// Opens and drops a connection, to wake a listener blocked in accept.
+    pub(crate) fn poke(&self) {
+        match self {
+            ListenAddr::Tcp(addr) => {
+                let _ = TcpStream::connect(addr);
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(crate) fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, addr)| (Stream::Tcp(s), PeerAddr::Tcp(addr))),
+            #[cfg(unix)]
+            Listener::Unix(l, _) => l.accept().map(|(s, _)| (Stream::Unix(s), PeerAddr::Unix)),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(l, _) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    /// Gives the listener up without closing its descriptor, for when the
+    /// descriptor was already closed elsewhere: its number may since have
+    /// been reused for an unrelated file, which closing it again would
+    /// break. A Unix socket's file is still removed.
+    ///
+    /// This only covers the close. Until `accept` reports the descriptor as
+    /// bad, calls on it may reach whatever file reused the number, which
+    /// cannot be prevented from inside the process that had it closed.
+    pub(crate) fn abandon(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = &self {
+            let _ = std::fs::remove_file(path);
+        }
+        std::mem::forget(self);
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match self {
+            Listener::Tcp(l) => l.as_raw_fd(),
+            Listener::Unix(l, _) => l.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -1,23 +1,48 @@
 use serial_test::serial;
 use task::{
-    message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
-    client::Client,
+    handler::{self, HandlerRegistry, MessageHandler, MessageKind},
+    message::{
+        client_message::{self, Message as ClientMessageEnum},
+        server_message, AddRequest, EchoMessage, MultiplyRequest, ServerMessage, StatusCode,
+        StreamEchoRequest,
+    },
+    server::{Server, ServerBuilder},
+    client::{Client, ClientPool, RetryPolicy},
+    codec::Codec,
+    error::ProtocolError,
+    framing::{self, FRAME_SYNC_MARKER},
+    metrics::{self, CloseReason, SIZE_BUCKETS},
 };
+use prost::Message;
 use std::{
-    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
 fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    let (ready, started) = mpsc::channel();
     let handle = thread::spawn(move || {
-        server.run().expect("Server encountered an error");
+        server
+            .run_with_ready(move || ready.send(()).unwrap())
+            .expect("Server encountered an error");
     });
-    thread::sleep(Duration::from_millis(200));
+    started
+        .recv_timeout(Duration::from_secs(5))
+        .expect("Server never signalled readiness");
     handle
 }
 
+/// The I/O error kind behind a transport error.
+fn io_kind(err: &ProtocolError) -> ErrorKind {
+    match err {
+        ProtocolError::Io(e) => e.kind(),
+        other => panic!("Expected a transport error, got {:?}", other),
+    }
+}
+
 fn create_server() -> Arc<Server> {
     Arc::new(Server::new("localhost:8080").expect("Failed to start server"))
 }
@@ -38,6 +63,61 @@ fn test_client_connection() {
     assert!(handle.join().is_ok());
 }
 
+#[test]
+#[serial]
+fn test_is_running_tracks_run_and_stop() {
+    let server = create_server();
+    assert!(!server.is_running());
+    let runner = server.clone();
+    let handle = thread::spawn(move || runner.run().expect("Server encountered an error"));
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while !server.is_running() {
+        assert!(Instant::now() < deadline, "Server did not start");
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // Once it reports running, the server accepts without further waiting.
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.connect().expect("Failed to connect once running");
+    assert_eq!(client.echo("running").unwrap(), "running");
+    assert!(client.disconnect().is_ok());
+
+    server.stop();
+    assert!(!server.is_running());
+    handle.join().unwrap();
+    assert!(!server.is_running());
+}
+
+#[test]
+#[serial]
+fn test_run_with_ready_signals_once_accepting() {
+    let server = create_server();
+    let (ready, started) = mpsc::channel();
+    let runner = server.clone();
+    let handle = thread::spawn(move || {
+        runner
+            .run_with_ready(move || ready.send(()).unwrap())
+            .expect("Server encountered an error");
+    });
+    started
+        .recv_timeout(Duration::from_secs(1))
+        .expect("Server never signalled readiness");
+
+    // No settling time: the first connection is served straight away.
+    let start = Instant::now();
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.connect().expect("Failed to connect right after readiness");
+    assert_eq!(client.echo("ready").unwrap(), "ready");
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(50), "First echo took {:?}", elapsed);
+    // The callback is consumed, so it cannot fire again.
+    assert!(started.try_recv().is_err());
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
 #[test]
 #[serial]
 fn test_client_echo_message() {
@@ -46,26 +126,19 @@ fn test_client_echo_message() {
 
     let mut client = Client::new("localhost", 8080, 2000);
     assert!(client.connect().is_ok());
-    thread::sleep(Duration::from_millis(50));
 
-    let mut echo_message = EchoMessage::default();
-    echo_message.content = "Hello, World!".to_string();
+    let echo_message = EchoMessage {
+        content: "Hello, World!".to_string(),
+    };
     let message = client_message::Message::EchoMessage(echo_message.clone());
 
-    assert!(client.send(message).is_ok());
-    thread::sleep(Duration::from_millis(50));
-
-    let response = client.receive();
-    assert!(response.is_ok());
-
-    match response.unwrap().message {
+    match client.request(message).expect("Failed to receive response").message {
         Some(server_message::Message::EchoMessage(echo)) => {
             assert_eq!(echo.content, echo_message.content);
         }
         _ => panic!("Expected EchoMessage"),
     }
 
-    thread::sleep(Duration::from_millis(50));
     assert!(client.disconnect().is_ok());
     server.stop();
     handle.join().unwrap();
@@ -79,30 +152,328 @@ fn test_client_add_request() {
 
     let mut client = Client::new("localhost", 8080, 2000);
     assert!(client.connect().is_ok());
-    thread::sleep(Duration::from_millis(50));
 
-    let add_request = AddRequest { a: 10, b: 20 };
+    let add_request = AddRequest { a: 10, b: 20, ..Default::default() };
     let message = client_message::Message::AddRequest(add_request);
 
-    assert!(client.send(message).is_ok());
-    thread::sleep(Duration::from_millis(50));
-
-    let response = client.receive();
-    assert!(response.is_ok());
-
-    match response.unwrap().message {
+    match client.request(message).expect("Failed to receive response").message {
         Some(server_message::Message::AddResponse(add_response)) => {
             assert_eq!(add_response.result, 30);
         }
         _ => panic!("Expected AddResponse"),
     }
 
-    thread::sleep(Duration::from_millis(50));
     assert!(client.disconnect().is_ok());
     server.stop();
     handle.join().unwrap();
 }
 
+#[test]
+#[serial]
+fn test_client_multiply_request() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // The product does not fit in an i32.
+    let multiply_request = MultiplyRequest { a: i32::MAX, b: -3 };
+    let message = client_message::Message::MultiplyRequest(multiply_request);
+
+    match client.request(message).expect("Failed to receive response").message {
+        Some(server_message::Message::MultiplyResponse(multiply_response)) => {
+            assert_eq!(multiply_response.result, i32::MAX as i64 * -3);
+        }
+        _ => panic!("Expected MultiplyResponse"),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_stream_echo_sends_count_responses() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_stream_echo_count(50),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let stream_echo = |count| {
+        client_message::Message::StreamEchoRequest(StreamEchoRequest {
+            content: "again".to_string(),
+            count,
+        })
+    };
+    assert!(client.send(stream_echo(50)).is_ok());
+    let responses = client.receive_all(50).expect("Failed to receive echoes");
+    for response in responses {
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "again"),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+
+    // Over the cap: one error instead of any echoes.
+    match client.request(stream_echo(51)).unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::InvalidArgument);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    assert_eq!(client.echo("after the stream").unwrap(), "after the stream");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_request_stream_invokes_callback_per_echo() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_stream_echo_count(50),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let mut chunks = Vec::new();
+    let request = StreamEchoRequest {
+        content: "chunk".to_string(),
+        count: 20,
+    };
+    let total = client
+        .request_stream(request, |echo| chunks.push(echo.content))
+        .expect("Stream failed");
+    assert_eq!(total, 20);
+    assert_eq!(chunks, vec!["chunk"; 20]);
+
+    // An empty stream ends at once.
+    let request = StreamEchoRequest {
+        content: "none".to_string(),
+        count: 0,
+    };
+    assert_eq!(client.request_stream(request, |_| panic!("No echoes expected")).unwrap(), 0);
+
+    // Over the cap: the error ends the stream before any chunk.
+    let request = StreamEchoRequest {
+        content: "too many".to_string(),
+        count: 51,
+    };
+    match client.request_stream(request, |_| panic!("No echoes expected")) {
+        Err(ProtocolError::Server { code, .. }) => assert_eq!(code, StatusCode::InvalidArgument),
+        other => panic!("Expected a server error, got {:?}", other),
+    }
+    assert_eq!(client.echo("after the stream").unwrap(), "after the stream");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_json_codec() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_codec(Codec::Json),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Plain JSON lines, as a tool without protobuf support would send them.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut exchange = |request: &str| {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+    assert_eq!(exchange("{\"echo\":\"hi\"}\n"), "{\"echo\":\"hi\"}\n");
+    assert_eq!(
+        exchange("{\"add\":{\"a\":1,\"b\":2}}\r\n"),
+        "{\"add\":{\"result\":3,\"a\":1,\"b\":2,\"operation\":\"add\",\"request_id\":0}}\n"
+    );
+    let error = exchange("{\"echo\":\n");
+    assert!(error.starts_with("{\"error\":{\"code\":\"INVALID_ARGUMENT\""), "{}", error);
+    assert_eq!(exchange("{\"echo\":\"still open\"}\n"), "{\"echo\":\"still open\"}\n");
+    drop(stream);
+
+    let mut client = Client::new("localhost", 8080, 1000);
+    client.set_codec(Codec::Json);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("via client").unwrap(), "via client");
+    assert_eq!(client.add(20, 22).unwrap(), 42);
+    assert!(client.disconnect().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_ping() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    for nonce in [0, 42, u64::MAX] {
+        assert_eq!(client.ping(nonce).expect("Ping failed"), nonce);
+    }
+
+    // Pings interleave with ordinary requests on the same connection.
+    assert_eq!(client.echo("between pings").unwrap(), "between pings");
+    assert_eq!(client.ping(7).unwrap(), 7);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_pong_reports_server_load() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 1000);
+    assert!(client.connect().is_ok());
+    let pong = client.ping_with_load(1).expect("Ping failed");
+    assert_eq!(pong.nonce, 1);
+    assert_eq!(pong.active_connections, 1);
+    assert_eq!(pong.queue_depth, 0);
+    assert_eq!(pong.error_rate, 0.0);
+
+    let mut other = Client::new("localhost", 8080, 1000);
+    assert!(other.connect().is_ok());
+    assert_eq!(other.echo("fine").unwrap(), "fine");
+    assert!(other.add(i32::MAX, 1).is_err());
+    let pong = client.ping_with_load(2).expect("Ping failed");
+    assert_eq!(pong.active_connections, 2);
+    assert_eq!(pong.queue_depth, 0);
+    // One failure among the first ping, the echo and the add.
+    assert!((pong.error_rate - 1.0 / 3.0).abs() < 1e-6, "error rate {}", pong.error_rate);
+
+    assert!(other.disconnect().is_ok());
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_server_status() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.echo("one").unwrap(), "one");
+    assert_eq!(client.echo("two").unwrap(), "two");
+
+    let status = client.status().expect("Status request failed");
+    assert!(status.active_connections >= 1, "{:?}", status);
+    assert!(status.total_requests >= 2, "{:?}", status);
+    assert!(status.uptime_secs < 60, "{:?}", status);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_pool() {
+    // Idle connections each hold a worker, so give every pooled one its own.
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:8080")
+            .thread_pool_size(8)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let pool = ClientPool::new("localhost", 8080, 2000, 5).expect("Failed to open pool");
+    assert_eq!(pool.size(), 5);
+    let handles: Vec<_> = (0..10)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for j in 0..5 {
+                    let content = format!("worker {} request {}", i, j);
+                    let mut client = pool.get().expect("Failed to get a pooled client");
+                    assert_eq!(client.echo(&content).unwrap(), content);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let metrics = server.metrics();
+    assert_eq!(metrics.requests[&MessageKind::Echo].count, 50);
+
+    // A client left disconnected is reconnected before it is handed out.
+    pool.get().unwrap().disconnect().unwrap();
+    assert_eq!(pool.get().unwrap().echo("reconnected").unwrap(), "reconnected");
+
+    drop(pool);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_pool_health_check_replaces_dead_connections() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let pool = ClientPool::new("localhost", 8080, 2000, 1)
+        .expect("Failed to open pool")
+        .with_health_check(Duration::from_millis(50));
+    assert_eq!(pool.get().unwrap().echo("before").unwrap(), "before");
+
+    // Kill the server under the idle pooled connection, then bring up a new
+    // one on the same port.
+    server.stop();
+    handle.join().unwrap();
+    drop(server);
+    thread::sleep(Duration::from_millis(200));
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // Without any `get`, the sweeper reconnects and its next ping reaches
+    // the new server.
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while server.metrics().requests[&MessageKind::Ping].count == 0 {
+        assert!(Instant::now() < deadline, "Sweeper never reconnected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client = pool.get().unwrap();
+    assert!(client.is_connected());
+    assert_eq!(client.echo("after").unwrap(), "after");
+
+    drop(client);
+    drop(pool);
+    server.stop();
+    handle.join().unwrap();
+}
+
 #[test]
 #[serial]
 fn test_server_scalability() {
@@ -128,9 +499,10 @@ fn test_server_scalability() {
                             content: format!("Client {} message {}", i, j),
                         })
                     } else {
-                        client_message::Message::AddRequest(AddRequest { 
-                            a: i as i32, 
-                            b: j as i32 
+                        client_message::Message::AddRequest(AddRequest {
+                            a: i,
+                            b: j,
+                            ..Default::default()
                         })
                     };
 
@@ -193,6 +565,7 @@ fn test_concurrent_request_handling() {
                         client_message::Message::AddRequest(AddRequest {
                             a: client_id as i32,
                             b: req_id as i32,
+                            ..Default::default()
                         })
                     };
 
@@ -309,4 +682,3182 @@ fn test_large_message_handling() {
     assert!(client.disconnect().is_ok());
     server.stop();
     handle.join().unwrap();
-}
\ No newline at end of file
+}
+
+#[test]
+#[serial]
+fn test_receive_times_out() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 300);
+    assert!(client.connect().is_ok());
+
+    // Nothing was sent, so nothing comes back.
+    let start = Instant::now();
+    let err = client.receive().unwrap_err();
+    assert_eq!(io_kind(&err), ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+
+    assert!(client.connect().is_ok());
+    let start = Instant::now();
+    let err = client.receive_timeout(Duration::from_millis(50)).unwrap_err();
+    assert_eq!(io_kind(&err), ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+
+    // The override only lasts for one call.
+    assert!(client.connect().is_ok());
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "late enough".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    match client.receive_timeout(Duration::from_secs(2)).unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "late enough"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_compressed_echo() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000).with_compression(true);
+    assert!(client.connect().is_ok());
+
+    let log: String = (0..2000)
+        .map(|i| format!("2024-05-01T12:00:00Z INFO worker {} handled request {}\n", i % 4, i))
+        .collect();
+    let payload = &log[..100 * 1024];
+    assert_eq!(client.echo(payload).unwrap(), payload);
+    // Small requests go out uncompressed on the same connection.
+    assert_eq!(client.echo("short").unwrap(), "short");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_compression_threshold_only_compresses_large_messages() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // Relays one connection to the server, noting whether each request
+    // frame is flagged as compressed.
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = proxy.local_addr().unwrap().port() as u32;
+    let relay = thread::spawn(move || {
+        let (mut client, _) = proxy.accept().unwrap();
+        let mut upstream = TcpStream::connect("localhost:8080").unwrap();
+        let mut downstream = upstream.try_clone().unwrap();
+        let mut replies = client.try_clone().unwrap();
+        thread::spawn(move || std::io::copy(&mut downstream, &mut replies));
+
+        let mut compressed = Vec::new();
+        let mut prefix = [0u8; 4];
+        while client.read_exact(&mut prefix).is_ok() {
+            let len = u32::from_be_bytes(prefix);
+            let mut payload = vec![0u8; (len & !framing::COMPRESSED_FRAME_FLAG) as usize];
+            client.read_exact(&mut payload).unwrap();
+            compressed.push(len & framing::COMPRESSED_FRAME_FLAG != 0);
+            upstream.write_all(&prefix).unwrap();
+            upstream.write_all(&payload).unwrap();
+        }
+        compressed
+    });
+
+    let mut client = Client::new("127.0.0.1", port, 2000).with_compression_threshold(1024);
+    assert!(client.connect().is_ok());
+    // Compressible, but under the threshold.
+    let small = "a".repeat(512);
+    assert_eq!(client.echo(&small).unwrap(), small);
+    let large = "b".repeat(64 * 1024);
+    assert_eq!(client.echo(&large).unwrap(), large);
+    assert!(client.disconnect().is_ok());
+
+    assert_eq!(relay.join().unwrap(), vec![false, true]);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_happy_eyeballs_connect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // "localhost" commonly resolves to both ::1 and 127.0.0.1, while the
+    // server is only listening on one of them.
+    let mut client = Client::new("localhost", 8080, 2000);
+    let start_time = Instant::now();
+    assert!(client.connect_happy_eyeballs().is_ok());
+    assert!(
+        start_time.elapsed() < Duration::from_millis(1000),
+        "Happy eyeballs connect took {:?}",
+        start_time.elapsed()
+    );
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "dual stack".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    match client.receive().unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "dual stack");
+        }
+        _ => panic!("Expected EchoMessage"),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+/// Answers every application request with an echo counting the requests
+/// it has seen.
+struct CountingHandler {
+    seen: AtomicUsize,
+}
+
+impl MessageHandler for CountingHandler {
+    fn handle(&self, _message: ClientMessageEnum) -> ServerMessage {
+        let seen = self.seen.fetch_add(1, Ordering::SeqCst) + 1;
+        ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage {
+                content: format!("request {}", seen),
+            })),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_custom_message_handler() {
+    let handler = Arc::new(CountingHandler { seen: AtomicUsize::new(0) });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_message_handler(handler.clone()),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("anything").unwrap(), "request 1");
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    match client.request(message).unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "request 2"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    // Protocol requests are still answered by the server itself.
+    assert_eq!(client.ping(9).unwrap(), 9);
+    assert_eq!(handler.seen.load(Ordering::SeqCst), 2);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_handlers_see_their_connection_id() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |_| {
+        let id = handler::current_connection().expect("Handler ran outside a connection");
+        handler::echo(ClientMessageEnum::EchoMessage(EchoMessage { content: id.to_string() }))
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut first = Client::new("localhost", 8080, 2000);
+    let mut second = Client::new("localhost", 8080, 2000);
+    assert!(first.connect().is_ok());
+    assert!(second.connect().is_ok());
+    let first_id = first.echo("").unwrap();
+    let second_id = second.echo("").unwrap();
+    assert_ne!(first_id, second_id);
+    // The id belongs to the connection, not the request.
+    assert_eq!(first.echo("").unwrap(), first_id);
+    assert_eq!(handler::current_connection(), None);
+
+    assert!(first.disconnect().is_ok());
+    assert!(second.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_unregistered_handler_returns_unsupported() {
+    let mut registry = HandlerRegistry::new();
+    registry.register(MessageKind::Echo, handler::echo);
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok());
+    match client.receive().unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::Unimplemented);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // The connection stays usable for registered operations.
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "still here".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    match client.receive().unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "still here");
+        }
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_send_after_server_stopped() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "before stop".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    assert!(client.receive().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+    // Idle long enough that send probes the connection first.
+    thread::sleep(Duration::from_millis(100));
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "after stop".to_string(),
+    });
+    let err = client.send(message).expect_err("Send should fail once the server is gone");
+    assert!(
+        matches!(
+            io_kind(&err),
+            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset
+        ),
+        "Unexpected error: {:?}",
+        err
+    );
+}
+
+#[test]
+#[serial]
+fn test_pipelined_order_preserved_across_workers() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    const NUM_CLIENTS: usize = 8; // More clients than worker threads
+    const PIPELINE_DEPTH: usize = 20;
+
+    let handles: Vec<_> = (0..NUM_CLIENTS)
+        .map(|client_id| {
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 2000);
+                assert!(client.connect().is_ok());
+
+                // Queue every request before reading any response.
+                for req_id in 0..PIPELINE_DEPTH {
+                    let message = if req_id % 2 == 0 {
+                        client_message::Message::EchoMessage(EchoMessage {
+                            content: format!("Client {} Request {}", client_id, req_id),
+                        })
+                    } else {
+                        client_message::Message::AddRequest(AddRequest {
+                            a: client_id as i32,
+                            b: req_id as i32,
+                            ..Default::default()
+                        })
+                    };
+                    assert!(client.send(message).is_ok());
+                }
+
+                for req_id in 0..PIPELINE_DEPTH {
+                    match client.receive().unwrap().message {
+                        Some(server_message::Message::EchoMessage(echo)) => {
+                            assert_eq!(
+                                echo.content,
+                                format!("Client {} Request {}", client_id, req_id)
+                            );
+                        }
+                        Some(server_message::Message::AddResponse(add)) => {
+                            assert_eq!(req_id % 2, 1, "Unexpected AddResponse at {}", req_id);
+                            assert_eq!(add.result, (client_id + req_id) as i32);
+                        }
+                        other => panic!("Unexpected response {:?}", other),
+                    }
+                }
+
+                assert!(client.disconnect().is_ok());
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_add_response_metadata() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 7,
+        b: -3,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok());
+
+    match client.receive().unwrap().message {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 4);
+            assert_eq!(add_response.a, 7);
+            assert_eq!(add_response.b, -3);
+            assert_eq!(add_response.operation, "add");
+            assert_eq!(add_response.request_id, 0);
+        }
+        _ => panic!("Expected AddResponse"),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_add_response_carries_request_id() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // Pipeline several requests before reading any response.
+    let ids = [7, 1, u64::MAX];
+    for (i, &request_id) in ids.iter().enumerate() {
+        let message = client_message::Message::AddRequest(AddRequest {
+            a: i as i32,
+            b: 100,
+            request_id,
+        });
+        assert!(client.send(message).is_ok());
+    }
+    for (i, &request_id) in ids.iter().enumerate() {
+        match client.receive().unwrap().message {
+            Some(server_message::Message::AddResponse(add_response)) => {
+                assert_eq!(add_response.request_id, request_id);
+                assert_eq!(add_response.result, i as i32 + 100);
+            }
+            other => panic!("Expected AddResponse, got {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_topic_subscriptions() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut alpha = Client::new("localhost", 8080, 2000);
+    let mut beta = Client::new("localhost", 8080, 2000);
+    assert!(alpha.connect().is_ok());
+    assert!(beta.connect().is_ok());
+    assert!(alpha.subscribe("alpha").is_ok());
+    assert!(beta.subscribe("beta").is_ok());
+
+    assert_eq!(server.publish("alpha", "a1"), 1);
+    assert_eq!(server.publish("beta", "b1"), 1);
+    assert_eq!(server.publish("alpha", "a2"), 1);
+    assert_eq!(server.publish("gamma", "nobody"), 0);
+
+    let received: Vec<_> = alpha
+        .incoming()
+        .take(2)
+        .map(|message| match message.unwrap().message {
+            Some(server_message::Message::TopicMessage(push)) => {
+                assert_eq!(push.topic, "alpha");
+                push.content
+            }
+            other => panic!("Unexpected message {:?}", other),
+        })
+        .collect();
+    assert_eq!(received, vec!["a1", "a2"]);
+
+    match beta.incoming().next() {
+        Some(Ok(message)) => match message.message {
+            Some(server_message::Message::TopicMessage(push)) => {
+                assert_eq!(push.topic, "beta");
+                assert_eq!(push.content, "b1");
+            }
+            other => panic!("Unexpected message {:?}", other),
+        },
+        other => panic!("Expected a push, got {:?}", other),
+    }
+
+    assert!(beta.unsubscribe("beta").is_ok());
+    assert_eq!(server.publish("beta", "b2"), 0);
+
+    assert!(alpha.disconnect().is_ok());
+    assert!(beta.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_publish() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut subscribers: Vec<_> = (0..2)
+        .map(|_| {
+            let mut client = Client::new("localhost", 8080, 2000);
+            assert!(client.connect().is_ok());
+            assert!(client.subscribe("news").is_ok());
+            client
+        })
+        .collect();
+    let mut publisher = Client::new("localhost", 8080, 2000);
+    assert!(publisher.connect().is_ok());
+
+    assert_eq!(publisher.publish("news", "extra").unwrap(), 2);
+    assert_eq!(publisher.publish("sports", "nobody listening").unwrap(), 0);
+
+    for subscriber in &mut subscribers {
+        match subscriber.receive().unwrap().message {
+            Some(server_message::Message::TopicMessage(push)) => {
+                assert_eq!(push.topic, "news");
+                assert_eq!(push.content, "extra");
+            }
+            other => panic!("Expected TopicMessage, got {:?}", other),
+        }
+    }
+
+    // A subscribed publisher gets its own copy from the next receive.
+    let publisher_subscriber = &mut subscribers[0];
+    assert_eq!(publisher_subscriber.publish("news", "echoed").unwrap(), 2);
+    match publisher_subscriber.receive().unwrap().message {
+        Some(server_message::Message::TopicMessage(push)) => assert_eq!(push.content, "echoed"),
+        other => panic!("Expected TopicMessage, got {:?}", other),
+    }
+
+    for mut client in subscribers {
+        assert!(client.disconnect().is_ok());
+    }
+    assert!(publisher.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_max_messages_per_turn_prevents_starvation() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_messages_per_turn(4),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // One flooding connection per worker thread keeps every worker busy.
+    const NUM_FLOODERS: usize = 4;
+    let stop_flooding = Arc::new(AtomicBool::new(false));
+    let flooders: Vec<_> = (0..NUM_FLOODERS)
+        .map(|_| {
+            let stop_flooding = Arc::clone(&stop_flooding);
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 2000);
+                assert!(client.connect().is_ok());
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while !stop_flooding.load(Ordering::SeqCst) && Instant::now() < deadline {
+                    let message = client_message::Message::AddRequest(AddRequest {
+                        a: 1,
+                        b: 2,
+                        ..Default::default()
+                    });
+                    assert!(client.send(message).is_ok());
+                    assert!(client.receive().is_ok());
+                }
+                client.disconnect().unwrap();
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let start_time = Instant::now();
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "my turn".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    assert!(client.receive().is_ok());
+    let latency = start_time.elapsed();
+    stop_flooding.store(true, Ordering::SeqCst);
+
+    assert!(
+        latency < Duration::from_secs(2),
+        "Quiet client waited {:?} behind flooding connections",
+        latency
+    );
+
+    assert!(client.disconnect().is_ok());
+    for flooder in flooders {
+        flooder.join().unwrap();
+    }
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_prioritized_requests_keep_connection_order() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    for i in 0..10u8 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("Message {}", i),
+        });
+        // Alternate between inline and scheduled handling.
+        assert!(client.send_with_priority(message, (i % 3) * 100).is_ok());
+    }
+
+    for i in 0..10 {
+        match client.receive().unwrap().message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("Message {}", i));
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_queue_depth_threshold_warnings() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_queue_depth_thresholds(&[2]),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Idle connections occupy every worker, so later ones have to queue.
+    // The first four may briefly queue before the workers wake, so count
+    // warnings only once they have all been picked up.
+    let connect = || {
+        let mut client = Client::new("localhost", 8080, 2000);
+        assert!(client.connect().is_ok());
+        client
+    };
+    let mut clients: Vec<_> = (0..4).map(|_| connect()).collect();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(server.metrics().queue_depth, 0);
+    let baseline = server.metrics().queue_depth_warnings;
+
+    clients.extend((0..3).map(|_| connect()));
+    thread::sleep(Duration::from_millis(300));
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.queue_depth, 3);
+    assert_eq!(metrics.queue_depth_warnings, baseline + 1);
+
+    for client in &mut clients {
+        assert!(client.disconnect().is_ok());
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(server.metrics().queue_depth, 0);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_connect_refused_fails_fast() {
+    // Grab a free port, then close it so nothing is listening there.
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+
+    let mut client = Client::new("127.0.0.1", port as u32, 5000);
+    let start_time = Instant::now();
+    let err = client.connect().expect_err("Nothing is listening on the port");
+
+    assert_eq!(io_kind(&err), ErrorKind::ConnectionRefused);
+    assert!(
+        start_time.elapsed() < Duration::from_secs(1),
+        "Refused connect took {:?}",
+        start_time.elapsed()
+    );
+}
+
+#[test]
+#[serial]
+fn test_connect_unanswered_times_out() {
+    // 192.0.2.0/24 is reserved for documentation and never answers.
+    let mut client = Client::new("192.0.2.1", 8080, 300);
+    let start_time = Instant::now();
+    let err = client.connect().expect_err("TEST-NET-1 should not answer");
+
+    match io_kind(&err) {
+        ErrorKind::TimedOut => assert!(
+            start_time.elapsed() >= Duration::from_millis(250),
+            "Timed out early after {:?}",
+            start_time.elapsed()
+        ),
+        // Sandboxes without a default route reject the packet locally.
+        other => println!("No route to TEST-NET-1 ({:?}); timeout not observable", other),
+    }
+}
+
+#[test]
+#[serial]
+fn test_frame_sync_detects_desync() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_frame_sync(true),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.set_frame_sync(true);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let echo_message = EchoMessage {
+        content: "in sync".to_string(),
+    };
+    assert!(client
+        .send(client_message::Message::EchoMessage(echo_message.clone()))
+        .is_ok());
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo, echo_message),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    assert!(client.disconnect().is_ok());
+
+    // One well-formed frame followed by one missing its marker: the server
+    // must answer the first and drop the connection at the second.
+    let request = task::message::ClientMessage {
+        message: Some(client_message::Message::EchoMessage(echo_message)),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    framing::write_frame(&mut stream, &request, true).unwrap();
+    framing::write_frame(&mut stream, &request, false).unwrap();
+
+    let mut marker = [0u8; 4];
+    stream.read_exact(&mut marker).unwrap();
+    assert_eq!(marker, FRAME_SYNC_MARKER);
+    assert!(framing::read_frame(&mut stream, false, usize::MAX).is_ok());
+
+    // Closing with the rest of the bad frame unread may surface as a reset.
+    let mut rest = Vec::new();
+    let read = stream.read_to_end(&mut rest);
+    assert!(
+        matches!(read, Ok(0)) || matches!(&read, Err(e) if e.kind() == ErrorKind::ConnectionReset),
+        "Expected the server to close the connection, got {:?}",
+        read
+    );
+
+    server.stop();
+    assert!(handle.join().is_ok());
+}
+
+/// Accepts one SOCKS5 CONNECT (no auth, domain or IPv4 target) and relays
+/// bytes between the client and the target until either side closes.
+#[cfg(feature = "socks5")]
+fn spawn_socks5_proxy() -> (std::net::SocketAddr, JoinHandle<()>) {
+    use std::io::{self, Write};
+    use std::net::{Ipv4Addr, Shutdown, ToSocketAddrs};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+
+        let mut greeting = [0u8; 2];
+        client.read_exact(&mut greeting).unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        client.read_exact(&mut methods).unwrap();
+        assert_eq!(greeting[0], 5);
+        assert!(methods.contains(&0));
+        client.write_all(&[5, 0]).unwrap();
+
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(&header[..3], &[5, 1, 0]);
+        let host = match header[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                client.read_exact(&mut ip).unwrap();
+                Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                let mut len = [0u8; 1];
+                client.read_exact(&mut len).unwrap();
+                let mut name = vec![0u8; len[0] as usize];
+                client.read_exact(&mut name).unwrap();
+                String::from_utf8(name).unwrap()
+            }
+            other => panic!("Unexpected address type {}", other),
+        };
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).unwrap();
+        let target = (host.as_str(), u16::from_be_bytes(port))
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap();
+
+        let upstream = TcpStream::connect(target).unwrap();
+        client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        let mut client_read = client.try_clone().unwrap();
+        let mut upstream_write = upstream.try_clone().unwrap();
+        let forward = thread::spawn(move || {
+            let _ = io::copy(&mut client_read, &mut upstream_write);
+            let _ = upstream_write.shutdown(Shutdown::Write);
+        });
+        let (mut upstream_read, mut client_write) = (upstream, client);
+        let _ = io::copy(&mut upstream_read, &mut client_write);
+        let _ = client_write.shutdown(Shutdown::Write);
+        forward.join().unwrap();
+    });
+    (addr, handle)
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+#[serial]
+fn test_connect_via_socks5_proxy() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let (proxy_addr, proxy) = spawn_socks5_proxy();
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(
+        client.connect_via_socks5(&proxy_addr.to_string()).is_ok(),
+        "Failed to connect through the proxy"
+    );
+
+    let echo_message = EchoMessage {
+        content: "Through the proxy".to_string(),
+    };
+    assert!(client
+        .send(client_message::Message::EchoMessage(echo_message.clone()))
+        .is_ok());
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo, echo_message),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    proxy.join().unwrap();
+
+    server.stop();
+    assert!(handle.join().is_ok());
+}
+
+#[test]
+#[serial]
+fn test_accept_rate_ramps_connection_burst() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_accept_rate(5, Duration::from_millis(200)),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // 20 connects at 5 per 200ms span four windows; the first may already be
+    // partly over, but the fourth opens at least 400ms after the second.
+    let start_time = Instant::now();
+    let clients: Vec<_> = (0..20)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 2000);
+                client.connect()?;
+                let echo_message = EchoMessage {
+                    content: format!("burst {}", i),
+                };
+                client.send(client_message::Message::EchoMessage(echo_message.clone()))?;
+                let response = client.receive()?;
+                client.disconnect()?;
+                Ok::<_, std::io::Error>((response, echo_message, start_time.elapsed()))
+            })
+        })
+        .collect();
+
+    let mut elapsed: Vec<Duration> = Vec::new();
+    for client in clients {
+        let (response, expected, answered_after) = client
+            .join()
+            .unwrap()
+            .expect("Client in the burst failed");
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo, expected),
+            other => panic!("Expected EchoMessage, but received {:?}", other),
+        }
+        elapsed.push(answered_after);
+    }
+    elapsed.sort();
+
+    assert!(
+        elapsed[19] >= Duration::from_millis(400),
+        "Burst was not ramped: last answer after {:?}",
+        elapsed[19]
+    );
+
+    server.stop();
+    assert!(handle.join().is_ok());
+}
+
+#[test]
+#[serial]
+fn test_request_checked() {
+    let mut registry = HandlerRegistry::new();
+    registry.register(MessageKind::Echo, handler::echo);
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let response = client
+        .request_checked(client_message::Message::EchoMessage(EchoMessage {
+            content: "checked".to_string(),
+        }))
+        .expect("Echo should succeed");
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "checked"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    let err = client
+        .request_checked(client_message::Message::AddRequest(AddRequest {
+            a: 1,
+            b: 2,
+            ..Default::default()
+        }))
+        .expect_err("Add is not registered");
+    match err {
+        ProtocolError::Server { code, .. } => assert_eq!(code, StatusCode::Unimplemented),
+        other => panic!("Expected a server error, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_protocol_errors_are_classified() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    let echo = |content: &str| {
+        client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        })
+    };
+    match client.request_checked(echo("offline")) {
+        Err(ProtocolError::NotConnected) => {}
+        other => panic!("Expected NotConnected, got {:?}", other),
+    }
+
+    assert!(client.connect().is_ok());
+    client.set_max_receive_size(16);
+    match client.request_checked(echo(&"x".repeat(64))) {
+        Err(ProtocolError::MessageTooLarge { max, .. }) => assert_eq!(max, 16),
+        other => panic!("Expected MessageTooLarge, got {:?}", other),
+    }
+    let err = std::io::Error::from(ProtocolError::MessageTooLarge { len: 70, max: 16 });
+    assert!(framing::is_too_large(&err));
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_upgrade_to_duplex_streaming() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert!(client.subscribe("ticks").is_ok());
+    let (mut sender, mut receiver) = client.upgrade().expect("Failed to upgrade");
+
+    // Requests go out from one thread while the server pushes topic messages
+    // from another; the receiver sees both streams interleaved.
+    let count = 50;
+    let send_thread = thread::spawn(move || {
+        for i in 0..count {
+            let echo_message = EchoMessage {
+                content: format!("stream {}", i),
+            };
+            sender
+                .send(client_message::Message::EchoMessage(echo_message))
+                .expect("Failed to send on the stream");
+        }
+        sender
+    });
+    let publisher = {
+        let server = server.clone();
+        thread::spawn(move || {
+            for i in 0..count {
+                assert_eq!(server.publish("ticks", &format!("tick {}", i)), 1);
+            }
+        })
+    };
+
+    let mut echoes = Vec::new();
+    let mut ticks = Vec::new();
+    while echoes.len() < count || ticks.len() < count {
+        match receiver.receive().expect("Failed to receive").message {
+            Some(server_message::Message::EchoMessage(echo)) => echoes.push(echo.content),
+            Some(server_message::Message::TopicMessage(tick)) => ticks.push(tick.content),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+    let expected_echoes: Vec<_> = (0..count).map(|i| format!("stream {}", i)).collect();
+    let expected_ticks: Vec<_> = (0..count).map(|i| format!("tick {}", i)).collect();
+    assert_eq!(echoes, expected_echoes);
+    assert_eq!(ticks, expected_ticks);
+
+    publisher.join().unwrap();
+    let sender = send_thread.join().unwrap();
+    assert!(sender.close().is_ok());
+    // The server closes its side once our side is done.
+    assert_eq!(io_kind(&receiver.receive().unwrap_err()), ErrorKind::UnexpectedEof);
+
+    server.stop();
+    assert!(handle.join().is_ok());
+}
+
+#[test]
+#[serial]
+fn test_message_size_histogram() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let mut expected_inbound = [0u64; SIZE_BUCKETS];
+    let mut expected_outbound = [0u64; SIZE_BUCKETS];
+    for len in [10, 500, 10_000, 100_000, 300_000] {
+        let echo_message = EchoMessage {
+            content: "x".repeat(len),
+        };
+        let request = task::message::ClientMessage {
+            message: Some(client_message::Message::EchoMessage(echo_message.clone())),
+            ..Default::default()
+        };
+        expected_inbound[metrics::size_bucket(request.encoded_len())] += 1;
+
+        assert!(client
+            .send(client_message::Message::EchoMessage(echo_message))
+            .is_ok());
+        let response = client.receive().expect("Failed to receive response");
+        expected_outbound[metrics::size_bucket(response.encoded_len())] += 1;
+    }
+
+    // A response is counted once its write returns, which can be just after
+    // the client has read it.
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut snapshot = server.metrics();
+    while snapshot.outbound_sizes != expected_outbound && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+        snapshot = server.metrics();
+    }
+    assert_eq!(snapshot.inbound_sizes, expected_inbound);
+    assert_eq!(snapshot.outbound_sizes, expected_outbound);
+    // Each size above lands in a different bucket.
+    assert_eq!(snapshot.inbound_sizes.iter().filter(|&&n| n == 1).count(), 5);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_request_counts_and_latencies() {
+    const ECHOES: u64 = 5;
+    const ADDS: u64 = 3;
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let mut bytes_read = 0;
+    let mut bytes_written = 0;
+    let mut exchange = |message: client_message::Message| {
+        let request = task::message::ClientMessage {
+            message: Some(message.clone()),
+            ..Default::default()
+        };
+        bytes_read += request.encoded_len() as u64;
+        let response = client.request(message).expect("Failed to receive response");
+        bytes_written += response.encoded_len() as u64;
+    };
+    for i in 0..ECHOES {
+        exchange(client_message::Message::EchoMessage(EchoMessage {
+            content: format!("echo {}", i),
+        }));
+    }
+    for i in 0..ADDS {
+        exchange(client_message::Message::AddRequest(AddRequest {
+            a: i as i32,
+            b: 1,
+            ..Default::default()
+        }));
+    }
+
+    // Responses are counted once their write returns; see
+    // test_message_size_histogram.
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut snapshot = server.metrics();
+    while snapshot.bytes_written != bytes_written && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+        snapshot = server.metrics();
+    }
+    assert_eq!(snapshot.bytes_read, bytes_read);
+    assert_eq!(snapshot.bytes_written, bytes_written);
+
+    for (kind, stats) in &snapshot.requests {
+        let expected = match kind {
+            MessageKind::Echo => ECHOES,
+            MessageKind::Add => ADDS,
+            _ => 0,
+        };
+        assert_eq!(stats.count, expected, "{} requests", kind);
+        assert_eq!(stats.latency.iter().sum::<u64>(), expected, "{} latencies", kind);
+    }
+    assert_eq!(snapshot.requests.len(), MessageKind::ALL.len());
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_measure_rtt() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let stats = client.measure_rtt(100).expect("Failed to measure RTT");
+    assert_eq!(stats.samples, 100);
+    assert!(stats.min > Duration::ZERO);
+    assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+    assert!(stats.min <= stats.p99 && stats.p99 <= stats.max);
+    assert!(stats.max < Duration::from_secs(1), "Loopback RTT too slow: {:?}", stats);
+
+    assert_eq!(
+        io_kind(&client.measure_rtt(0).unwrap_err()),
+        ErrorKind::InvalidInput
+    );
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_privileged_port_error_is_descriptive() {
+    // Binding succeeds with root or CAP_NET_BIND_SERVICE; nothing to check.
+    if TcpListener::bind("127.0.0.1:1").is_ok() {
+        println!("Privileged ports are bindable here; skipping");
+        return;
+    }
+
+    let err = match Server::new("127.0.0.1:1") {
+        Ok(_) => panic!("Binding a privileged port should fail"),
+        Err(e) => e,
+    };
+    assert_eq!(io_kind(&err), ErrorKind::PermissionDenied);
+    let message = err.to_string();
+    assert!(message.contains("privileged"), "Unhelpful error: {}", message);
+    assert!(message.contains("1024"), "Unhelpful error: {}", message);
+}
+
+#[test]
+#[serial]
+fn test_send_all_pipelines_in_order() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let messages: Vec<_> = (0..200)
+        .map(|i| {
+            if i % 2 == 0 {
+                client_message::Message::EchoMessage(EchoMessage {
+                    content: format!("pipelined {}", i),
+                })
+            } else {
+                client_message::Message::AddRequest(AddRequest { a: i, b: 1, ..Default::default() })
+            }
+        })
+        .collect();
+    assert!(client.send_all(&messages).is_ok());
+
+    let responses = client.receive_all(messages.len()).expect("Failed to receive responses");
+    for (i, response) in responses.into_iter().enumerate() {
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("pipelined {}", i));
+            }
+            Some(server_message::Message::AddResponse(add)) => {
+                assert_eq!(add.result, i as i32 + 1);
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_request_batch_lines_up_responses() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    let batch = vec![
+        client_message::Message::EchoMessage(EchoMessage { content: "first".to_string() }),
+        client_message::Message::AddRequest(AddRequest { a: 2, b: 3, ..Default::default() }),
+        client_message::Message::AddRequest(AddRequest { a: 10, b: -4, ..Default::default() }),
+        client_message::Message::EchoMessage(EchoMessage { content: "last".to_string() }),
+    ];
+    let responses = client.request_batch(batch).expect("Batch failed");
+    assert_eq!(responses.len(), 4);
+    let contents: Vec<String> = responses
+        .into_iter()
+        .map(|response| match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => echo.content,
+            Some(server_message::Message::AddResponse(add)) => add.result.to_string(),
+            other => panic!("Unexpected response {:?}", other),
+        })
+        .collect();
+    assert_eq!(contents, ["first", "5", "6", "last"]);
+
+    // An empty batch sends and waits for nothing.
+    assert!(client.request_batch(Vec::new()).unwrap().is_empty());
+    assert_eq!(client.echo("after").unwrap(), "after");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_reset_on_malformed_frames() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_reset_on_malformed(true),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let echo = |content: &str| {
+        task::message::ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: content.to_string(),
+            })),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    };
+    let expect_echo = |stream: &mut TcpStream, content: &str| {
+        let reply = framing::read_frame(stream, false, usize::MAX).expect("Expected an echo");
+        match task::message::ServerMessage::decode(&reply[..]).unwrap().message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    };
+
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    // An undecodable payload inside an intact frame.
+    framing::write_frame(&mut stream, &[0xff; 8], false).unwrap();
+    let err = framing::read_frame(&mut stream, false, usize::MAX).unwrap_err();
+    assert!(framing::is_reset(&err), "Expected RESET, got {:?}", err);
+    framing::write_reset(&mut stream, false).unwrap();
+    framing::write_frame(&mut stream, &echo("after bad payload"), false).unwrap();
+    expect_echo(&mut stream, "after bad payload");
+
+    // A corrupted length prefix: the server cannot find the next frame on
+    // its own, so it skips ahead to our RESET reply.
+    stream.write_all(&0x7fff_0000u32.to_be_bytes()).unwrap();
+    stream.write_all(&[0x42; 32]).unwrap();
+    let err = framing::read_frame(&mut stream, false, usize::MAX).unwrap_err();
+    assert!(framing::is_reset(&err), "Expected RESET, got {:?}", err);
+    framing::write_reset(&mut stream, false).unwrap();
+    framing::write_frame(&mut stream, &echo("after bad length"), false).unwrap();
+    expect_echo(&mut stream, "after bad length");
+
+    drop(stream);
+    server.stop();
+    assert!(handle.join().is_ok());
+}
+
+#[test]
+#[serial]
+fn test_client_acknowledges_reset() {
+    // Stands in for a server that resets the first request it sees.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let fake_server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+        framing::write_reset(&mut stream, false).unwrap();
+
+        let err = framing::read_frame(&mut stream, false, usize::MAX).unwrap_err();
+        assert!(framing::is_reset(&err), "Client did not acknowledge RESET");
+
+        let retried = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+        let request = task::message::ClientMessage::decode(&retried[..]).unwrap();
+        let Some(client_message::Message::EchoMessage(echo)) = request.message else {
+            panic!("Expected the retried echo");
+        };
+        let reply = task::message::ServerMessage {
+            message: Some(server_message::Message::EchoMessage(echo)),
+        };
+        framing::write_frame(&mut stream, &reply.encode_to_vec(), false).unwrap();
+    });
+
+    let mut client = Client::new("127.0.0.1", port as u32, 2000);
+    assert!(client.connect().is_ok());
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "retry me".to_string(),
+    });
+
+    assert!(client.send(message.clone()).is_ok());
+    let err = client.receive().expect_err("Server reset the framing");
+    match err {
+        ProtocolError::Io(ref e) => assert!(framing::is_reset(e), "{:?}", e),
+        other => panic!("Expected a reset, got {:?}", other),
+    }
+
+    assert!(client.send(message).is_ok());
+    match client.receive().expect("Retry should succeed").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "retry me"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    fake_server.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_receive_gives_up_on_stalled_frame() {
+    // Stands in for a server that sends half a length prefix and stalls.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let fake_server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+        stream.write_all(&[0, 0]).unwrap();
+        let _ = done_rx.recv();
+    });
+
+    let mut client = Client::new("127.0.0.1", port as u32, 300);
+    assert!(client.connect().is_ok());
+    assert!(client
+        .send(client_message::Message::EchoMessage(EchoMessage {
+            content: "stall".to_string(),
+        }))
+        .is_ok());
+
+    let start = Instant::now();
+    let err = client.receive().expect_err("Frame never completes");
+    assert!(start.elapsed() < Duration::from_secs(2), "Took {:?}", start.elapsed());
+    match err {
+        ProtocolError::Io(ref e) => {
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+            assert!(framing::is_stalled(e), "{:?}", e);
+        }
+        other => panic!("Expected a stalled frame, got {:?}", other),
+    }
+    assert!(!client.is_connected(), "Misaligned stream should be closed");
+
+    drop(done_tx);
+    fake_server.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_connect_cancellable() {
+    // Already cancelled: no attempt is made.
+    let cancel = Arc::new(AtomicBool::new(true));
+    let mut client = Client::new("192.0.2.1", 8080, 5000);
+    let err = client.connect_cancellable(cancel).expect_err("Cancelled before starting");
+    assert_eq!(io_kind(&err), ErrorKind::Interrupted);
+
+    // Cancelled mid-attempt against a host that never answers.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let canceller = {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancel.store(true, Ordering::SeqCst);
+        })
+    };
+    let mut client = Client::new("192.0.2.1", 8080, 5000);
+    let start_time = Instant::now();
+    let result = client.connect_cancellable(cancel);
+    let elapsed = start_time.elapsed();
+    canceller.join().unwrap();
+
+    match result {
+        Err(ref e) if io_kind(e) == ErrorKind::Interrupted => {
+            assert!(elapsed < Duration::from_millis(500), "Cancel took {:?}", elapsed);
+        }
+        // Sandboxes without a default route settle the attempt first.
+        other => println!("TEST-NET-1 attempt finished before cancel: {:?}", other),
+    }
+}
+
+#[test]
+#[serial]
+fn test_slow_requests_are_counted() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(150));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry)
+            .with_slow_request_threshold(Duration::from_millis(100)),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // Fast requests stay under the threshold.
+    for _ in 0..3 {
+        let message = client_message::Message::AddRequest(AddRequest {
+            a: 1,
+            b: 2,
+            ..Default::default()
+        });
+        assert!(client.request_checked(message).is_ok());
+    }
+    assert_eq!(server.metrics().slow_requests, 0);
+
+    for _ in 0..2 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: "slow".to_string(),
+        });
+        assert!(client.request_checked(message).is_ok());
+    }
+    assert_eq!(server.metrics().slow_requests, 2);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_resolve_on_connect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let counting_client = |resolve_on_connect: bool| {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut client = Client::new("localhost", 8080, 2000);
+        let counter = lookups.clone();
+        client.set_resolver(move |address| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(std::net::ToSocketAddrs::to_socket_addrs(address)?.collect())
+        });
+        client.set_resolve_on_connect(resolve_on_connect);
+        (client, lookups)
+    };
+
+    for (resolve_on_connect, expected_lookups) in [(true, 3), (false, 1)] {
+        let (mut client, lookups) = counting_client(resolve_on_connect);
+        for _ in 0..3 {
+            assert!(client.connect().is_ok());
+            assert!(client.disconnect().is_ok());
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), expected_lookups);
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_connect_falls_back_to_later_addresses() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // A port nothing listens on, ahead of the server's real addresses.
+    let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let mut client = Client::new("localhost", 8080, 2000);
+    let counter = lookups.clone();
+    client.set_resolver(move |address| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        let mut addrs = vec![unreachable];
+        addrs.extend(std::net::ToSocketAddrs::to_socket_addrs(address)?);
+        Ok(addrs)
+    });
+
+    // The resolution is cached across reconnects by default.
+    for i in 0..3 {
+        assert!(client.connect().is_ok(), "Connect {} did not fall back", i);
+        assert_eq!(client.echo("fallback").unwrap(), "fallback");
+        assert!(client.disconnect().is_ok());
+    }
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+    // A cancellable connect falls back the same way.
+    let cancel = Arc::new(AtomicBool::new(false));
+    assert!(client.connect_cancellable(cancel).is_ok(), "Cancellable connect did not fall back");
+    assert_eq!(client.echo("fallback").unwrap(), "fallback");
+    assert!(client.disconnect().is_ok());
+
+    // Once every cached address fails, the next connect resolves again.
+    server.stop();
+    handle.join().unwrap();
+    drop(server);
+    assert!(client.connect().is_err());
+    assert!(client.connect().is_err());
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+#[serial]
+fn test_invalid_utf8_echo_is_rejected() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    // ClientMessage { echo_message: EchoMessage { content: [0xff, 0xfe] } },
+    // hand-encoded since the generated types only carry valid strings.
+    let invalid_echo = [0x0a, 0x04, 0x0a, 0x02, 0xff, 0xfe];
+    framing::write_frame(&mut stream, &invalid_echo, false).unwrap();
+    let reply = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+    match task::message::ServerMessage::decode(&reply[..]).unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::InvalidArgument);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // The connection is still usable.
+    let valid_echo = task::message::ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "valid".to_string(),
+        })),
+        ..Default::default()
+    };
+    framing::write_frame(&mut stream, &valid_echo.encode_to_vec(), false).unwrap();
+    let reply = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+    match task::message::ServerMessage::decode(&reply[..]).unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "valid"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    drop(stream);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_undecodable_request_is_answered_with_error() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    // Not a ClientMessage: the server says so instead of hanging up.
+    framing::write_frame(&mut stream, &[0xff; 8], false).unwrap();
+    let reply = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+    match task::message::ServerMessage::decode(&reply[..]).unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::InvalidArgument);
+            assert!(error.message.contains("decode"), "{}", error.message);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // The connection is still usable.
+    let valid_echo = task::message::ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "still open".to_string(),
+        })),
+        ..Default::default()
+    };
+    framing::write_frame(&mut stream, &valid_echo.encode_to_vec(), false).unwrap();
+    let reply = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+    match task::message::ServerMessage::decode(&reply[..]).unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "still open")
+        }
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    drop(stream);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_stale_requests_are_shed() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(400));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry)
+            .with_max_messages_per_turn(1)
+            .with_queue_wait_budget(Duration::from_millis(100)),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Occupy all four workers with slow echoes.
+    let mut busy: Vec<_> = (0..4)
+        .map(|i| {
+            let mut client = Client::new("localhost", 8080, 2000);
+            assert!(client.connect().is_ok());
+            let message = client_message::Message::EchoMessage(EchoMessage {
+                content: format!("slow {}", i),
+            });
+            assert!(client.send(message).is_ok());
+            client
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(50));
+
+    // This request waits ~350ms for a worker, well past the budget.
+    let mut late = Client::new("localhost", 8080, 2000);
+    assert!(late.connect().is_ok());
+    let add = client_message::Message::AddRequest(AddRequest { a: 1, b: 2, ..Default::default() });
+    assert!(late.send(add.clone()).is_ok());
+    match late.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::Unavailable);
+        }
+        other => panic!("Expected the request to be shed, got {:?}", other),
+    }
+
+    // Requests already being handled are unaffected.
+    for (i, client) in busy.iter_mut().enumerate() {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("slow {}", i));
+            }
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+
+    // Once the pool has capacity again, the same connection is served.
+    match late.request_checked(add) {
+        Ok(response) => match response.message {
+            Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, 3),
+            other => panic!("Expected AddResponse, got {:?}", other),
+        },
+        Err(e) => panic!("Request after recovery failed: {}", e),
+    }
+    assert_eq!(server.metrics().shed_requests, 1);
+
+    for client in busy.iter_mut().chain(Some(&mut late)) {
+        assert!(client.disconnect().is_ok());
+    }
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_negotiated_limit_rejects_oversized_send_locally() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect_negotiated().is_ok());
+    let limit = client.max_send_size().expect("Limit should be negotiated");
+    assert_eq!(limit, 1024 * 1024);
+
+    let oversized = client_message::Message::EchoMessage(EchoMessage {
+        content: "x".repeat(limit + 1),
+    });
+    let err = client.send(oversized).expect_err("Oversized send should be rejected");
+    assert!(
+        matches!(err, ProtocolError::MessageTooLarge { max, .. } if max == limit),
+        "Unexpected error: {:?}",
+        err
+    );
+
+    // Nothing was sent, so the connection is still in sync and usable.
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "fits".to_string(),
+    });
+    match client.request_checked(message) {
+        Ok(response) => match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "fits"),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        },
+        Err(e) => panic!("Request failed: {}", e),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_receive_raw_matches_decoded() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // One server push and one reply.
+    assert!(client.subscribe("raw").is_ok());
+    assert_eq!(server.publish("raw", "pushed"), 1);
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 2,
+        b: 5,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok());
+
+    for _ in 0..2 {
+        let (message, raw) = client.receive_raw().expect("Failed to receive");
+        let redecoded = task::message::ServerMessage::decode(&raw[..]).unwrap();
+        assert_eq!(redecoded, message);
+        assert_eq!(raw, message.encode_to_vec());
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_split_client_sends_and_receives_concurrently() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let (mut sender, mut receiver) = client.split().expect("Failed to split");
+
+    let count = 100;
+    let send_thread = thread::spawn(move || {
+        for i in 0..count {
+            let message = client_message::Message::EchoMessage(EchoMessage {
+                content: format!("split {}", i),
+            });
+            sender.send(message).expect("Failed to send");
+        }
+        // Closing the sending half is the supported way to disconnect while
+        // the other half is still reading.
+        sender.close().expect("Failed to close");
+    });
+
+    for i in 0..count {
+        match receiver.receive().expect("Failed to receive").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("split {}", i));
+            }
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+    send_thread.join().unwrap();
+    assert_eq!(io_kind(&receiver.receive().unwrap_err()), ErrorKind::UnexpectedEof);
+
+    let unconnected = Client::new("localhost", 8080, 2000);
+    assert!(matches!(unconnected.split(), Err(ProtocolError::NotConnected)));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_identical_requests_are_coalesced() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut registry = HandlerRegistry::builtin();
+    {
+        let runs = runs.clone();
+        registry.register(MessageKind::Add, move |message| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(300));
+            handler::add(message)
+        });
+    }
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry)
+            .with_request_coalescing(true),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // One client per worker, all asking the same question at once.
+    let barrier = Arc::new(std::sync::Barrier::new(4));
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 2000);
+                client.connect().unwrap();
+                barrier.wait();
+                let message = client_message::Message::AddRequest(AddRequest {
+                    a: 20,
+                    b: 22,
+                    ..Default::default()
+                });
+                let response = client.request_checked(message).unwrap();
+                client.disconnect().unwrap();
+                response
+            })
+        })
+        .collect();
+
+    for client in clients {
+        match client.join().unwrap().message {
+            Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, 42),
+            other => panic!("Expected AddResponse, got {:?}", other),
+        }
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // Once nothing is in flight, the next request runs the handler again.
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 20,
+        b: 22,
+        ..Default::default()
+    });
+    assert!(client.request_checked(message).is_ok());
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_drain_and_reconnect_recovers_connection() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // Leave a push queued and a reply unread, so the next receive would
+    // return something unrelated to the next request.
+    assert!(client.subscribe("stale").is_ok());
+    assert_eq!(server.publish("stale", "old push"), 1);
+    let stale = client_message::Message::EchoMessage(EchoMessage {
+        content: "stale".to_string(),
+    });
+    assert!(client.send(stale).is_ok());
+    thread::sleep(Duration::from_millis(100));
+
+    assert!(client.drain_and_reconnect().is_ok());
+
+    let fresh = client_message::Message::EchoMessage(EchoMessage {
+        content: "fresh".to_string(),
+    });
+    assert!(client.send(fresh).is_ok());
+    match client.receive().expect("Failed to receive").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "fresh"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_add_overflow_is_invalid_argument() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    for (a, b) in [(i32::MAX, 1), (i32::MIN, -1)] {
+        let message = client_message::Message::AddRequest(AddRequest {
+            a,
+            b,
+            ..Default::default()
+        });
+        match client.request_checked(message) {
+            Err(ProtocolError::Server { code, message }) => {
+                assert_eq!(code, StatusCode::InvalidArgument);
+                assert_eq!(message, "Integer overflow");
+            }
+            other => panic!("Expected InvalidArgument for {} + {}, got {:?}", a, b, other),
+        }
+    }
+
+    // The connection stays usable after a rejected request.
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: i32::MAX,
+        b: -1,
+        ..Default::default()
+    });
+    match client.request_checked(message).expect("Failed to add").message {
+        Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, i32::MAX - 1),
+        other => panic!("Expected AddResponse, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_resource_audit_reports_usage() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_resource_audit(Duration::from_millis(100)),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut subscriber = Client::new("localhost", 8080, 2000);
+    assert!(subscriber.connect().is_ok());
+    assert!(subscriber.subscribe("audit").is_ok());
+    let mut idle = Client::new("localhost", 8080, 2000);
+    assert!(idle.connect().is_ok());
+
+    let before = server.metrics().audits;
+    thread::sleep(Duration::from_millis(450));
+    let metrics = server.metrics();
+    assert!(
+        metrics.audits >= before + 2,
+        "Expected periodic audits, got {} after {}",
+        metrics.audits,
+        before
+    );
+
+    let audit = metrics.last_audit.expect("Expected an audit report");
+    assert_eq!(audit.open_connections, 2);
+    assert_eq!(audit.queue_depth, 0);
+    assert_eq!(audit.topics, 1);
+    assert_eq!(audit.subscriptions, 1);
+
+    assert!(subscriber.disconnect().is_ok());
+    assert!(idle.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+/// Forwards connections to the test server, except that the first one is
+/// dropped after the server's first response is read and before it reaches
+/// the client.
+fn spawn_flaky_proxy() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for (i, downstream) in listener.incoming().enumerate() {
+            let Ok(mut downstream) = downstream else { return };
+            let mut upstream = TcpStream::connect("localhost:8080").unwrap();
+            let (mut to_upstream, mut from_downstream) =
+                (upstream.try_clone().unwrap(), downstream.try_clone().unwrap());
+            thread::spawn(move || {
+                let _ = std::io::copy(&mut from_downstream, &mut to_upstream);
+            });
+            thread::spawn(move || {
+                if i == 0 {
+                    let _ = framing::read_frame(&mut upstream, false, usize::MAX);
+                    let _ = downstream.shutdown(std::net::Shutdown::Both);
+                    let _ = upstream.shutdown(std::net::Shutdown::Both);
+                } else {
+                    let _ = std::io::copy(&mut upstream, &mut downstream);
+                }
+            });
+        }
+    });
+    port
+}
+
+#[test]
+#[serial]
+fn test_request_retry_survives_dropped_connection() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut registry = HandlerRegistry::builtin();
+    {
+        let runs = runs.clone();
+        registry.register(MessageKind::Add, move |message| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            handler::add(message)
+        });
+    }
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+    let proxy_port = spawn_flaky_proxy();
+
+    let mut client = Client::new("127.0.0.1", proxy_port as u32, 2000);
+    assert!(client.connect().is_ok());
+
+    // The first attempt is handled but its response is lost with the
+    // connection; the retry must replay it rather than add again.
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 40,
+        b: 2,
+        ..Default::default()
+    });
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_millis(50),
+    };
+    match client.request_retry(message, policy).expect("Retry failed").message {
+        Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, 42),
+        other => panic!("Expected AddResponse, got {:?}", other),
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // A new request gets a new key and is handled.
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 40,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.request_retry(message, policy).is_ok());
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_request_retry_sends_stream_echo_once() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let proxy_port = spawn_flaky_proxy();
+
+    let mut client = Client::new("127.0.0.1", proxy_port as u32, 2000);
+    assert!(client.connect().is_ok());
+
+    // Its replies span several frames, which a retry would leave behind, so
+    // the dropped connection is reported instead of retried.
+    let message = client_message::Message::StreamEchoRequest(StreamEchoRequest {
+        content: "once".to_string(),
+        count: 3,
+    });
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_millis(50),
+    };
+    assert!(client.request_retry(message, policy).is_err());
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_connect_with_retry_waits_for_server() {
+    // Nothing is listening yet: every attempt fails, with growing delays.
+    let mut client = Client::new("localhost", 8080, 1000);
+    let start = Instant::now();
+    let err = client
+        .connect_with_retry(3, Duration::from_millis(50))
+        .unwrap_err();
+    assert_eq!(io_kind(&err), ErrorKind::ConnectionRefused);
+    // 50ms + 100ms of backoff between the three attempts.
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    // A server that comes up during the backoff is reached.
+    let (started, server) = mpsc::channel();
+    let starter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(150));
+        let server = create_server();
+        started.send(server.clone()).unwrap();
+        server.run().expect("Server encountered an error");
+    });
+    assert!(client.connect_with_retry(6, Duration::from_millis(50)).is_ok());
+    let server = server.recv().unwrap();
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "after retry".to_string(),
+    });
+    assert!(client.request_checked(message).is_ok());
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    starter.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_graceful_shutdown_drains_in_flight_requests() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(300));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut busy = Client::new("localhost", 8080, 2000);
+    assert!(busy.connect().is_ok());
+    let mut idle = Client::new("localhost", 8080, 2000);
+    assert!(idle.connect().is_ok());
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "in flight".to_string(),
+    });
+    assert!(busy.send(message).is_ok());
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    server.shutdown_graceful(Duration::from_secs(2));
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(1), "Drain took {:?}", elapsed);
+
+    // The request being handled was answered before its connection closed.
+    match busy.receive().expect("In-flight response was cut off").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "in flight"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    assert!(busy.receive().is_err());
+    assert!(idle.receive().is_err());
+
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_graceful_shutdown_times_out_on_stuck_handler() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(800));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "too slow".to_string(),
+    });
+    assert!(client.send(message).is_ok());
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    server.shutdown_graceful(Duration::from_millis(200));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_millis(700), "Shutdown took {:?}", elapsed);
+
+    // Forced teardown closed the connection before the response was sent.
+    assert!(client.receive().is_err());
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_local_addr_reports_ephemeral_port() {
+    let server = Arc::new(Server::new("127.0.0.1:0").expect("Failed to start server"));
+    let addr = server.local_addr().expect("Failed to get local address");
+    assert_ne!(addr.port(), 0);
+    let handle = setup_server_thread(server.clone());
+    assert_eq!(server.local_addr().unwrap(), addr);
+
+    let mut client = Client::new("127.0.0.1", addr.port() as u32, 2000);
+    assert!(client.connect().is_ok());
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "ephemeral".to_string(),
+    });
+    assert!(client.request_checked(message).is_ok());
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_client_reports_socket_addresses() {
+    let server = Arc::new(Server::new("127.0.0.1:0").expect("Failed to start server"));
+    let addr = server.local_addr().expect("Failed to get local address");
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("127.0.0.1", addr.port() as u32, 2000);
+    let error = client.peer_addr().expect_err("Unconnected client has a peer");
+    assert!(matches!(error, ProtocolError::NotConnected), "{:?}", error);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.peer_addr().unwrap(), addr);
+    let local = client.local_addr().expect("Failed to get local address");
+    assert_eq!(local.ip(), addr.ip());
+    assert_ne!(local.port(), addr.port());
+
+    assert!(client.disconnect().is_ok());
+    assert!(client.local_addr().is_err());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_echo_and_add_convenience_methods() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    assert_eq!(client.echo("Hello, World!").unwrap(), "Hello, World!");
+    assert_eq!(client.echo("").unwrap(), "");
+    assert_eq!(client.add(10, 20).unwrap(), 30);
+    assert_eq!(client.add(-7, 3).unwrap(), -4);
+
+    match client.add(i32::MAX, 1).unwrap_err() {
+        ProtocolError::Server { code, .. } => assert_eq!(code, StatusCode::InvalidArgument),
+        other => panic!("Expected a server error, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_echo_rejects_mismatched_reply() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |_| {
+        handler::add(client_message::Message::AddRequest(AddRequest {
+            a: 1,
+            b: 1,
+            ..Default::default()
+        }))
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let err = client.echo("mismatch").unwrap_err();
+    assert!(matches!(err, ProtocolError::DecodeFailed(_)), "{:?}", err);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_configurable_read_timeout_closes_idle_connections() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_read_timeout(Some(Duration::from_millis(200)))
+            .expect("Invalid read timeout"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("before idling").unwrap(), "before idling");
+
+    // Idle past the server's timeout: it closes the connection.
+    thread::sleep(Duration::from_millis(500));
+    let err = client.receive().unwrap_err();
+    assert!(
+        matches!(io_kind(&err), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset),
+        "Expected the server to close the connection, got {:?}",
+        err
+    );
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_max_message_size_is_configurable() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_message_size(64 * 1024)
+            .expect("Invalid max message size"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut negotiated = Client::new("localhost", 8080, 2000);
+    assert!(negotiated.connect().is_ok());
+    assert_eq!(negotiated.negotiate_limits().unwrap(), 64 * 1024);
+    assert!(negotiated.disconnect().is_ok());
+
+    // Without negotiating, the client sends whatever it is given.
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let fits = "x".repeat(60 * 1024);
+    assert_eq!(client.echo(&fits).unwrap(), fits);
+
+    // Over the instance limit, though well under the 1 MiB default: the
+    // server drops the connection rather than reading the payload.
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "x".repeat(100 * 1024),
+    });
+    assert!(client.send(message).is_ok());
+    let start = Instant::now();
+    assert!(client.receive().is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_leaving_before_its_reply_is_a_normal_close() {
+    const CLIENTS: u64 = 20;
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let request = task::message::ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "never read".to_string(),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    for _ in 0..CLIENTS {
+        let mut stream = TcpStream::connect("localhost:8080").unwrap();
+        framing::write_frame(&mut stream, &request, false).unwrap();
+        drop(stream);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut closes = server.metrics().closes;
+    while closes.values().sum::<u64>() < CLIENTS && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+        closes = server.metrics().closes;
+    }
+    assert_eq!(closes.values().sum::<u64>(), CLIENTS, "{:?}", closes);
+    for (reason, count) in &closes {
+        assert!(reason.is_normal() || *count == 0, "{} close(s) for {}", count, reason);
+    }
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_reset_during_write_is_a_normal_disconnect() {
+    // Large enough that the response cannot sit entirely in socket buffers,
+    // so the server is still writing when the client resets.
+    let size = 16 * 1024 * 1024;
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_message_size(2 * size)
+            .expect("Invalid max message size"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    let request = task::message::ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "x".repeat(size),
+        })),
+        ..Default::default()
+    };
+    framing::write_frame(&mut stream, &request.encode_to_vec(), false).unwrap();
+
+    // Read the start of the response, then close with the rest unread,
+    // which makes the kernel reset the connection.
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).unwrap();
+    drop(stream);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.metrics().write_disconnects == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(server.metrics().write_disconnects, 1);
+
+    // The server carries on serving other clients.
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("still up").unwrap(), "still up");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_rejects_oversized_length_prefix() {
+    let listener = TcpListener::bind("localhost:8080").unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // Announce a ~4 GiB frame (u32::MAX itself is the RESET marker)
+        // and then send nothing more.
+        stream.write_all(&(u32::MAX - 1).to_be_bytes()).unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+    });
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    let start = Instant::now();
+    let err = client.receive().unwrap_err();
+    assert!(matches!(err, ProtocolError::MessageTooLarge { .. }), "{:?}", err);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert!(client.disconnect().is_ok());
+    server.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_max_receive_size_is_configurable() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.set_max_receive_size(1024);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("small").unwrap(), "small");
+
+    let err = client.echo(&"x".repeat(2048)).unwrap_err();
+    assert!(matches!(err, ProtocolError::MessageTooLarge { max: 1024, .. }), "{:?}", err);
+    // The oversized payload is still on the socket, so the connection is
+    // dropped rather than misread.
+    assert!(!client.is_connected());
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("small").unwrap(), "small");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_dedicated_pool_isolates_slow_requests() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Multiply, |message| {
+        thread::sleep(Duration::from_millis(300));
+        handler::multiply(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry)
+            .with_dedicated_pool("compute", 1, &[MessageKind::Multiply]),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // More slow requests than the default pool has workers, all queued on
+    // the single compute worker.
+    let slow: Vec<_> = (0..5)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 5000);
+                client.connect().unwrap();
+                let message =
+                    client_message::Message::MultiplyRequest(MultiplyRequest { a: i, b: 2 });
+                let response = client.request_checked(message).unwrap();
+                client.disconnect().unwrap();
+                (i, response)
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    for i in 0..10 {
+        let start = Instant::now();
+        assert_eq!(client.echo(&format!("fast {}", i)).unwrap(), format!("fast {}", i));
+        assert!(
+            start.elapsed() < Duration::from_millis(100),
+            "Echo waited {:?} behind slow requests",
+            start.elapsed()
+        );
+    }
+
+    for slow in slow {
+        let (i, response) = slow.join().unwrap();
+        match response.message {
+            Some(server_message::Message::MultiplyResponse(product)) => {
+                assert_eq!(product.result, i as i64 * 2);
+            }
+            other => panic!("Expected MultiplyResponse, got {:?}", other),
+        }
+    }
+
+    // A connection that was routed to the compute pool is served by the
+    // default pool again afterwards.
+    let message = client_message::Message::MultiplyRequest(MultiplyRequest { a: 6, b: 7 });
+    assert!(client.request_checked(message).is_ok());
+    assert_eq!(client.echo("back home").unwrap(), "back home");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_overloaded_server_sends_throttle() {
+    let mut registry = HandlerRegistry::builtin();
+    registry.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(200));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(registry)
+            .with_backpressure(1, Duration::from_millis(100)),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Twice as many slow requests as workers, so connections queue up.
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 5000);
+                client.connect().unwrap();
+                let content = format!("busy {}", i);
+                assert_eq!(client.echo(&content).unwrap(), content);
+                client.disconnect().unwrap();
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    // Throttles were sent, and clients skipped over them to their replies.
+    assert!(server.metrics().throttles_sent >= 1);
+
+    // Without a backlog, nobody is throttled.
+    let throttled = server.metrics().throttles_sent;
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("idle").unwrap(), "idle");
+    assert_eq!(server.metrics().throttles_sent, throttled);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_client_honors_throttle() {
+    let listener = TcpListener::bind("localhost:8080").unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for i in 0..2 {
+            let request = framing::read_frame(&mut stream, false, usize::MAX).unwrap();
+            let echo = match task::message::ClientMessage::decode(&request[..]).unwrap().message {
+                Some(client_message::Message::EchoMessage(echo)) => echo,
+                other => panic!("Expected EchoMessage, got {:?}", other),
+            };
+            if i == 0 {
+                let throttle = task::message::ServerMessage {
+                    message: Some(server_message::Message::Throttle(task::message::Throttle {
+                        retry_after_ms: 300,
+                    })),
+                };
+                framing::write_frame(&mut stream, &throttle.encode_to_vec(), false).unwrap();
+            }
+            let reply = task::message::ServerMessage {
+                message: Some(server_message::Message::EchoMessage(echo)),
+            };
+            framing::write_frame(&mut stream, &reply.encode_to_vec(), false).unwrap();
+        }
+    });
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    // The throttle is not mistaken for the reply.
+    assert_eq!(client.echo("first").unwrap(), "first");
+
+    // The next request is held back for the requested delay.
+    let start = Instant::now();
+    assert_eq!(client.echo("second").unwrap(), "second");
+    assert!(start.elapsed() >= Duration::from_millis(250), "Sent after {:?}", start.elapsed());
+
+    assert!(client.disconnect().is_ok());
+    server.join().unwrap();
+}
+
+/// Waits up to a second for the server to have closed `expected` connections
+/// for `reason`.
+fn wait_for_closes(server: &Server, reason: CloseReason, expected: u64) {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        let closes = server.metrics().closes;
+        if closes[&reason] == expected {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Expected {} close(s) for {}, got {:?}",
+            expected,
+            reason,
+            closes
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[serial]
+fn test_truncated_length_prefix_is_not_a_clean_close() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // Closing before sending anything is an ordinary hang-up.
+    let stream = TcpStream::connect("localhost:8080").unwrap();
+    drop(stream);
+    wait_for_closes(&server, CloseReason::ClientClosed, 1);
+
+    // Closing after half a length prefix is a truncated frame.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.write_all(&[0, 0]).unwrap();
+    drop(stream);
+    wait_for_closes(&server, CloseReason::ConnectionLost, 1);
+    assert_eq!(server.metrics().closes[&CloseReason::ClientClosed], 1);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_stalled_length_prefix_times_out() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_read_timeout(Some(Duration::from_millis(200)))
+            .expect("Invalid read timeout"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Half a length prefix, then nothing: the worker must not wait forever.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.write_all(&[0, 0]).unwrap();
+    wait_for_closes(&server, CloseReason::Timeout, 1);
+    let mut byte = [0u8; 1];
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    assert!(matches!(stream.read(&mut byte), Ok(0) | Err(_)));
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_write_timeout_frees_worker_from_client_that_stops_reading() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_thread_pool_size(1)
+            .expect("Failed to resize thread pool")
+            .with_write_timeout(Some(Duration::from_millis(200))),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Asks for far more than the socket buffers hold, then never reads.
+    let mut stalled = Client::new("localhost", 8080, 2000);
+    assert!(stalled.connect().is_ok());
+    let flood = client_message::Message::StreamEchoRequest(StreamEchoRequest {
+        content: "x".repeat(64 * 1024),
+        count: 1000,
+    });
+    assert!(stalled.send(flood).is_ok());
+
+    // The only worker is stuck writing until the timeout closes `stalled`.
+    let start = Instant::now();
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("still served").unwrap(), "still served");
+    assert!(start.elapsed() < Duration::from_secs(2), "Worker was held for {:?}", start.elapsed());
+    wait_for_closes(&server, CloseReason::Timeout, 1);
+
+    assert!(client.disconnect().is_ok());
+    drop(stalled);
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_close_reasons_are_metered() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_frame_sync(true)
+            .with_read_timeout(Some(Duration::from_millis(300)))
+            .expect("Invalid read timeout")
+            .with_max_message_size(1024)
+            .expect("Invalid max message size"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // The client hangs up between requests.
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.set_frame_sync(true);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("hello").unwrap(), "hello");
+    assert!(client.disconnect().is_ok());
+    wait_for_closes(&server, CloseReason::ClientClosed, 1);
+
+    // The client goes quiet past the read timeout.
+    let idle = TcpStream::connect("localhost:8080").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    wait_for_closes(&server, CloseReason::Timeout, 1);
+    drop(idle);
+
+    // A frame without its sync marker.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    framing::write_frame(&mut stream, &[0xff; 8], false).unwrap();
+    wait_for_closes(&server, CloseReason::Malformed, 1);
+    drop(stream);
+
+    // A length prefix over the server's limit.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.write_all(&FRAME_SYNC_MARKER).unwrap();
+    stream.write_all(&2048u32.to_be_bytes()).unwrap();
+    wait_for_closes(&server, CloseReason::Oversized, 1);
+    drop(stream);
+
+    // The client vanishes part-way through a frame.
+    let mut stream = TcpStream::connect("localhost:8080").unwrap();
+    stream.write_all(&FRAME_SYNC_MARKER).unwrap();
+    stream.write_all(&[0, 0, 0, 16, 1, 2]).unwrap();
+    drop(stream);
+    wait_for_closes(&server, CloseReason::ConnectionLost, 1);
+
+    // The server stops with a connection still open.
+    let mut client = Client::new("localhost", 8080, 2000);
+    client.set_frame_sync(true);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("still here").unwrap(), "still here");
+    server.stop();
+    handle.join().unwrap();
+    wait_for_closes(&server, CloseReason::Shutdown, 1);
+
+    let closes = server.metrics().closes;
+    assert_eq!(closes[&CloseReason::Error], 0, "{:?}", closes);
+    assert_eq!(closes.values().sum::<u64>(), 6, "{:?}", closes);
+}
+
+#[test]
+#[serial]
+fn test_connections_over_the_limit_are_rejected() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_max_connections(2)
+            .expect("Invalid max connections"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut first = Client::new("localhost", 8080, 2000);
+    let mut second = Client::new("localhost", 8080, 2000);
+    assert!(first.connect().is_ok());
+    assert!(second.connect().is_ok());
+    assert_eq!(first.echo("first").unwrap(), "first");
+    assert_eq!(second.echo("second").unwrap(), "second");
+
+    // A third connection is told why and closed.
+    let mut third = Client::new("localhost", 8080, 2000);
+    assert!(third.connect().is_ok());
+    match third.receive().expect("Expected a rejection").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), StatusCode::ResourceExhausted);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    assert!(third.receive().is_err(), "Rejected connection was left open");
+    assert_eq!(server.metrics().rejected_connections, 1);
+
+    // Once a connection closes there is room again.
+    assert!(first.disconnect().is_ok());
+    wait_for_closes(&server, CloseReason::ClientClosed, 1);
+    let mut fourth = Client::new("localhost", 8080, 2000);
+    assert!(fourth.connect().is_ok());
+    assert_eq!(fourth.echo("fourth").unwrap(), "fourth");
+    assert_eq!(server.metrics().rejected_connections, 1);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_rate_limit_rejects_burst() {
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_rate_limit(5),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    for i in 0..10 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("burst {}", i),
+        });
+        assert!(client.send(message).is_ok());
+    }
+
+    // The first five fit the burst; the rest arrive well within a second.
+    let mut served = 0;
+    let mut limited = 0;
+    for _ in 0..10 {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(_)) => served += 1,
+            Some(server_message::Message::ErrorResponse(error)) => {
+                assert_eq!(error.code(), StatusCode::ResourceExhausted);
+                assert_eq!(error.message, "rate limited");
+                limited += 1;
+            }
+            other => panic!("Expected EchoMessage or ErrorResponse, got {:?}", other),
+        }
+    }
+    assert!(served >= 5, "served {} of the burst", served);
+    assert!(limited > 0, "no request was rate limited");
+    assert_eq!(server.metrics().rate_limited_requests, limited);
+
+    // Once tokens refill the connection is served again.
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(client.echo("after").unwrap(), "after");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_rate_limit_allows_steady_rate_then_throttles_burst() {
+    const RATE: u32 = 10;
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_rate_limit(RATE),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+
+    // Two seconds at exactly the limit, never ahead of schedule.
+    let interval = Duration::from_secs(1) / RATE;
+    let start = Instant::now();
+    for i in 0..2 * RATE {
+        let due = start + interval * i;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        let content = format!("steady {}", i);
+        assert_eq!(client.echo(&content).expect("Steady request was rejected"), content);
+    }
+    assert_eq!(server.metrics().rate_limited_requests, 0);
+
+    let burst = 2 * RATE as usize;
+    for i in 0..burst {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("burst {}", i),
+        });
+        assert!(client.send(message).is_ok());
+    }
+    let mut limited = 0;
+    for _ in 0..burst {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(_)) => {}
+            Some(server_message::Message::ErrorResponse(error)) => {
+                assert_eq!(error.code(), StatusCode::ResourceExhausted);
+                limited += 1;
+            }
+            other => panic!("Expected EchoMessage or ErrorResponse, got {:?}", other),
+        }
+    }
+    assert!(limited > 0, "a burst above the limit was not throttled");
+    assert_eq!(server.metrics().rate_limited_requests, limited);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_thread_pool_size_is_configurable() {
+    const CLIENTS: usize = 16;
+    let mut handlers = HandlerRegistry::builtin();
+    handlers.register(MessageKind::Echo, |message| {
+        thread::sleep(Duration::from_millis(300));
+        handler::echo(message)
+    });
+    let server = Arc::new(
+        Server::new("localhost:8080")
+            .expect("Failed to start server")
+            .with_handlers(handlers)
+            .with_thread_pool_size(CLIENTS)
+            .expect("Failed to size the thread pool"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // With a worker per client every slow request runs at once; the
+    // default four workers would take four rounds.
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = Client::new("localhost", 8080, 5000);
+                assert!(client.connect().is_ok());
+                let content = format!("client {}", i);
+                assert_eq!(client.echo(&content).unwrap(), content);
+                assert!(client.disconnect().is_ok());
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(900), "Took {:?}", elapsed);
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_server_listens_on_several_addresses() {
+    let server = Arc::new(
+        Server::bind_all(&["localhost:8080", "127.0.0.1:0"]).expect("Failed to start server"),
+    );
+    let addrs = server.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert_eq!(addrs[0].port(), 8080);
+    assert_eq!(server.local_addr().unwrap(), addrs[0]);
+    let handle = setup_server_thread(server.clone());
+
+    let mut clients: Vec<Client> = addrs
+        .iter()
+        .map(|addr| Client::new(&addr.ip().to_string(), u32::from(addr.port()), 2000))
+        .collect();
+    for client in &mut clients {
+        assert!(client.connect().is_ok());
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        let content = format!("via address {}", i);
+        assert_eq!(client.echo(&content).unwrap(), content);
+    }
+    for client in &mut clients {
+        assert!(client.disconnect().is_ok());
+    }
+
+    server.stop();
+    handle.join().unwrap();
+    assert!(Server::bind_all(&[]).is_err());
+}
+
+#[test]
+fn test_listen_address_validation() {
+    let cases = [
+        ("localhost:808O", "invalid port `808O`"),
+        ("localhost", "has no port"),
+        ("no-such-host.invalid:8080", "cannot resolve host `no-such-host.invalid`"),
+    ];
+    for (addr, expected) in cases {
+        let err = Server::new(addr).err().expect("Bad address should fail");
+        assert_eq!(io_kind(&err), ErrorKind::InvalidInput, "{}: {}", addr, err);
+        assert!(err.to_string().contains(expected), "{}: {}", addr, err);
+    }
+
+    // A bare port listens on every interface.
+    let server = Server::new(":0").expect("Bare port should bind");
+    let addr = server.local_addr().unwrap();
+    assert!(addr.ip().is_unspecified(), "{}", addr);
+    assert_ne!(addr.port(), 0);
+}
+
+#[test]
+#[serial]
+fn test_client_reconnects_after_disconnect() {
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 1000);
+    assert!(!client.is_connected());
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.is_connected());
+    assert_eq!(client.echo("first").unwrap(), "first");
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(!client.is_connected());
+    let err = client.echo("while disconnected").unwrap_err();
+    assert!(matches!(err, ProtocolError::NotConnected), "{:?}", err);
+
+    assert!(client.connect().is_ok(), "Failed to reconnect to the server");
+    assert!(client.is_connected());
+    assert_eq!(client.echo("second").unwrap(), "second");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    handle.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_transport() {
+    let path = std::env::temp_dir().join(format!("task-test-{}.sock", std::process::id()));
+    let server = Arc::new(Server::bind_unix(&path).expect("Failed to start server"));
+    assert!(server.local_addr().is_err());
+    assert!(Server::bind_unix(&path).is_err(), "A live socket must not be replaced");
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("unused", 0, 1000);
+    assert!(client.connect_unix(&path).is_ok());
+    assert_eq!(client.echo("over a unix socket").unwrap(), "over a unix socket");
+    assert_eq!(client.add(2, 3).unwrap(), 5);
+
+    // Reconnecting goes back to the same socket.
+    assert!(client.disconnect().is_ok());
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("again").unwrap(), "again");
+    assert!(client.disconnect().is_ok());
+
+    server.stop();
+    handle.join().unwrap();
+    drop(server);
+    assert!(!path.exists(), "Socket file should be removed with the server");
+}
+
+#[test]
+#[serial]
+fn test_server_builder() {
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:8080")
+            .thread_pool_size(2)
+            .read_timeout(Duration::from_secs(5))
+            .max_message_size(64)
+            .max_connections(1)
+            .build()
+            .expect("Failed to build server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    assert_eq!(client.echo("built").unwrap(), "built");
+    let response = client.echo(&"x".repeat(100));
+    assert!(response.is_err(), "Message above the built limit should be rejected");
+    let _ = client.disconnect();
+
+    server.stop();
+    handle.join().unwrap();
+
+    let invalid = [
+        ServerBuilder::new(),
+        ServerBuilder::new().addr("localhost:0").thread_pool_size(0),
+        ServerBuilder::new().addr("localhost:0").read_timeout(Duration::ZERO),
+        ServerBuilder::new().addr("localhost:0").max_message_size(0),
+        ServerBuilder::new().addr("localhost:0").max_message_size(u32::MAX as usize),
+        ServerBuilder::new().addr("localhost:0").max_connections(0),
+    ];
+    for builder in invalid {
+        let err = builder.clone().build().err().expect("Invalid settings should fail");
+        assert_eq!(io_kind(&err), ErrorKind::InvalidInput, "{:?}", builder);
+    }
+
+    // The setters on a built server apply the same checks.
+    let server = || Server::new("localhost:0").expect("Failed to start server");
+    let rejected = [
+        server().with_read_timeout(Some(Duration::ZERO)).err(),
+        server().with_max_message_size(0).err(),
+        server().with_max_message_size(u32::MAX as usize).err(),
+        server().with_max_connections(0).err(),
+    ];
+    for err in rejected {
+        let err = err.expect("Invalid setting should fail");
+        assert_eq!(io_kind(&err), ErrorKind::InvalidInput, "{:?}", err);
+    }
+    assert!(server().with_read_timeout(None).is_ok());
+}
+
+#[test]
+#[serial]
+fn test_connection_closes_after_max_requests() {
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:8080")
+            .max_requests_per_connection(3)
+            .build()
+            .expect("Failed to build server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = Client::new("localhost", 8080, 2000);
+    assert!(client.connect().is_ok());
+    for i in 0..5 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("request {}", i),
+        });
+        assert!(client.send(message).is_ok());
+    }
+    for i in 0..3 {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("request {}", i));
+            }
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+    assert!(client.receive().is_err(), "Connection stayed open past its limit");
+    wait_for_closes(&server, CloseReason::RequestLimit, 1);
+
+    // A new connection starts with a fresh allowance.
+    assert!(client.drain_and_reconnect().is_ok());
+    assert_eq!(client.echo("again").unwrap(), "again");
+
+    let _ = client.disconnect();
+    server.stop();
+    handle.join().unwrap();
+}
@@ -0,0 +1,88 @@
+//! Tests that close a server's listening descriptor behind its back. They
+//! live in their own binary, and run serially, because closing a descriptor
+//! number that another test in the process has just reused would break that
+//! test.
+#![cfg(unix)]
+
+use serial_test::serial;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use task::{client::Client, server::Server};
+
+/// Whether `fd` is a socket listening on `addr`'s port.
+fn is_listener_on(fd: libc::c_int, addr: SocketAddr) -> bool {
+    let mut accepting: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&mut accepting as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 || accepting == 0 {
+        return false;
+    }
+
+    let mut bound: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let result =
+        unsafe { libc::getsockname(fd, (&mut bound as *mut libc::sockaddr_in).cast(), &mut len) };
+    result == 0
+        && bound.sin_family as libc::c_int == libc::AF_INET
+        && u16::from_be(bound.sin_port) == addr.port()
+}
+
+/// Closes the descriptor of the server listening on `addr`.
+fn close_listener(addr: SocketAddr) {
+    let fd = (0..1024)
+        .find(|&fd| is_listener_on(fd, addr))
+        .expect("No descriptor is listening on the server's address");
+    unsafe { libc::close(fd) };
+}
+
+#[test]
+#[serial]
+fn test_listener_closed_externally_is_rebound() {
+    let server = Arc::new(Server::new("127.0.0.1:0").unwrap().with_listener_rebind(true));
+    let addr = server.local_addrs()[0];
+    let runner = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run())
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    close_listener(addr);
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = Client::new("127.0.0.1", addr.port() as u32, 2000);
+    client.connect().expect("Listener was not rebound");
+    assert_eq!(client.echo("rebound").unwrap(), "rebound");
+
+    client.disconnect().unwrap();
+    server.stop();
+    runner.join().unwrap().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_listener_closed_externally_stops_server_with_reason() {
+    let server = Arc::new(Server::new("127.0.0.1:0").unwrap());
+    let addr = server.local_addrs()[0];
+    let runner = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run())
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    close_listener(addr);
+    let err = runner.join().unwrap().unwrap_err();
+    assert!(err.to_string().contains("listener on"), "Unclear error: {}", err);
+    assert!(!server.is_running());
+}